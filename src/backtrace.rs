@@ -0,0 +1,393 @@
+use core::fmt;
+use std::cell::RefCell;
+
+use crate::{
+    continuations::{self, UNWINDING},
+    expression::{Exp, Function},
+    locations::{self, Location},
+};
+
+// Frames are recorded as an error unwinds through calls, innermost first, so calls that return
+// normally cost nothing. Like raised objects, the frames are kept aside for the error's message.
+
+// Frames past this many are only counted
+const MAX_FRAMES: usize = 1000;
+// How long calls and arguments are written before they're cut short
+const MAX_CALL_LEN: usize = 60;
+const MAX_ARG_LEN: usize = 40;
+// The longest run of calls that's collapsed when it repeats
+const MAX_CYCLE: usize = 8;
+
+pub struct Frame {
+    // The name of the def that first bound the function, if any
+    pub name: Option<String>,
+    // The call expression, if the function was called from code rather than by a builtin
+    pub call: Option<Exp>,
+    // Where the call expression was read from, if it was in a file
+    pub location: Option<Location>,
+    pub args: Vec<Exp>,
+    function: Function,
+}
+
+impl Frame {
+    pub fn is_builtin(&self) -> bool {
+        matches!(self.function, Function::External(_))
+    }
+
+    // Calls the user didn't write, which would only get in the way
+    fn is_hidden(&self) -> bool {
+        matches!(self.function, Function::External(f) if continuations::is_wind_helper(f))
+    }
+
+    pub fn new(function: &Function, call: Option<&Exp>, args: Vec<Exp>) -> Self {
+        // A builtin is known by what it was called as
+        let name = function.name().or_else(|| match call {
+            Some(Exp::List(list)) => match list.head() {
+                Some(Exp::Ident(name)) => Some(name.clone()),
+                Some(Exp::Resolved(resolved)) => Some(resolved.name.clone()),
+                _ => None,
+            },
+            _ => None,
+        });
+        Frame {
+            name,
+            location: location(call),
+            call: call.cloned(),
+            args,
+            function: function.clone(),
+        }
+    }
+}
+
+fn location(call: Option<&Exp>) -> Option<Location> {
+    match call {
+        Some(Exp::List(list)) => locations::of(list),
+        _ => None,
+    }
+}
+
+struct Trace {
+    err: String,
+    frames: Vec<Frame>,
+    // The last frame past MAX_FRAMES, which is only kept until it can't be merged with any more
+    overflow: Option<Frame>,
+    omitted: usize,
+}
+
+thread_local! {
+    static TRACE: RefCell<Option<Trace>> = const { RefCell::new(None) };
+}
+
+// Adds the call an error is leaving to its backtrace
+pub fn record(err: &str, frame: impl FnOnce() -> Frame) {
+    if err == UNWINDING {
+        return;
+    }
+    TRACE.with(|trace| {
+        let mut trace = trace.borrow_mut();
+        let trace = match &mut *trace {
+            Some(trace) if trace.err == err => trace,
+            trace => trace.insert(Trace {
+                err: err.to_owned(),
+                frames: Vec::new(),
+                overflow: None,
+                omitted: 0,
+            }),
+        };
+        let frame = frame();
+        if frame.is_hidden() {
+            return;
+        }
+        let full = trace.frames.len() >= MAX_FRAMES;
+        let last = match &mut trace.overflow {
+            Some(overflow) => Some(overflow),
+            None => trace.frames.last_mut(),
+        };
+        // A function started by the VM or called by a builtin is recorded without a call, which
+        // its caller then supplies
+        match last {
+            Some(last) if last.call.is_none() && last.function.ptr_eq(&frame.function) => {
+                last.call = frame.call;
+                last.location = frame.location;
+            }
+            _ if !full => trace.frames.push(frame),
+            _ => {
+                if trace.overflow.replace(frame).is_some() {
+                    trace.omitted += 1;
+                }
+            }
+        }
+    });
+}
+
+// Keeps the backtrace with an error whose message changed
+pub fn rename(err: &str, renamed: &str) {
+    TRACE.with(|trace| {
+        if let Some(trace) = &mut *trace.borrow_mut() {
+            if trace.err == err {
+                trace.err = renamed.to_owned();
+            }
+        }
+    });
+}
+
+pub fn clear() {
+    TRACE.with(|trace| *trace.borrow_mut() = None);
+}
+
+pub fn take(err: &str, depth: usize) -> Option<Backtrace> {
+    let trace = TRACE.with(|trace| trace.borrow_mut().take())?;
+    (trace.err == err).then_some(Backtrace {
+        frames: trace.frames,
+        omitted: trace.omitted + usize::from(trace.overflow.is_some()),
+        depth,
+    })
+}
+
+pub struct Backtrace {
+    frames: Vec<Frame>,
+    // Frames beyond those recorded
+    omitted: usize,
+    // How many lines of frames are shown
+    depth: usize,
+}
+
+impl Backtrace {
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+}
+
+// Stops taking text once it has enough, since a value can take ages to write out in full
+struct Bounded {
+    text: String,
+    max_len: usize,
+}
+
+impl fmt::Write for Bounded {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            if self.text.len() >= self.max_len {
+                return Err(fmt::Error);
+            }
+            self.text.push(c);
+        }
+        Ok(())
+    }
+}
+
+fn shortened(exp: &Exp, max_len: usize) -> String {
+    let mut bounded = Bounded {
+        text: String::new(),
+        max_len,
+    };
+    match fmt::write(&mut bounded, format_args!("{}", exp)) {
+        Ok(()) => bounded.text,
+        Err(_) => bounded.text + "...",
+    }
+}
+
+// The shortest run of calls at the start of frames that's repeated straight after, with how
+// many more times it is. A run has to include a named function, since anonymous ones can't be
+// told apart.
+fn cycle(frames: &[Frame]) -> Option<(usize, usize)> {
+    (1..=MAX_CYCLE.min(frames.len() / 2)).find_map(|len| {
+        let first = &frames[..len];
+        if first.iter().all(|frame| frame.name.is_none()) {
+            return None;
+        }
+        let repeats = frames[len..]
+            .chunks_exact(len)
+            .take_while(|run| run.iter().zip(first).all(|(a, b)| a.name == b.name))
+            .count();
+        (repeats > 0).then_some((len, repeats))
+    })
+}
+
+fn write_frame(f: &mut fmt::Formatter, frame: &Frame) -> fmt::Result {
+    write!(f, "  {}", display_name(frame))?;
+    if let Some(location) = &frame.location {
+        write!(f, " at {}", location)?;
+    }
+    if let Some(call) = &frame.call {
+        let at = if frame.location.is_some() { "" } else { " at" };
+        write!(f, "{} {}", at, shortened(call, MAX_CALL_LEN))?;
+    }
+    let args = frame
+        .args
+        .iter()
+        .map(|arg| shortened(arg, MAX_ARG_LEN))
+        .collect::<Vec<_>>();
+    writeln!(f, " with ({})", args.join(" "))
+}
+
+fn display_name(frame: &Frame) -> &str {
+    frame.name.as_deref().unwrap_or("<anonymous>")
+}
+
+impl fmt::Display for Backtrace {
+    // Recursive calls, of one function or of several calling each other in turn, are shown once,
+    // with a count of the rest
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Backtrace, innermost call first:")?;
+        let frames = &self.frames;
+        let (mut idx, mut shown) = (0, 0);
+        while idx < frames.len() {
+            let (len, repeats) = cycle(&frames[idx..]).unwrap_or((1, 0));
+            for frame in &frames[idx..idx + len] {
+                if shown == self.depth {
+                    return writeln!(f, "  ... {} more", frames.len() - idx + self.omitted);
+                }
+                shown += 1;
+                idx += 1;
+                write_frame(f, frame)?;
+            }
+            let run = &frames[idx - len..idx];
+            match (len, repeats) {
+                (_, 0) => (),
+                (1, _) => writeln!(
+                    f,
+                    "  ... {} more call{} to {}",
+                    repeats,
+                    if repeats == 1 { "" } else { "s" },
+                    display_name(&run[0])
+                )?,
+                _ => writeln!(
+                    f,
+                    "  ... {} more time{} through {}",
+                    repeats,
+                    if repeats == 1 { "" } else { "s" },
+                    run.iter()
+                        .map(display_name)
+                        .collect::<Vec<_>>()
+                        .join(" -> ")
+                )?,
+            }
+            idx += len * repeats;
+        }
+        if self.omitted > 0 {
+            writeln!(f, "  ... {} more", self.omitted)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env, fs, process};
+
+    use crate::{evaluator::Evaluator, Interpreter};
+
+    #[test]
+    fn records_calls() {
+        for evaluator in [Evaluator::Bytecode, Evaluator::TreeWalker] {
+            let mut interpreter = Interpreter::new();
+            interpreter.set_evaluator(evaluator);
+            interpreter.set_backtrace_depth(3);
+            interpreter
+                .eval_str("(def f (lambda (n) (if (= n 0) (+ n \"a\") (* 2 (f (- n 1))))))")
+                .unwrap();
+            interpreter
+                .eval_str("(def g (lambda (v) (vector-map (lambda (n) (+ 1 (f n))) v)))")
+                .unwrap();
+            assert!(interpreter.eval_str("(g (vector 3))").is_err());
+            let backtrace = interpreter.backtrace().unwrap();
+            assert_eq!(
+                backtrace.to_string(),
+                [
+                    "Backtrace, innermost call first:",
+                    "  + at (+ n \"a\") with (0 \"a\")",
+                    "  f at (f (- n 1)) with (0)",
+                    "  ... 3 more calls to f",
+                    "  <anonymous> with (3)",
+                    "  ... 2 more",
+                    "",
+                ]
+                .join("\n"),
+                "{:?}",
+                evaluator
+            );
+            assert!(interpreter.eval_str("(+ 1 1)").is_ok());
+            assert!(interpreter.backtrace().is_none());
+        }
+    }
+
+    #[test]
+    fn collapses_cycles_and_hides_dynamic_wind() {
+        for evaluator in [Evaluator::Bytecode, Evaluator::TreeWalker] {
+            let mut interpreter = Interpreter::new();
+            interpreter.set_evaluator(evaluator);
+            interpreter
+                .eval_str(
+                    "(def loop (lambda (n) \
+                       (+ 1 ((lambda (m) (if (= m 0) (+ m \"a\") (+ 1 (loop (- m 1))))) n))))",
+                )
+                .unwrap();
+            assert!(interpreter.eval_str("(loop 10)").is_err());
+            assert_eq!(
+                interpreter.backtrace().unwrap().to_string(),
+                [
+                    "Backtrace, innermost call first:",
+                    "  + at (+ m \"a\") with (0 \"a\")",
+                    "  <anonymous> at ((lambda (m) (if (= m 0) (+ m \"a\") (+ 1 (loop (- m 1))))) n) with (0)",
+                    "  loop at (loop (- m 1)) with (0)",
+                    "  ... 10 more times through <anonymous> -> loop",
+                    "",
+                ]
+                .join("\n"),
+                "{:?}",
+                evaluator
+            );
+
+            let wind = "(dynamic-wind (lambda () (+ 1 \"a\")) (lambda () 1) (lambda () 2))";
+            assert!(interpreter.eval_str(wind).is_err());
+            assert_eq!(
+                interpreter.backtrace().unwrap().to_string(),
+                [
+                    "Backtrace, innermost call first:",
+                    "  + at (+ 1 \"a\") with (1 \"a\")",
+                    "  <anonymous> with ()",
+                    "  dynamic-wind at (dynamic-wind (lambda () (+ 1 \"a\")) (lambda () 1) (lambda ()... \
+                     with (#function# #function# #function#)",
+                    "",
+                ]
+                .join("\n"),
+                "{:?}",
+                evaluator
+            );
+        }
+    }
+
+    #[test]
+    fn locates_calls_from_files() {
+        let dir = env::temp_dir().join(format!("liasp-backtrace-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("f.lsp");
+        fs::write(
+            &path,
+            "(def f (lambda (n)\n  (if (= n 0)\n      (+ n \"a\")\n      (* 2 (f (- n 1))))))\n",
+        )
+        .unwrap();
+        let file = path.to_str().unwrap();
+        for evaluator in [Evaluator::Bytecode, Evaluator::TreeWalker] {
+            let mut interpreter = Interpreter::new();
+            interpreter.set_evaluator(evaluator);
+            interpreter.eval_str(&format!("(load {:?})", file)).unwrap();
+            assert!(interpreter.eval_str("(f 1)").is_err());
+            assert_eq!(
+                interpreter.backtrace().unwrap().to_string(),
+                [
+                    "Backtrace, innermost call first:".to_owned(),
+                    format!("  + at {}:3:7 (+ n \"a\") with (0 \"a\")", file),
+                    format!("  f at {}:4:12 (f (- n 1)) with (0)", file),
+                    "  ... 1 more call to f".to_owned(),
+                    "".to_owned(),
+                ]
+                .join("\n"),
+                "{:?}",
+                evaluator
+            );
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,107 @@
+use std::{cell::RefCell, rc::Rc};
+
+use crate::{expression::Exp, limits, list::List, math};
+
+fn new_bytevector(bytes: Vec<u8>) -> Exp {
+    Exp::Bytevector(Rc::new(RefCell::new(bytes)))
+}
+
+fn bytevector_arg(arg: Option<&Exp>) -> Result<&Rc<RefCell<Vec<u8>>>, String> {
+    match arg {
+        Some(Exp::Bytevector(bytes)) => Ok(bytes),
+        _ => Err("Type error".to_owned()),
+    }
+}
+
+fn byte_arg(arg: Option<&Exp>) -> Result<u8, String> {
+    let val = math::integer_arg(arg.ok_or("Missing required argument".to_owned())?)?;
+    u8::try_from(val).map_err(|_| "Not a byte".to_owned())
+}
+
+fn index_arg(arg: Option<&Exp>, len: usize) -> Result<usize, String> {
+    let idx = math::integer_arg(arg.ok_or("Missing required argument".to_owned())?)?;
+    usize::try_from(idx)
+        .ok()
+        .filter(|idx| *idx < len)
+        .ok_or("Index out of bounds".to_owned())
+}
+
+pub fn is_bytevector(args: &List<Exp>) -> Result<Exp, String> {
+    let arg = args.head().ok_or("Missing required argument".to_owned())?;
+    Ok(Exp::Bool(matches!(arg, Exp::Bytevector(_))))
+}
+
+pub fn bytevector(args: &List<Exp>) -> Result<Exp, String> {
+    let bytes = args
+        .iter()
+        .map(|arg| byte_arg(Some(arg)))
+        .collect::<Result<Vec<u8>, String>>()?;
+    Ok(new_bytevector(bytes))
+}
+
+pub fn make_bytevector(args: &List<Exp>) -> Result<Exp, String> {
+    let mut args_iter = args.iter();
+    let len = math::integer_arg(args_iter.next().ok_or("Type error".to_owned())?)?;
+    let len = usize::try_from(len).map_err(|_| "Invalid bytevector length".to_owned())?;
+    limits::reserve(len)?;
+    let fill = match args_iter.next() {
+        Some(fill) => byte_arg(Some(fill))?,
+        None => 0,
+    };
+    // Failing to allocate would abort the process, so a length too big for memory is an error
+    let mut bytes = Vec::new();
+    bytes
+        .try_reserve_exact(len)
+        .map_err(|_| "Invalid bytevector length".to_owned())?;
+    bytes.resize(len, fill);
+    Ok(new_bytevector(bytes))
+}
+
+pub fn bytevector_u8_ref(args: &List<Exp>) -> Result<Exp, String> {
+    let mut args_iter = args.iter();
+    let bytes = bytevector_arg(args_iter.next())?.borrow();
+    let idx = index_arg(args_iter.next(), bytes.len())?;
+    Ok(Exp::Number(bytes[idx] as f32))
+}
+
+pub fn bytevector_u8_set(args: &List<Exp>) -> Result<Exp, String> {
+    let mut args_iter = args.iter();
+    let mut bytes = bytevector_arg(args_iter.next())?.borrow_mut();
+    let idx = index_arg(args_iter.next(), bytes.len())?;
+    bytes[idx] = byte_arg(args_iter.next())?;
+    Ok(Exp::Void)
+}
+
+pub fn bytevector_length(args: &List<Exp>) -> Result<Exp, String> {
+    let bytes = bytevector_arg(args.head())?.borrow();
+    Ok(Exp::Number(bytes.len() as f32))
+}
+
+pub fn utf8_to_string(args: &List<Exp>) -> Result<Exp, String> {
+    let bytes = bytevector_arg(args.head())?.borrow();
+    let text = std::str::from_utf8(&bytes).map_err(|_| "Invalid UTF-8 in bytevector".to_owned())?;
+    Ok(Exp::Str(text.into()))
+}
+
+pub fn string_to_utf8(args: &List<Exp>) -> Result<Exp, String> {
+    match args.head() {
+        Some(Exp::Str(text)) => Ok(new_bytevector(text.as_bytes().to_vec())),
+        _ => Err("Type error".to_owned()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::Interpreter;
+
+    #[test]
+    fn make_bytevector() {
+        let mut interpreter = Interpreter::new();
+        let made = interpreter.eval_str("(make-bytevector 2 7)").unwrap();
+        assert_eq!(made.to_string(), "#u8(7 7)");
+        assert_eq!(
+            interpreter.eval_str("(make-bytevector 100000000000000 0)"),
+            Err("Invalid bytevector length".to_owned())
+        );
+    }
+}
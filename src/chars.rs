@@ -0,0 +1,92 @@
+use crate::{expression::Exp, list::List, math};
+
+fn char_arg(args: &List<Exp>) -> Result<char, String> {
+    match args.head() {
+        Some(Exp::Char(c)) => Ok(*c),
+        _ => Err("Type error".to_owned()),
+    }
+}
+
+pub fn is_char(args: &List<Exp>) -> Result<Exp, String> {
+    let arg = args.head().ok_or("Missing required argument".to_owned())?;
+    Ok(Exp::Bool(matches!(arg, Exp::Char(_))))
+}
+
+pub fn char_to_integer(args: &List<Exp>) -> Result<Exp, String> {
+    let c = char_arg(args)?;
+    Ok(Exp::Number(c as u32 as f32))
+}
+
+pub fn integer_to_char(args: &List<Exp>) -> Result<Exp, String> {
+    let code = math::integer_arg(args.head().ok_or("Type error".to_owned())?)?;
+    u32::try_from(code)
+        .ok()
+        .and_then(char::from_u32)
+        .map(Exp::Char)
+        .ok_or("Invalid character code".to_owned())
+}
+
+pub fn char_upcase(args: &List<Exp>) -> Result<Exp, String> {
+    let c = char_arg(args)?;
+    // Characters without a single-character uppercase form are left alone
+    let mut upper = c.to_uppercase();
+    match (upper.next(), upper.next()) {
+        (Some(u), None) => Ok(Exp::Char(u)),
+        _ => Ok(Exp::Char(c)),
+    }
+}
+
+pub fn is_char_alphabetic(args: &List<Exp>) -> Result<Exp, String> {
+    char_arg(args).map(|c| Exp::Bool(c.is_alphabetic()))
+}
+
+pub fn is_char_numeric(args: &List<Exp>) -> Result<Exp, String> {
+    char_arg(args).map(|c| Exp::Bool(c.is_numeric()))
+}
+
+pub fn is_char_whitespace(args: &List<Exp>) -> Result<Exp, String> {
+    char_arg(args).map(|c| Exp::Bool(c.is_whitespace()))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::Interpreter;
+
+    #[test]
+    fn conversions_and_predicates() {
+        let mut interpreter = Interpreter::new();
+        let cases = [
+            ("(char->integer #\\A)", Ok("65")),
+            ("(char->integer \"A\")", Err("Type error")),
+            ("(integer->char 955)", Ok("#\\λ")),
+            ("(integer->char (char->integer #\\z))", Ok("#\\z")),
+            ("(integer->char -1)", Err("Invalid character code")),
+            // Surrogates aren't characters
+            ("(integer->char 55296)", Err("Invalid character code")),
+            ("(integer->char 1.5)", Err("Type error")),
+            ("(char-upcase #\\a)", Ok("#\\A")),
+            // Its uppercase form is two characters, SS
+            ("(char-upcase #\\ß)", Ok("#\\ß")),
+            ("(char-upcase 1)", Err("Type error")),
+            ("(list (char? #\\a) (char? \"a\"))", Ok("(#t #f)")),
+            (
+                "(list (char-alphabetic? #\\λ) (char-alphabetic? #\\5))",
+                Ok("(#t #f)"),
+            ),
+            (
+                "(list (char-numeric? #\\5) (char-numeric? #\\a))",
+                Ok("(#t #f)"),
+            ),
+            (
+                "(list (char-whitespace? #\\newline) (char-whitespace? #\\a))",
+                Ok("(#t #f)"),
+            ),
+            ("(char-numeric? \"5\")", Err("Type error")),
+        ];
+        for (form, expected) in cases {
+            let result = interpreter.eval_str(form).map(|val| val.to_string());
+            let expected = expected.map(str::to_owned).map_err(str::to_owned);
+            assert_eq!(result, expected, "{}", form);
+        }
+    }
+}
@@ -0,0 +1,633 @@
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    ptr,
+    rc::Rc,
+};
+
+use crate::{
+    conditions,
+    environment::Environment,
+    expression::{Exp, Function},
+    list::List,
+    macros, math, special_forms,
+};
+
+// Compiles top-level forms for the VM. Only if, def, lambda, quote, set!, calls and macros that
+// expand to those are compiled. A form that uses anything else, or that the tree-walker would
+// reject, isn't compiled at all, so the tree-walker evaluates it and behaves exactly as before.
+
+#[derive(Clone, Copy, Debug)]
+pub enum Op {
+    Const(usize),
+    // Locals kept on the stack, addressed from the frame's base
+    Local(usize),
+    SetLocal(usize),
+    // Locals that closures capture, kept in a frame of their own that can outlive the call
+    Captured(usize),
+    SetCaptured(usize),
+    Upvalue(usize),
+    SetUpvalue(usize),
+    // Variables of the environment the form was compiled in, by name
+    Global(usize),
+    DefGlobal(usize),
+    SetGlobal(usize),
+    Closure(usize),
+    Jump(usize),
+    JumpIfFalse(usize),
+    Call(usize),
+    TailCall(usize),
+    // Names the function on top of the stack after the def it's about to be stored by
+    Name(usize),
+    // A call of a global arithmetic builtin with two arguments. The arguments are computed
+    // without the function, which is only looked up to check it hasn't been redefined.
+    Primitive(Primitive, u32, Operand, Operand),
+    Return,
+}
+
+// Where an argument of a primitive comes from. Locals and constants are read in place rather than
+// pushed first, which saves dispatching an instruction for each.
+#[derive(Clone, Copy, Debug)]
+pub enum Operand {
+    Stack,
+    Local(u32),
+    Const(u32),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Primitive {
+    Add,
+    Subtract,
+    Multiply,
+    Equals,
+}
+
+impl Primitive {
+    fn builtin(self) -> fn(&List<Exp>) -> Result<Exp, String> {
+        match self {
+            Primitive::Add => math::add,
+            Primitive::Subtract => math::subtract,
+            Primitive::Multiply => math::multiply,
+            Primitive::Equals => math::equals,
+        }
+    }
+
+    fn of(f: &Function) -> Option<Self> {
+        [
+            Primitive::Add,
+            Primitive::Subtract,
+            Primitive::Multiply,
+            Primitive::Equals,
+        ]
+        .into_iter()
+        .find(|primitive| primitive.matches(f))
+    }
+
+    pub fn matches(self, f: &Function) -> bool {
+        matches!(f, Function::External(f) if ptr::fn_addr_eq(*f, self.builtin()))
+    }
+
+    pub fn apply(self, a: f32, b: f32) -> Exp {
+        match self {
+            Primitive::Add => Exp::Number(a + b),
+            Primitive::Subtract => Exp::Number(a - b),
+            Primitive::Multiply => Exp::Number(a * b),
+            Primitive::Equals => Exp::Bool(a == b),
+        }
+    }
+}
+
+// Where a closure finds each of its upvalues when it's created: in the creating call's captured
+// frame, or among the creating closure's own upvalues
+#[derive(Clone, Copy, Debug)]
+pub enum Capture {
+    Local(usize),
+    Upvalue(usize),
+}
+
+pub struct Proto {
+    pub arity: usize,
+    pub code: Vec<Op>,
+    pub constants: Vec<Exp>,
+    pub globals: Vec<String>,
+    pub protos: Vec<Rc<Proto>>,
+    // Stack slots for locals, starting with the parameters
+    pub slots: usize,
+    // Size of the captured frame, and the parameters that move there on entry
+    pub captured: usize,
+    pub captured_params: Vec<(usize, usize)>,
+    pub upvalues: Vec<Capture>,
+    // The names of the locals and upvalues, for reporting one that's read before it's defined
+    locals: HashMap<String, Local>,
+    upvalue_names: Vec<String>,
+    // The form each call instruction came from, by the instruction's index, for backtraces
+    pub call_sites: Vec<(usize, Exp)>,
+    // Global lookups, remembered until any binding changes
+    pub global_cache: RefCell<Vec<Option<(u64, Exp)>>>,
+}
+
+impl Proto {
+    // The form of the call instruction at ip
+    pub fn call_site(&self, ip: usize) -> Option<&Exp> {
+        let index = self
+            .call_sites
+            .binary_search_by_key(&ip, |(op, _)| *op)
+            .ok()?;
+        Some(&self.call_sites[index].1)
+    }
+
+    // The name of the variable a Local, Captured or Upvalue instruction reads
+    pub fn name(&self, op: Op) -> &str {
+        let local = match op {
+            Op::Local(index) => Local::Slot(index),
+            Op::Captured(index) => Local::Captured(index),
+            Op::Upvalue(index) => return &self.upvalue_names[index],
+            _ => return "",
+        };
+        self.locals
+            .iter()
+            .find(|(_, other)| **other == local)
+            .map_or("", |(name, _)| name)
+    }
+}
+
+// The forms left once macros have been expanded
+enum Core {
+    Const(Exp),
+    Var(String),
+    If(Box<Core>, Box<Core>, Box<Core>),
+    Def(String, Box<Core>),
+    Set(String, Box<Core>),
+    Lambda(Vec<String>, Box<Core>),
+    // Also keeps the call's form
+    Call(Box<Core>, Vec<Core>, Exp),
+}
+
+impl Core {
+    // Names referenced but not bound inside this expression
+    fn free_vars(&self, free: &mut HashSet<String>) {
+        match self {
+            Core::Const(_) => (),
+            Core::Var(name) => {
+                free.insert(name.clone());
+            }
+            Core::If(test, then, otherwise) => {
+                test.free_vars(free);
+                then.free_vars(free);
+                otherwise.free_vars(free);
+            }
+            Core::Def(name, val) | Core::Set(name, val) => {
+                free.insert(name.clone());
+                val.free_vars(free);
+            }
+            Core::Lambda(params, body) => {
+                let mut inner = HashSet::new();
+                body.free_vars(&mut inner);
+                let mut bound = body.defs();
+                bound.extend(params.iter().cloned());
+                free.extend(inner.into_iter().filter(|name| !bound.contains(name)));
+            }
+            Core::Call(f, args, _) => {
+                f.free_vars(free);
+                args.iter().for_each(|arg| arg.free_vars(free));
+            }
+        }
+    }
+
+    // Names defined in this function's own frame, which doesn't include nested lambdas
+    fn defs(&self) -> Vec<String> {
+        let mut defs = Vec::new();
+        self.visit(&mut |core| {
+            if let Core::Def(name, _) = core {
+                defs.push(name.clone());
+            }
+        });
+        defs
+    }
+
+    // Names that lambdas nested in this function refer to, and names it assigns. Those can't
+    // live on the stack, which continuations take copies of.
+    fn captured_vars(&self) -> HashSet<String> {
+        let mut free = HashSet::new();
+        self.visit(&mut |core| match core {
+            Core::Lambda(..) => core.free_vars(&mut free),
+            Core::Set(name, _) => {
+                free.insert(name.clone());
+            }
+            _ => (),
+        });
+        free
+    }
+
+    // Calls f on every node of this function, stopping at nested lambdas
+    fn visit(&self, f: &mut impl FnMut(&Core)) {
+        f(self);
+        match self {
+            Core::If(test, then, otherwise) => {
+                test.visit(f);
+                then.visit(f);
+                otherwise.visit(f);
+            }
+            Core::Def(_, val) | Core::Set(_, val) => val.visit(f),
+            Core::Call(head, args, _) => {
+                head.visit(f);
+                args.iter().for_each(|arg| arg.visit(f));
+            }
+            Core::Const(_) | Core::Var(_) | Core::Lambda(..) => (),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Local {
+    Slot(usize),
+    Captured(usize),
+}
+
+enum Var {
+    Local(Local),
+    Upvalue(usize),
+    Global,
+}
+
+#[derive(Default)]
+struct Scope {
+    arity: usize,
+    locals: HashMap<String, Local>,
+    upvalues: Vec<(String, Capture)>,
+    code: Vec<Op>,
+    constants: Vec<Exp>,
+    globals: Vec<String>,
+    protos: Vec<Rc<Proto>>,
+    call_sites: Vec<(usize, Exp)>,
+}
+
+impl Scope {
+    fn emit(&mut self, op: Op) -> usize {
+        self.code.push(op);
+        self.code.len() - 1
+    }
+
+    fn constant(&mut self, val: Exp) -> usize {
+        self.constants.push(val);
+        self.constants.len() - 1
+    }
+
+    fn global(&mut self, name: &str) -> usize {
+        match self.globals.iter().position(|global| global == name) {
+            Some(index) => index,
+            None => {
+                self.globals.push(name.to_owned());
+                self.globals.len() - 1
+            }
+        }
+    }
+}
+
+struct Compiler<'a> {
+    env: &'a Environment,
+    // Names bound in each enclosing lambda while expanding, innermost last
+    bound: Vec<HashSet<String>>,
+    // Names the form defines at the top level, which will be bound to what it gives them
+    defined: HashSet<String>,
+    scopes: Vec<Scope>,
+}
+
+// Returns None if the form has to be left to the tree-walker
+pub fn compile(exp: &Exp, env: &Environment) -> Option<Rc<Proto>> {
+    let mut compiler = Compiler {
+        env,
+        bound: Vec::new(),
+        defined: HashSet::new(),
+        scopes: Vec::new(),
+    };
+    let core = compiler.expand(exp)?;
+    compiler.function(&[], &core)
+}
+
+impl Compiler<'_> {
+    fn is_local(&self, name: &str) -> bool {
+        self.bound.iter().any(|names| names.contains(name))
+    }
+
+    fn expand(&mut self, exp: &Exp) -> Option<Core> {
+        let list = match exp {
+            Exp::Ident(name) => return Some(Core::Var(name.clone())),
+            Exp::List(list) => list,
+            _ => return Some(Core::Const(exp.clone())),
+        };
+        let head = list.head()?;
+        let rest = list.tail()?;
+        let args = rest.iter().collect::<Vec<&Exp>>();
+        let callee = match head {
+            Exp::Ident(name) if self.is_local(name) || self.defined.contains(name) => None,
+            // Anything else that isn't bound yet could still become a macro before the call is
+            // made, which only the tree-walker can expand then
+            Exp::Ident(name) => Some(self.env.lookup(name)?),
+            Exp::SpecialForm(_) => Some(head.clone()),
+            _ => None,
+        };
+        match callee {
+            Some(Exp::SpecialForm(form)) => self.special_form(form, &args),
+            Some(Exp::Macro(macro_)) => {
+                // The form is left to the tree-walker if this fails, which expands it again
+                let expansion = conditions::unattended(|| {
+                    macros::expand_cached(&macro_, list, self.env, &|name| self.is_local(name))
+                })
+                .ok()?;
+                self.expand(&expansion)
+            }
+            _ => Some(Core::Call(
+                Box::new(self.expand(head)?),
+                args.into_iter()
+                    .map(|arg| self.expand(arg))
+                    .collect::<Option<Vec<Core>>>()?,
+                exp.clone(),
+            )),
+        }
+    }
+
+    fn special_form(
+        &mut self,
+        form: fn(&List<Exp>, &mut Environment) -> Result<Exp, String>,
+        args: &[&Exp],
+    ) -> Option<Core> {
+        let is = |other: fn(&List<Exp>, &mut Environment) -> Result<Exp, String>| {
+            ptr::fn_addr_eq(form, other)
+        };
+        if is(special_forms::quote) {
+            let [quoted] = args else { return None };
+            Some(Core::Const((*quoted).clone()))
+        } else if is(special_forms::if_exp) {
+            let [test, then, otherwise] = args else {
+                return None;
+            };
+            Some(Core::If(
+                Box::new(self.expand(test)?),
+                Box::new(self.expand(then)?),
+                Box::new(self.expand(otherwise)?),
+            ))
+        } else if is(special_forms::def) || is(special_forms::set) {
+            let [Exp::Ident(name), val] = args else {
+                return None;
+            };
+            if is(special_forms::set) {
+                return Some(Core::Set(name.clone(), Box::new(self.expand(val)?)));
+            }
+            match self.bound.last_mut() {
+                Some(names) => names.insert(name.clone()),
+                None => self.defined.insert(name.clone()),
+            };
+            Some(Core::Def(name.clone(), Box::new(self.expand(val)?)))
+        } else if is(special_forms::lambda) {
+            let [Exp::List(params), body] = args else {
+                return None;
+            };
+            let params = params
+                .iter()
+                .map(|param| match param {
+                    Exp::Ident(name) => Some(name.clone()),
+                    _ => None,
+                })
+                .collect::<Option<Vec<String>>>()?;
+            self.bound.push(params.iter().cloned().collect());
+            let body = self.expand(body);
+            self.bound.pop();
+            Some(Core::Lambda(params, Box::new(body?)))
+        } else {
+            None
+        }
+    }
+
+    fn function(&mut self, params: &[String], body: &Core) -> Option<Rc<Proto>> {
+        let defs = body.defs();
+        let captured = body.captured_vars();
+        let mut scope = Scope {
+            arity: params.len(),
+            ..Scope::default()
+        };
+        let (mut slots, mut captured_count) = (params.len(), 0);
+        let mut captured_params = Vec::new();
+        for (index, param) in params.iter().enumerate() {
+            let local = if captured.contains(param) {
+                captured_params.push((index, captured_count));
+                captured_count += 1;
+                Local::Captured(captured_count - 1)
+            } else {
+                Local::Slot(index)
+            };
+            // A repeated parameter, or a definition of one, is an error the tree-walker reports
+            if scope.locals.insert(param.clone(), local).is_some() {
+                return None;
+            }
+        }
+        // The top level defines into the environment instead
+        if !self.scopes.is_empty() || !params.is_empty() {
+            for def in defs {
+                let local = if captured.contains(&def) {
+                    captured_count += 1;
+                    Local::Captured(captured_count - 1)
+                } else {
+                    slots += 1;
+                    Local::Slot(slots - 1)
+                };
+                if scope.locals.insert(def, local).is_some() {
+                    return None;
+                }
+            }
+        }
+
+        // A top-level form keeps its frame, so backtraces show the call it made
+        let tail = !self.scopes.is_empty();
+        self.scopes.push(scope);
+        let compiled = self.exp(body, tail);
+        let scope = self.scopes.pop().unwrap();
+        compiled?;
+        let mut scope = scope;
+        scope.emit(Op::Return);
+        Some(Rc::new(Proto {
+            arity: params.len(),
+            global_cache: RefCell::new(vec![None; scope.globals.len()]),
+            code: scope.code,
+            constants: scope.constants,
+            globals: scope.globals,
+            protos: scope.protos,
+            call_sites: scope.call_sites,
+            slots,
+            captured: captured_count,
+            captured_params,
+            upvalue_names: scope
+                .upvalues
+                .iter()
+                .map(|(name, _)| name.clone())
+                .collect(),
+            upvalues: scope.upvalues.into_iter().map(|(_, up)| up).collect(),
+            locals: scope.locals,
+        }))
+    }
+
+    fn scope(&mut self) -> &mut Scope {
+        self.scopes.last_mut().unwrap()
+    }
+
+    fn resolve(&mut self, level: usize, name: &str) -> Option<Var> {
+        if let Some(local) = self.scopes[level].locals.get(name) {
+            return Some(Var::Local(*local));
+        }
+        if let Some(index) = self.scopes[level]
+            .upvalues
+            .iter()
+            .position(|(upvalue, _)| upvalue == name)
+        {
+            return Some(Var::Upvalue(index));
+        }
+        if level == 0 {
+            return Some(Var::Global);
+        }
+        let capture = match self.resolve(level - 1, name)? {
+            Var::Global => return Some(Var::Global),
+            Var::Local(Local::Captured(index)) => Capture::Local(index),
+            Var::Upvalue(index) => Capture::Upvalue(index),
+            // Every variable a lambda refers to is captured, so this can't happen
+            Var::Local(Local::Slot(_)) => return None,
+        };
+        let upvalues = &mut self.scopes[level].upvalues;
+        upvalues.push((name.to_owned(), capture));
+        Some(Var::Upvalue(upvalues.len() - 1))
+    }
+
+    fn store(&mut self, name: &str, define: bool) -> Option<()> {
+        let level = self.scopes.len() - 1;
+        let op = match self.resolve(level, name)? {
+            Var::Local(Local::Slot(index)) => Op::SetLocal(index),
+            Var::Local(Local::Captured(index)) => Op::SetCaptured(index),
+            Var::Upvalue(index) => Op::SetUpvalue(index),
+            Var::Global if define => Op::DefGlobal(self.scope().global(name)),
+            Var::Global => Op::SetGlobal(self.scope().global(name)),
+        };
+        let scope = self.scope();
+        scope.emit(op);
+        let void = scope.constant(Exp::Void);
+        scope.emit(Op::Const(void));
+        Some(())
+    }
+
+    fn operand(&mut self, core: &Core) -> Option<Operand> {
+        match core {
+            Core::Const(val) => Some(Operand::Const(self.scope().constant(val.clone()) as u32)),
+            Core::Var(name) => match self.resolve(self.scopes.len() - 1, name)? {
+                // Only parameters, since a local defined in the body has to be checked for being
+                // read before it's defined
+                Var::Local(Local::Slot(index)) if index < self.scope().arity => {
+                    Some(Operand::Local(index as u32))
+                }
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn exp(&mut self, core: &Core, tail: bool) -> Option<()> {
+        match core {
+            Core::Const(val) => {
+                let index = self.scope().constant(val.clone());
+                self.scope().emit(Op::Const(index));
+            }
+            Core::Var(name) => {
+                let level = self.scopes.len() - 1;
+                let op = match self.resolve(level, name)? {
+                    Var::Local(Local::Slot(index)) => Op::Local(index),
+                    Var::Local(Local::Captured(index)) => Op::Captured(index),
+                    Var::Upvalue(index) => Op::Upvalue(index),
+                    Var::Global => Op::Global(self.scope().global(name)),
+                };
+                self.scope().emit(op);
+            }
+            Core::If(test, then, otherwise) => {
+                self.exp(test, false)?;
+                let jump_to_else = self.scope().emit(Op::JumpIfFalse(0));
+                self.exp(then, tail)?;
+                // A branch in tail position can return straight away
+                let jump_to_end = self
+                    .scope()
+                    .emit(if tail { Op::Return } else { Op::Jump(0) });
+                let else_start = self.scope().code.len();
+                self.exp(otherwise, tail)?;
+                let end = self.scope().code.len();
+                self.scope().code[jump_to_else] = Op::JumpIfFalse(else_start);
+                if !tail {
+                    self.scope().code[jump_to_end] = Op::Jump(end);
+                }
+            }
+            Core::Def(name, val) => {
+                self.exp(val, false)?;
+                if !matches!(**val, Core::Const(_)) {
+                    let index = self.scope().constant(Exp::Ident(name.clone()));
+                    self.scope().emit(Op::Name(index));
+                }
+                self.store(name, true)?;
+            }
+            Core::Set(name, val) => {
+                self.exp(val, false)?;
+                self.store(name, false)?;
+            }
+            Core::Lambda(params, body) => {
+                let proto = self.function(params, body)?;
+                let scope = self.scope();
+                scope.protos.push(proto);
+                let index = scope.protos.len() - 1;
+                scope.emit(Op::Closure(index));
+            }
+            Core::Call(head, args, form) => {
+                if let (Core::Var(name), [a, b]) = (&**head, &args[..]) {
+                    let level = self.scopes.len() - 1;
+                    if let Some(Var::Global) = self.resolve(level, name) {
+                        let primitive = match self.env.lookup(name) {
+                            Some(Exp::Function(f)) => Primitive::of(&f),
+                            _ => None,
+                        };
+                        if let Some(primitive) = primitive {
+                            // The first argument can only be read late if evaluating the second
+                            // can't change it
+                            let b_operand = self.operand(b);
+                            let a_operand = b_operand.and_then(|_| self.operand(a));
+                            let a_operand = match a_operand {
+                                Some(operand) => operand,
+                                None => {
+                                    self.exp(a, false)?;
+                                    Operand::Stack
+                                }
+                            };
+                            let b_operand = match b_operand {
+                                Some(operand) => operand,
+                                None => {
+                                    self.exp(b, false)?;
+                                    Operand::Stack
+                                }
+                            };
+                            let index = self.scope().global(name) as u32;
+                            let scope = self.scope();
+                            let op =
+                                scope.emit(Op::Primitive(primitive, index, a_operand, b_operand));
+                            scope.call_sites.push((op, form.clone()));
+                            return Some(());
+                        }
+                    }
+                }
+                self.exp(head, false)?;
+                for arg in args {
+                    self.exp(arg, false)?;
+                }
+                let op = if tail {
+                    Op::TailCall(args.len())
+                } else {
+                    Op::Call(args.len())
+                };
+                let scope = self.scope();
+                let op = scope.emit(op);
+                scope.call_sites.push((op, form.clone()));
+            }
+        }
+        Some(())
+    }
+}
@@ -0,0 +1,447 @@
+use std::{cell::RefCell, iter, ptr, rc::Rc};
+
+use crate::{
+    backtrace,
+    continuations::{Active, Continuation, UNWINDING},
+    environment::Environment,
+    exceptions::{self, Handler},
+    expression::Exp,
+    lexer::tokenize,
+    limits::LimitExceeded,
+    list::List,
+    machine::{self, Native},
+    parser::parse,
+    ports::Port,
+    vm,
+};
+
+// Errors are signalled where they happen, before anything unwinds. Handlers from handler-bind are
+// called right there, and can fix the problem by invoking a restart: one established with
+// restart-case, or one the evaluator offers at the point of the error, like using a value in
+// place of a failed builtin call. A handler that returns declines, and the error goes on to be
+// raised as usual.
+
+#[derive(Clone, Debug)]
+pub struct RestartInfo {
+    pub name: String,
+    pub description: String,
+    pub params: Vec<String>,
+}
+
+// Called with an error that nothing is going to handle, and the restarts available. Returns the
+// index of the restart to invoke and the source text of each of its arguments, or None to let the
+// error through.
+pub type Debugger = Rc<dyn Fn(&str, &[RestartInfo]) -> Option<(usize, Vec<String>)>>;
+
+pub struct Restart {
+    info: RestartInfo,
+    // Where the restart-case or erroring call is, to unwind to
+    continuation: Rc<Continuation>,
+    index: usize,
+}
+
+thread_local! {
+    // Innermost first
+    static RESTARTS: RefCell<List<Rc<Restart>>> = RefCell::new(List::new());
+    static DEBUGGER: RefCell<Option<Debugger>> = const { RefCell::new(None) };
+}
+
+pub fn set_debugger(debugger: Option<Debugger>) {
+    DEBUGGER.with(|current| *current.borrow_mut() = debugger);
+}
+
+pub fn restarts() -> List<Rc<Restart>> {
+    RESTARTS.with(|restarts| restarts.borrow().clone())
+}
+
+pub fn set_restarts(restarts: List<Rc<Restart>>) -> List<Rc<Restart>> {
+    RESTARTS.with(|current| current.replace(restarts))
+}
+
+impl Restart {
+    // The arguments go to the restart's point along with which restart was invoked there
+    fn invoke(&self, args: impl IntoIterator<Item = Exp>) -> Result<Exp, String> {
+        let index = Exp::Number(self.index as f32);
+        let value = Exp::List(List::from_vec(iter::once(index).chain(args).collect()));
+        self.continuation.resume(&List::from_vec(vec![value]))
+    }
+}
+
+// Installs restarts that resume the continuation, returning the ones that were installed before
+pub fn establish(infos: Vec<RestartInfo>, continuation: Rc<Continuation>) -> List<Rc<Restart>> {
+    let mut restarts = restarts();
+    for (index, info) in infos.into_iter().enumerate().rev() {
+        restarts = restarts.prepend(Rc::new(Restart {
+            info,
+            continuation: continuation.clone(),
+            index,
+        }));
+    }
+    set_restarts(restarts)
+}
+
+// Which restart was invoked and its arguments, from the value the restart resumed with
+pub fn restart_value(value: Exp) -> Result<(usize, List<Exp>), String> {
+    match value {
+        Exp::List(value) => match value.head() {
+            Some(Exp::Number(index)) => Ok((*index as usize, value.tail().unwrap())),
+            _ => Err("Type error".to_owned()),
+        },
+        _ => Err("Type error".to_owned()),
+    }
+}
+
+// Establishes restarts around f, and returns which one was invoked and its arguments if one was
+fn with_restarts(
+    infos: Vec<RestartInfo>,
+    f: impl FnOnce() -> Result<Exp, String>,
+) -> Result<Result<Exp, (usize, List<Exp>)>, String> {
+    let active = Active::enter();
+    let continuation = Rc::new(Continuation::new(None, active.id(), false));
+    let outer = establish(infos, continuation);
+    let result = f();
+    set_restarts(outer);
+    match result {
+        Ok(val) => Ok(Ok(val)),
+        Err(err) => match active.catch(&err) {
+            Some((value, _)) => restart_value(value).map(Err),
+            None => Err(err),
+        },
+    }
+}
+
+fn restart_info(name: &str, description: String, params: &[&str]) -> RestartInfo {
+    RestartInfo {
+        name: name.to_owned(),
+        description,
+        params: params.iter().map(|param| param.to_string()).collect(),
+    }
+}
+
+// Offers an error to the handlers with some restarts established. Returns the restart one of them
+// invoked, or None if the error should be raised.
+fn signal_error(err: &str, infos: Vec<RestartInfo>) -> Result<Option<(usize, List<Exp>)>, String> {
+    // Limits aren't for handlers to get around
+    if err == UNWINDING || LimitExceeded::of(err).is_some() || exceptions::mark_signalled(err) {
+        return Ok(None);
+    }
+    let condition = exceptions::condition(err);
+    match with_restarts(infos, || offer(err, &condition).map(|_| Exp::Void))? {
+        Ok(_) => Ok(None),
+        Err(restart) => {
+            exceptions::clear_signalled();
+            Ok(Some(restart))
+        }
+    }
+}
+
+// Calls handlers from handler-bind until reaching one that catches errors once they're raised.
+// If there's none, the debugger gets a go.
+fn offer(err: &str, condition: &Exp) -> Result<(), String> {
+    let mut handlers = exceptions::handlers();
+    while let Some(handler) = handlers.head().cloned() {
+        handlers = handlers.tail().unwrap();
+        match &*handler {
+            Handler::Bind { .. } => exceptions::call_bound(&handler, condition, &handlers)?,
+            Handler::Exception(_) | Handler::Guard => return Ok(()),
+        }
+    }
+    debug(err)
+}
+
+fn debug(err: &str) -> Result<(), String> {
+    let Some(debugger) = DEBUGGER.with(|debugger| debugger.borrow().clone()) else {
+        return Ok(());
+    };
+    let restarts = restarts().iter().cloned().collect::<Vec<_>>();
+    let infos = restarts
+        .iter()
+        .map(|restart| restart.info.clone())
+        .collect::<Vec<_>>();
+    Port::current_output().flush()?;
+    let Some((index, texts)) = debugger(err, &infos) else {
+        return Ok(());
+    };
+    let restart = restarts.get(index).ok_or("No such restart".to_owned())?;
+    let mut env = Environment::interaction().ok_or("No interaction environment".to_owned())?;
+    let args = texts
+        .iter()
+        .map(|text| {
+            let exp = tokenize(text).and_then(|tokens| parse(&tokens))?;
+            vm::eval_toplevel(&exp, &mut env)
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    restart.invoke(args).map(|_| ())
+}
+
+// Runs something that's only being tried, and is done again for real if it fails, without
+// offering its errors to the handlers or the debugger
+pub fn unattended<T>(f: impl FnOnce() -> Result<T, String>) -> Result<T, String> {
+    let outer = exceptions::set_handlers(List::new().prepend(Rc::new(Handler::Guard)));
+    let result = f();
+    exceptions::set_handlers(outer);
+    if result.is_err() {
+        exceptions::clear_signalled();
+        backtrace::clear();
+    }
+    result
+}
+
+// Calls a builtin, offering to use a value in its place or to call it again if it fails
+pub fn call_builtin(
+    f: fn(&List<Exp>) -> Result<Exp, String>,
+    args: &List<Exp>,
+) -> Result<Exp, String> {
+    loop {
+        let err = match f(args) {
+            Err(err) => err,
+            result => return result,
+        };
+        // Builtins that only raise would raise again if retried, and their callers don't expect
+        // a value from them
+        let is = |other: fn(&List<Exp>) -> Result<Exp, String>| ptr::fn_addr_eq(f, other);
+        let infos = if is(exceptions::error)
+            || is(exceptions::raise)
+            || is(exceptions::raise_continuable)
+        {
+            Vec::new()
+        } else {
+            vec![
+                restart_info(
+                    "use-value",
+                    "Return a value from the call".to_owned(),
+                    &["value"],
+                ),
+                restart_info("retry", "Call it again".to_owned(), &[]),
+            ]
+        };
+        match signal_error(&err, infos)? {
+            Some((0, values)) => return Ok(values.head().cloned().unwrap_or(Exp::Void)),
+            Some(_) => (),
+            None => return Err(err),
+        }
+    }
+}
+
+// The error for an identifier without a binding, offering to use a value in its place
+pub fn unbound(name: &str) -> Result<Exp, String> {
+    let err = "Undefined identifier";
+    let irritants = List::from_vec(vec![Exp::Ident(name.to_owned())]);
+    exceptions::raise_as(
+        err,
+        Exp::Error(Rc::new(exceptions::ErrorObject {
+            message: err.to_owned(),
+            irritants,
+        })),
+    );
+    let infos = vec![restart_info(
+        "use-value",
+        format!("Use a value for {}", name),
+        &["value"],
+    )];
+    match signal_error(err, infos)? {
+        Some((_, values)) => Ok(values.head().cloned().unwrap_or(Exp::Void)),
+        None => Err(err.to_owned()),
+    }
+}
+
+// (handler-bind ((predicate handler) ...) body...) calls the first handler whose predicate
+// accepts a condition signalled in the body
+pub fn handler_bind(args: &List<Exp>, env: &mut Environment) -> Result<Exp, String> {
+    machine::special_form(Native::HandlerBind, args, env)
+}
+
+// The handlers to install for a handler-bind, with its body
+pub fn bound_handlers(
+    args: &List<Exp>,
+    env: &Environment,
+) -> Result<(List<Rc<Handler>>, List<Exp>), String> {
+    let Some(Exp::List(bindings)) = args.head() else {
+        return Err("Type error".to_owned());
+    };
+    let body = args.tail().unwrap();
+    let bound = bindings
+        .iter()
+        .map(|binding| match binding {
+            Exp::List(binding) if binding.iter().count() == 2 => {
+                let mut parts = binding.iter();
+                let predicate = machine::eval(parts.next().unwrap(), env)?;
+                let handler = machine::eval(parts.next().unwrap(), env)?;
+                Ok(Handler::Bind { predicate, handler })
+            }
+            _ => Err("Type error".to_owned()),
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    let mut handlers = exceptions::handlers();
+    for handler in bound.into_iter().rev() {
+        handlers = handlers.prepend(Rc::new(handler));
+    }
+    Ok((handlers, body))
+}
+
+// (restart-case form (name (params...) body...) ...) evaluates form, and if it invokes one of the
+// restarts, evaluates that restart's body with its arguments instead
+pub fn restart_case(args: &List<Exp>, env: &mut Environment) -> Result<Exp, String> {
+    machine::special_form(Native::RestartCase, args, env)
+}
+
+// A restart of a restart-case, with its body
+pub type RestartClause = (RestartInfo, List<Exp>);
+
+// The form of a restart-case, and its restarts
+pub fn restart_clauses(args: &List<Exp>) -> Result<(Exp, Vec<RestartClause>), String> {
+    let form = args.head().ok_or("Missing required argument".to_owned())?;
+    let clauses = args
+        .tail()
+        .unwrap()
+        .iter()
+        .map(|clause| {
+            let Exp::List(clause) = clause else {
+                return Err("Type error".to_owned());
+            };
+            let mut parts = clause.iter();
+            let (Some(Exp::Ident(name)), Some(Exp::List(params))) = (parts.next(), parts.next())
+            else {
+                return Err("Type error".to_owned());
+            };
+            let params = params
+                .iter()
+                .map(|param| match param {
+                    Exp::Ident(param) => Ok(param.clone()),
+                    _ => Err("Type error".to_owned()),
+                })
+                .collect::<Result<Vec<_>, String>>()?;
+            let info = RestartInfo {
+                name: name.clone(),
+                description: String::new(),
+                params,
+            };
+            Ok((info, List::from_vec(parts.cloned().collect())))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    Ok((form.clone(), clauses))
+}
+
+// (invoke-restart 'name args...) transfers control to the innermost restart with that name
+pub fn invoke_restart(args: &List<Exp>) -> Result<Exp, String> {
+    let name = match args.head() {
+        Some(Exp::Ident(name)) => name,
+        Some(_) => return Err("Type error".to_owned()),
+        None => return Err("Missing required argument".to_owned()),
+    };
+    let restart = restarts()
+        .iter()
+        .find(|restart| restart.info.name == *name)
+        .cloned()
+        .ok_or_else(|| format!("No restart named {}", name))?;
+    restart.invoke(args.tail().unwrap().iter().cloned())
+}
+
+// (signal condition) calls the handlers from handler-bind that accept the condition, and returns
+// if they all decline
+pub fn signal(args: &List<Exp>) -> Result<Exp, String> {
+    let condition = args.head().ok_or("Missing required argument".to_owned())?;
+    let mut handlers = exceptions::handlers();
+    while let Some(handler) = handlers.head().cloned() {
+        handlers = handlers.tail().unwrap();
+        exceptions::call_bound(&handler, condition, &handlers)?;
+    }
+    Ok(Exp::Void)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{cell::RefCell, rc::Rc};
+
+    use crate::{evaluator::Evaluator, Interpreter};
+
+    #[test]
+    fn restarts() {
+        let cases = [
+            (
+                "(handler-bind ((error-object? (lambda (e) (invoke-restart 'use-value 42)))) (+ 1 nope))",
+                Ok("43"),
+            ),
+            (
+                "(handler-bind ((error-object? (lambda (e) (invoke-restart 'use-value (error-object-irritants e))))) (list 1 nope))",
+                Ok("(1 (nope))"),
+            ),
+            (
+                "(handler-bind ((error-object? (lambda (e) (invoke-restart 'use-value 0)))) (+ 1 (vector-ref (vector) 3)))",
+                Ok("1"),
+            ),
+            (
+                "(restart-case (+ 1 (raise 'oops)) (skip (x y) (list x y)))",
+                Err("Uncaught exception: oops"),
+            ),
+            (
+                "(handler-bind (((lambda (c) (eq? c 'oops)) (lambda (c) (invoke-restart 'skip c 2)))) (restart-case (+ 1 (raise 'oops)) (skip (x y) (list x y))))",
+                Ok("(oops 2)"),
+            ),
+            // Declining handlers leave the error to be raised, and guard catches it
+            (
+                "(guard (e (#t (list 'caught (error-object-message e)))) (handler-bind ((error-object? (lambda (e) 'declined))) (+ 1 \"a\")))",
+                Ok("(caught Type error)"),
+            ),
+            // guard catches inside handler-bind before its handlers get a look
+            (
+                "(handler-bind ((error-object? (lambda (e) (invoke-restart 'use-value 0)))) (guard (e (#t 'guarded)) (+ 1 \"a\")))",
+                Ok("guarded"),
+            ),
+            (
+                "(def seen (vector 0))",
+                Ok("#void#"),
+            ),
+            (
+                "(list (handler-bind (((lambda (c) #t) (lambda (c) (vector-set! seen 0 c)))) (signal 5)) seen)",
+                Ok("(#void# #(5))"),
+            ),
+            ("(invoke-restart 'nowhere)", Err("No restart named nowhere")),
+        ];
+        for evaluator in [Evaluator::Bytecode, Evaluator::TreeWalker] {
+            let mut interpreter = Interpreter::new();
+            interpreter.set_evaluator(evaluator);
+            for (form, expected) in cases {
+                let result = interpreter
+                    .eval_str(form)
+                    .map(|val| val.display_form().to_string());
+                let expected = expected.map(str::to_owned).map_err(str::to_owned);
+                assert_eq!(result, expected, "{:?} {}", evaluator, form);
+            }
+        }
+    }
+
+    #[test]
+    fn debugger() {
+        let offered = Rc::new(RefCell::new(Vec::new()));
+        let mut interpreter = Interpreter::new();
+        let seen = offered.clone();
+        interpreter.set_debugger(move |err, restarts| {
+            let names = restarts.iter().map(|restart| restart.name.clone());
+            seen.borrow_mut()
+                .push((err.to_owned(), names.collect::<Vec<_>>()));
+            let index = restarts
+                .iter()
+                .position(|restart| restart.name == "use-value")?;
+            Some((index, vec!["(+ 20 1)".to_owned()]))
+        });
+        let result = interpreter.eval_str("(* 2 missing)").unwrap();
+        assert_eq!(result.to_string(), "42");
+        assert_eq!(
+            offered.borrow().as_slice(),
+            [(
+                "Undefined identifier".to_owned(),
+                vec!["use-value".to_owned()]
+            )]
+        );
+
+        // Raising builtins only offer the restarts around them
+        offered.borrow_mut().clear();
+        let raised = "(restart-case (+ 1 (error \"boom\")) (use (v) v))";
+        assert!(interpreter.eval_str(raised).is_err());
+        assert_eq!(
+            offered.borrow().as_slice(),
+            [("boom".to_owned(), vec!["use".to_owned()])]
+        );
+    }
+}
@@ -0,0 +1,429 @@
+use std::{
+    cell::{Cell, RefCell},
+    ptr,
+    rc::Rc,
+};
+
+use crate::{
+    conditions::{self, Restart},
+    environment::Environment,
+    exceptions::{self, Handler},
+    expression::{Exp, Function},
+    gc::{Trace, Tracer},
+    lexer,
+    list::List,
+    machine, parser, vm,
+};
+
+// Continuations are resumed by returning an error with this message up to the point that can
+// resume them: the machine they were captured on, a call/cc called from Rust, or the interpreter
+// itself. What to resume with is kept to the side until it gets there.
+pub const UNWINDING: &str = "Continuation invoked";
+
+pub struct Continuation {
+    // The machine's frames, or None for one that can only escape back to its call/cc
+    frames: Option<Vec<machine::Frame>>,
+    // The point that resumes it
+    target: usize,
+    // Whether it can be resumed after its machine has finished. Only the interpreter's machines
+    // qualify, since what's left to do after them is the same for every form.
+    rooted: bool,
+    winders: List<Rc<Winder>>,
+    handlers: List<Rc<Handler>>,
+    restarts: List<Rc<Restart>>,
+}
+
+// The before and after thunks of a dynamic-wind whose thunk is running
+pub struct Winder {
+    before: Exp,
+    after: Exp,
+}
+
+struct Unwind {
+    target: usize,
+    value: Exp,
+    continuation: Rc<Continuation>,
+}
+
+thread_local! {
+    // The points that can resume a continuation, innermost last
+    static ACTIVE: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+    static NEXT_ID: Cell<usize> = const { Cell::new(0) };
+    // The interpreter's own point, while it's evaluating
+    static ROOT: Cell<Option<usize>> = const { Cell::new(None) };
+    static PENDING: RefCell<Option<Unwind>> = const { RefCell::new(None) };
+    static WINDERS: RefCell<List<Rc<Winder>>> = RefCell::new(List::new());
+}
+
+// Keeps a resumption point active until it's dropped
+pub struct Active(usize);
+
+impl Active {
+    pub fn enter() -> Self {
+        let id = NEXT_ID.with(|next| {
+            next.set(next.get() + 1);
+            next.get()
+        });
+        ACTIVE.with(|active| active.borrow_mut().push(id));
+        Active(id)
+    }
+
+    pub fn id(&self) -> usize {
+        self.0
+    }
+
+    // The value to resume with, if the error is a continuation being resumed here
+    pub fn catch(&self, err: &str) -> Option<(Exp, Rc<Continuation>)> {
+        if err != UNWINDING {
+            return None;
+        }
+        PENDING.with(|pending| {
+            let mut pending = pending.borrow_mut();
+            if pending.as_ref()?.target != self.0 {
+                return None;
+            }
+            let unwind = pending.take()?;
+            Some((unwind.value, unwind.continuation))
+        })
+    }
+}
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        ACTIVE.with(|active| active.borrow_mut().pop());
+    }
+}
+
+fn is_active(id: usize) -> bool {
+    ACTIVE.with(|active| active.borrow().contains(&id))
+}
+
+// Evaluates a form for the interpreter on the given point, which resumes continuations captured by
+// earlier forms too
+pub fn root(id: usize, f: impl FnOnce() -> Result<Exp, String>) -> Result<Exp, String> {
+    let outer = ROOT.with(|root| root.replace(Some(id)));
+    let result = f();
+    ROOT.with(|root| root.set(outer));
+    // Thunks whose dynamic-wind an error escaped from are finished now
+    if outer.is_none() {
+        let mut remaining = winders();
+        while let Some(winder) = remaining.head().cloned() {
+            remaining = remaining.tail().unwrap();
+            WINDERS.with(|winders| *winders.borrow_mut() = remaining.clone());
+            // The rest are dropped if one fails, so they don't outlive the evaluation
+            if let Err(err) = call_thunk(&winder.after) {
+                WINDERS.with(|winders| *winders.borrow_mut() = List::new());
+                return Err(err);
+            }
+        }
+    }
+    result
+}
+
+impl Continuation {
+    pub fn new(frames: Option<Vec<machine::Frame>>, target: usize, rooted: bool) -> Self {
+        Continuation {
+            frames,
+            target,
+            rooted,
+            winders: winders(),
+            handlers: exceptions::handlers(),
+            restarts: conditions::restarts(),
+        }
+    }
+
+    // Puts back the handlers and restarts from where it was captured, and returns the frames to
+    // continue with
+    pub fn reinstate(&self) -> Vec<machine::Frame> {
+        exceptions::set_handlers(self.handlers.clone());
+        conditions::set_restarts(self.restarts.clone());
+        self.frames.clone().unwrap_or_default()
+    }
+
+    pub fn resume(self: &Rc<Self>, args: &List<Exp>) -> Result<Exp, String> {
+        let target = if is_active(self.target) {
+            self.target
+        } else {
+            match ROOT.with(Cell::get) {
+                Some(root) if self.rooted => root,
+                _ => return Err("Continuation can no longer be resumed".to_owned()),
+            }
+        };
+        rewind(&self.winders)?;
+        let value = args.head().cloned().unwrap_or(Exp::Void);
+        PENDING.with(|pending| {
+            *pending.borrow_mut() = Some(Unwind {
+                target,
+                value,
+                continuation: self.clone(),
+            })
+        });
+        Err(UNWINDING.to_owned())
+    }
+}
+
+fn call_thunk(thunk: &Exp) -> Result<Exp, String> {
+    match thunk {
+        Exp::Function(f) => f.call(&List::new()),
+        _ => Err("Type error".to_owned()),
+    }
+}
+
+// The dynamic-winds whose thunks are running, innermost first
+pub fn winders() -> List<Rc<Winder>> {
+    WINDERS.with(|winders| winders.borrow().clone())
+}
+
+// Runs the after thunks being left and the before thunks being entered, in that order, to get
+// from the current dynamic-winds to the given ones
+pub fn rewind(to: &List<Rc<Winder>>) -> Result<(), String> {
+    let from = winders();
+    let depth = |list: &List<Rc<Winder>>| list.iter().count();
+    let (mut leaving, mut entering) = (from.clone(), to.clone());
+    while depth(&leaving) > depth(&entering) {
+        leaving = leaving.tail().unwrap();
+    }
+    while depth(&entering) > depth(&leaving) {
+        entering = entering.tail().unwrap();
+    }
+    while !leaving.ptr_eq(&entering) {
+        leaving = leaving.tail().unwrap();
+        entering = entering.tail().unwrap();
+    }
+    let common = leaving;
+
+    let mut current = from;
+    while !current.ptr_eq(&common) {
+        let winder = current.head().unwrap().clone();
+        current = current.tail().unwrap();
+        WINDERS.with(|winders| *winders.borrow_mut() = current.clone());
+        call_thunk(&winder.after)?;
+    }
+    let mut entered = Vec::new();
+    let mut target = to.clone();
+    while !target.ptr_eq(&common) {
+        entered.push(target.clone());
+        target = target.tail().unwrap();
+    }
+    for winders in entered.into_iter().rev() {
+        call_thunk(&winders.head().unwrap().before)?;
+        WINDERS.with(|current| *current.borrow_mut() = winders);
+    }
+    Ok(())
+}
+
+// The function passed to call/cc
+pub fn receiver(args: &List<Exp>) -> Result<Function, String> {
+    match args.head().ok_or("Missing required argument".to_owned())? {
+        Exp::Function(f) => Ok(f.clone()),
+        _ => Err("Type error".to_owned()),
+    }
+}
+
+// The machine captures the continuation itself when it calls this, and one that can be resumed
+// again after escaping. Called from a builtin, the continuation can only escape.
+pub fn call_cc(args: &List<Exp>) -> Result<Exp, String> {
+    let f = receiver(args)?;
+    let active = Active::enter();
+    let continuation = Continuation::new(None, active.id(), false);
+    let k = Exp::Function(Function::Continuation(Rc::new(continuation)));
+    f.call(&List::new().prepend(k))
+        .or_else(|err| active.catch(&err).map(|(value, _)| value).ok_or(err))
+}
+
+fn wind_enter(args: &List<Exp>) -> Result<Exp, String> {
+    let mut args = args.iter();
+    let before = args.next().ok_or("Missing required argument".to_owned())?;
+    let after = args.next().ok_or("Missing required argument".to_owned())?;
+    call_thunk(before)?;
+    let winder = Rc::new(Winder {
+        before: before.clone(),
+        after: after.clone(),
+    });
+    WINDERS.with(|winders| {
+        let mut winders = winders.borrow_mut();
+        *winders = winders.prepend(winder);
+    });
+    Ok(Exp::Void)
+}
+
+// Takes what wind-enter returned too, so that the thunk is called between the two
+fn wind_exit(args: &List<Exp>) -> Result<Exp, String> {
+    let mut args = args.iter().skip(1);
+    let result = args.next().ok_or("Missing required argument".to_owned())?;
+    let after = args.next().ok_or("Missing required argument".to_owned())?;
+    WINDERS.with(|winders| {
+        let mut winders = winders.borrow_mut();
+        *winders = winders.tail().unwrap_or_default();
+    });
+    call_thunk(after)?;
+    Ok(result.clone())
+}
+
+// dynamic-wind is a function in Lisp so that its thunk runs on the same machine as its caller,
+// which lets continuations captured inside it be resumed after it returns
+const DYNAMIC_WIND: &str =
+    "(lambda (before thunk after) (wind-exit (wind-enter before after) (thunk) after))";
+
+// The builtins dynamic-wind is made of, which backtraces leave out
+pub fn is_wind_helper(f: fn(&List<Exp>) -> Result<Exp, String>) -> bool {
+    let is = |other: fn(&List<Exp>) -> Result<Exp, String>| ptr::fn_addr_eq(f, other);
+    is(wind_enter) || is(wind_exit)
+}
+
+pub fn dynamic_wind(global_env: &Environment) -> Exp {
+    let mut env = global_env.extend();
+    env.define("wind-enter", &Exp::Function(Function::External(wind_enter)))
+        .unwrap();
+    env.define("wind-exit", &Exp::Function(Function::External(wind_exit)))
+        .unwrap();
+    let exp = parser::parse(&lexer::tokenize(DYNAMIC_WIND).unwrap()).unwrap();
+    let dynamic_wind = vm::eval_compiled(&exp, &env)
+        .expect("dynamic-wind compiles")
+        .unwrap();
+    if let Exp::Function(f) = &dynamic_wind {
+        f.name_as("dynamic-wind");
+    }
+    dynamic_wind
+}
+
+impl Trace for Winder {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.before.trace(tracer);
+        self.after.trace(tracer);
+    }
+}
+
+impl Trace for Continuation {
+    fn trace(&self, tracer: &mut Tracer) {
+        if let Some(frames) = &self.frames {
+            frames.trace(tracer);
+        }
+        self.winders.trace(tracer);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{evaluator::Evaluator, Interpreter};
+
+    fn run(interpreter: &mut Interpreter, forms: &[&str]) -> Result<String, String> {
+        let mut last = None;
+        for form in forms {
+            last = Some(interpreter.eval_str(form)?);
+        }
+        Ok(last.unwrap().display_form().to_string())
+    }
+
+    #[test]
+    fn escapes() {
+        for evaluator in [Evaluator::Bytecode, Evaluator::TreeWalker] {
+            let mut interpreter = Interpreter::new();
+            interpreter.set_evaluator(evaluator);
+            let forms = [
+                "(def deep (lambda (n k) (if (= n 0) (k 'bottom) (+ 1 (deep (- n 1) k)))))",
+                "(call/cc (lambda (k) (deep 100 k)))",
+            ];
+            assert_eq!(run(&mut interpreter, &forms), Ok("bottom".to_owned()));
+            let forms = [
+                "(def notes (list))",
+                "(def note (lambda (x) (set! notes (list x notes))))",
+                "(call/cc (lambda (k) (dynamic-wind (lambda () (note 'in)) (lambda () (k 1)) (lambda () (note 'out)))))",
+                "notes",
+            ];
+            assert_eq!(
+                run(&mut interpreter, &forms),
+                Ok("(out (in ()))".to_owned())
+            );
+            // An error escaping finishes the dynamic-wind too
+            let forms = [
+                "(def notes (list))",
+                "(dynamic-wind (lambda () (note 'in)) (lambda () (+ 'a 1)) (lambda () (note 'out)))",
+            ];
+            assert!(run(&mut interpreter, &forms).is_err());
+            assert_eq!(
+                run(&mut interpreter, &["notes"]),
+                Ok("(out (in ()))".to_owned())
+            );
+        }
+    }
+
+    #[test]
+    fn resumes() {
+        for evaluator in [Evaluator::Bytecode, Evaluator::TreeWalker] {
+            let mut interpreter = Interpreter::new();
+            interpreter.set_evaluator(evaluator);
+            // A generator that hands out the next number each time it's resumed
+            let forms = [
+                "(def gen-k #f)",
+                "(def gen (lambda (return) ((lambda (loop) ((lambda (ignored) (loop 0)) (set! loop (lambda (i) ((lambda (new-return) ((lambda (ignored) (loop (+ i 1))) (set! return new-return))) (call/cc (lambda (resume) ((lambda (ignored) (return i)) (set! gen-k resume))))))))) #f)))",
+                "(def next (lambda () (call/cc (lambda (return) (if gen-k (gen-k return) (gen return))))))",
+                "(list (next) (next) (next))",
+            ];
+            assert_eq!(
+                run(&mut interpreter, &forms),
+                Ok("(0 1 2)".to_owned()),
+                "{:?}",
+                evaluator
+            );
+            // Forms that aren't compiled can be resumed too
+            let forms = [
+                "(guard (e (#t 'caught)) (list (next) (next)))",
+                "(handler-bind ((error-object? (lambda (e) #f))) (list (next) (next)))",
+                "(restart-case (list (next) (next)) (skip () 'skipped))",
+                "(apply list (list (next) (next)))",
+            ];
+            for (form, expected) in forms.iter().zip(["(3 4)", "(5 6)", "(7 8)", "(9 10)"]) {
+                assert_eq!(
+                    run(&mut interpreter, &[form]),
+                    Ok(expected.to_owned()),
+                    "{:?} {}",
+                    evaluator,
+                    form
+                );
+            }
+            assert_eq!(
+                run(
+                    &mut interpreter,
+                    &["((call/cc (lambda (k) k)) (lambda (x) 9))"]
+                ),
+                Ok("9".to_owned()),
+                "{:?}",
+                evaluator
+            );
+
+            // Variables assigned after the continuation was captured keep their new values
+            let forms = [
+                "(def loop3 (lambda () ((lambda (i k) ((lambda (ignored) (if (= i 3) i (k k))) ((lambda (ignored) (set! i (+ i 1))) (set! k (call/cc (lambda (c) c)))))) 0 #f)))",
+                "(loop3)",
+            ];
+            assert_eq!(
+                run(&mut interpreter, &forms),
+                Ok("3".to_owned()),
+                "{:?}",
+                evaluator
+            );
+
+            // Resuming from a later form reenters the dynamic-wind
+            let forms = [
+                "(def notes (list))",
+                "(def note (lambda (x) (set! notes (list x notes))))",
+                "(def saved #f)",
+                "(dynamic-wind (lambda () (note 'in)) (lambda () (call/cc (lambda (k) (set! saved k)))) (lambda () (note 'out)))",
+                "(saved 1)",
+            ];
+            assert_eq!(
+                run(&mut interpreter, &forms),
+                Ok("1".to_owned()),
+                "{:?}",
+                evaluator
+            );
+            assert_eq!(
+                run(&mut interpreter, &["notes"]),
+                Ok("(out (in (out (in ()))))".to_owned()),
+                "{:?}",
+                evaluator
+            );
+        }
+    }
+}
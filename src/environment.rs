@@ -1,8 +1,17 @@
-use std::{cell::RefCell, collections::HashMap, rc::Rc};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    rc::{Rc, Weak},
+};
 
 use crate::{
+    bytevectors, chars, conditions, continuations, equality, evaluation, exceptions,
     expression::{Exp, Function},
-    math,
+    gc,
+    gc::{Trace, Tracer},
+    hash_tables, io,
+    list::List,
+    lists, loader, macros, math, modules, persistent, reader, special_forms, strings, vectors,
 };
 
 #[derive(Clone)]
@@ -12,19 +21,177 @@ pub struct Environment {
 
 type EnvLink = Option<Rc<RefCell<EnvNode>>>;
 
+thread_local! {
+    static INTERACTION: RefCell<Option<Environment>> = const { RefCell::new(None) };
+    // Every environment node, so the collector can find cycles between them
+    static TRACKED: RefCell<Tracked> = const { RefCell::new(Tracked { nodes: Vec::new(), prune_at: 1024 }) };
+    // Changes whenever any binding does, so lookups can be cached until then
+    static BINDINGS_VERSION: Cell<u64> = const { Cell::new(0) };
+}
+
+fn bindings_changed() {
+    BINDINGS_VERSION.with(|version| version.set(version.get() + 1));
+}
+
+struct Tracked {
+    nodes: Vec<Weak<RefCell<EnvNode>>>,
+    // Dead entries are dropped once there are this many, so the list stays proportional to the
+    // number of live environments
+    prune_at: usize,
+}
+
+impl Tracked {
+    fn prune(&mut self) {
+        self.nodes.retain(|node| node.strong_count() > 0);
+        self.prune_at = (self.nodes.len() * 2).max(1024);
+    }
+}
+
+fn track(node: EnvNode) -> Rc<RefCell<EnvNode>> {
+    let node = Rc::new(RefCell::new(node));
+    TRACKED.with(|tracked| {
+        let mut tracked = tracked.borrow_mut();
+        if tracked.nodes.len() >= tracked.prune_at {
+            tracked.prune();
+        }
+        tracked.nodes.push(Rc::downgrade(&node));
+    });
+    node
+}
+
+// A reference to an environment that doesn't keep it alive
+pub struct WeakEnvironment(Weak<RefCell<EnvNode>>);
+
+impl WeakEnvironment {
+    pub fn upgrade(&self) -> Option<Environment> {
+        self.0
+            .upgrade()
+            .map(|root| Environment { root: Some(root) })
+    }
+
+    pub fn as_ptr(&self) -> *const () {
+        self.0.as_ptr() as *const ()
+    }
+
+    pub fn strong_count(&self) -> usize {
+        self.0.strong_count()
+    }
+}
+
+// Slot i holds the value bound to names[i]. Resolved identifiers index the slots directly, and
+// names are searched for everything else.
 struct EnvNode {
-    bindings: HashMap<String, Exp>,
+    names: Vec<String>,
+    slots: Vec<Exp>,
+    // Frames with many bindings, like the global one, are indexed instead of searched
+    index: Option<HashMap<String, usize>>,
     parent: EnvLink,
 }
 
+const INDEX_AT: usize = 16;
+
+impl EnvNode {
+    fn new(parent: EnvLink) -> Self {
+        EnvNode {
+            names: Vec::new(),
+            slots: Vec::new(),
+            index: None,
+            parent,
+        }
+    }
+
+    fn position(&self, ident: &str) -> Option<usize> {
+        match &self.index {
+            Some(index) => index.get(ident).copied(),
+            None => self.names.iter().position(|name| name == ident),
+        }
+    }
+
+    fn push(&mut self, ident: &str, val: Exp) {
+        if let Some(index) = &mut self.index {
+            index.insert(ident.to_owned(), self.names.len());
+        } else if self.names.len() + 1 >= INDEX_AT {
+            let mut index = self
+                .names
+                .iter()
+                .enumerate()
+                .map(|(i, name)| (name.clone(), i))
+                .collect::<HashMap<_, _>>();
+            index.insert(ident.to_owned(), self.names.len());
+            self.index = Some(index);
+        }
+        self.names.push(ident.to_owned());
+        self.slots.push(val);
+    }
+}
+
 impl Environment {
     pub fn new() -> Self {
-        let env_node = EnvNode {
-            bindings: HashMap::new(),
-            parent: None,
-        };
         Environment {
-            root: Some(Rc::new(RefCell::new(env_node))),
+            root: Some(track(EnvNode::new(None))),
+        }
+    }
+
+    // The global environment of the interpreter currently evaluating, if there is one
+    pub fn interaction() -> Option<Environment> {
+        INTERACTION.with(|env| env.borrow().clone())
+    }
+
+    pub fn set_interaction(env: Environment) {
+        INTERACTION.with(|current| *current.borrow_mut() = Some(env))
+    }
+
+    pub fn bindings_version() -> u64 {
+        BINDINGS_VERSION.with(Cell::get)
+    }
+
+    pub fn tracked() -> Vec<WeakEnvironment> {
+        TRACKED.with(|tracked| {
+            let mut tracked = tracked.borrow_mut();
+            tracked.prune();
+            tracked
+                .nodes
+                .iter()
+                .map(|node| WeakEnvironment(node.clone()))
+                .collect()
+        })
+    }
+
+    pub fn tracked_count() -> usize {
+        TRACKED.with(|tracked| {
+            let mut tracked = tracked.borrow_mut();
+            tracked.prune();
+            tracked.nodes.len()
+        })
+    }
+
+    // Traces this frame's contents, without counting a reference to the frame itself
+    pub fn trace_bindings(&self, tracer: &mut Tracer) {
+        if let Some(root) = &self.root {
+            root.as_ref().trace(tracer);
+        }
+    }
+
+    // Empties the frame for the collector. The contents are returned so they can be dropped
+    // after every garbage frame has been emptied.
+    pub fn take_bindings(&self) -> Option<(Vec<Exp>, Option<Environment>)> {
+        let mut node = self.root.as_ref()?.try_borrow_mut().ok()?;
+        bindings_changed();
+        node.names.clear();
+        node.index = None;
+        let bindings = std::mem::take(&mut node.slots);
+        let parent = node
+            .parent
+            .take()
+            .map(|root| Environment { root: Some(root) });
+        Some((bindings, parent))
+    }
+
+    pub fn ptr_eq(&self, other: &Environment) -> bool {
+        match (&self.root, &other.root) {
+            (Some(a), Some(b)) => Rc::ptr_eq(a, b),
+            (None, None) => true,
+            _ => false,
         }
     }
 
@@ -35,22 +202,19 @@ impl Environment {
     }
 
     pub fn extend(&self) -> Self {
-        let env_node = EnvNode {
-            bindings: HashMap::new(),
-            parent: self.root.clone(),
-        };
         Environment {
-            root: Some(Rc::new(RefCell::new(env_node))),
+            root: Some(track(EnvNode::new(self.root.clone()))),
         }
     }
 
     pub fn define(&mut self, ident: &str, val: &Exp) -> Result<(), String> {
         // Attempting to define on an environment with no root node is nonsense
         let mut borrow = self.root.as_ref().unwrap().borrow_mut();
-        if borrow.bindings.contains_key(ident) {
+        if borrow.position(ident).is_some() {
             Err("Identifier already defined".to_owned())
         } else {
-            borrow.bindings.insert(ident.to_owned(), val.clone());
+            borrow.push(ident, val.clone());
+            bindings_changed();
             Ok(())
         }
     }
@@ -61,10 +225,13 @@ impl Environment {
             .as_ref()
             .ok_or("Identifier does not exist in environment".to_owned())?;
         let mut borrow = root_link.borrow_mut();
-        if borrow.bindings.contains_key(ident) {
-            borrow.bindings.insert(ident.to_owned(), val.clone());
+        if let Some(i) = borrow.position(ident) {
+            borrow.slots[i] = val.clone();
+            bindings_changed();
             Ok(())
         } else {
+            // Release this frame before walking up, since parent() needs to borrow it too
+            drop(borrow);
             let mut parent = self
                 .parent()
                 .ok_or("Identifier does not exist in environment".to_owned())?;
@@ -72,28 +239,288 @@ impl Environment {
         }
     }
 
-    pub fn lookup(&self, ident: &str) -> Option<Exp> {
-        self.root.as_ref().and_then(|root_link| {
-            root_link
-                .borrow()
-                .bindings
-                .get(ident)
+    // The bindings of this frame only, not including any parents
+    pub fn local_bindings(&self) -> Vec<(String, Exp)> {
+        self.root.as_ref().map_or_else(Vec::new, |root_link| {
+            let node = root_link.borrow();
+            node.names
+                .iter()
                 .cloned()
-                .or_else(|| self.parent().and_then(|parent| parent.lookup(ident)))
+                .zip(node.slots.iter().cloned())
+                .collect()
         })
     }
+
+    pub fn lookup(&self, ident: &str) -> Option<Exp> {
+        let mut link = self.root.clone();
+        while let Some(node) = link {
+            let node = node.borrow();
+            if let Some(i) = node.position(ident) {
+                return Some(node.slots[i].clone());
+            }
+            link = node.parent.clone();
+        }
+        None
+    }
+
+    // The frame the name is bound in, if any is
+    pub fn binder(&self, ident: &str) -> Option<Environment> {
+        let mut link = self.root.clone();
+        while let Some(node) = link {
+            if node.borrow().position(ident).is_some() {
+                return Some(Environment { root: Some(node) });
+            }
+            link = node.borrow().parent.clone();
+        }
+        None
+    }
+
+    // The frame depth levels up from this one
+    pub fn ancestor(&self, depth: usize) -> Option<Environment> {
+        let mut link = self.root.clone();
+        for _ in 0..depth {
+            link = link?.borrow().parent.clone();
+        }
+        link.map(|root| Environment { root: Some(root) })
+    }
+
+    // The value in a slot of the frame depth levels up, if that frame has bound it yet
+    pub fn slot(&self, depth: usize, index: usize) -> Option<Exp> {
+        let mut node = self.root.clone()?;
+        for _ in 0..depth {
+            let parent = node.borrow().parent.clone()?;
+            node = parent;
+        }
+        let node = node.borrow();
+        node.slots.get(index).cloned()
+    }
+}
+
+impl Trace for EnvNode {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.slots.iter().for_each(|val| val.trace(tracer));
+        if let Some(parent) = &self.parent {
+            parent.trace(tracer);
+        }
+    }
+}
+
+impl Trace for Environment {
+    fn trace(&self, tracer: &mut Tracer) {
+        if let Some(root) = &self.root {
+            root.trace(tracer);
+        }
+    }
+}
+
+impl Default for Environment {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn define_builtin(env: &mut Environment, ident: &str, f: fn(&List<Exp>) -> Result<Exp, String>) {
+    env.define(ident, &Exp::Function(Function::External(f)))
+        .unwrap();
+}
+
+// Special forms are bound like any other value, so they can be shadowed and new ones can be
+// added without changing the reader
+fn define_special_form(
+    env: &mut Environment,
+    ident: &str,
+    f: fn(&List<Exp>, &mut Environment) -> Result<Exp, String>,
+) {
+    env.define(ident, &Exp::SpecialForm(f)).unwrap();
 }
 
 pub fn build_global_env() -> Environment {
     let mut env = Environment::new();
-    env.define("+", &Exp::Function(Function::External(math::add)))
-        .unwrap();
-    env.define("-", &Exp::Function(Function::External(math::subtract)))
-        .unwrap();
-    env.define("*", &Exp::Function(Function::External(math::multiply)))
-        .unwrap();
-    env.define("=", &Exp::Function(Function::External(math::equals)))
-        .unwrap();
+    define_special_form(&mut env, "if", special_forms::if_exp);
+    define_special_form(&mut env, "def", special_forms::def);
+    define_special_form(&mut env, "lambda", special_forms::lambda);
+    define_special_form(&mut env, "quote", special_forms::quote);
+    define_special_form(&mut env, "set!", special_forms::set);
+    define_special_form(&mut env, "the-environment", special_forms::the_environment);
+    define_special_form(&mut env, "module", modules::module);
+    define_special_form(&mut env, "export", modules::export);
+    define_special_form(&mut env, "import", modules::import);
+    define_special_form(&mut env, "define-syntax", macros::define_syntax);
+    define_special_form(&mut env, "let-syntax", macros::let_syntax);
+    define_special_form(&mut env, "guard", exceptions::guard);
+    define_special_form(&mut env, "handler-bind", conditions::handler_bind);
+    define_special_form(&mut env, "restart-case", conditions::restart_case);
+    define_special_form(&mut env, "defmacro", macros::defmacro);
+
+    define_builtin(&mut env, "+", math::add);
+    define_builtin(&mut env, "-", math::subtract);
+    define_builtin(&mut env, "*", math::multiply);
+    define_builtin(&mut env, "=", math::equals);
+
+    define_builtin(&mut env, "apply", evaluation::apply);
+    define_builtin(&mut env, "eval", evaluation::eval);
+    define_builtin(
+        &mut env,
+        "interaction-environment",
+        evaluation::interaction_environment,
+    );
+    define_builtin(&mut env, "make-environment", evaluation::make_environment);
+    define_builtin(&mut env, "environment?", evaluation::is_environment);
+    define_builtin(&mut env, "macroexpand-1", macros::macroexpand_1);
+    define_builtin(&mut env, "macroexpand", macros::macroexpand);
+    define_builtin(&mut env, "gensym", macros::gensym);
+    define_builtin(&mut env, "gc", gc::gc);
+    define_builtin(&mut env, "heap-stats", gc::heap_stats_builtin);
+    define_builtin(&mut env, "load", loader::load);
+    define_builtin(&mut env, "loaded?", loader::is_loaded);
+
+    define_builtin(&mut env, "eq?", equality::eqv);
+    define_builtin(&mut env, "eqv?", equality::eqv);
+    define_builtin(&mut env, "equal?", equality::equal);
+
+    env.define("nil", &Exp::List(List::new())).unwrap();
+    define_builtin(&mut env, "list", lists::list);
+    define_builtin(&mut env, "memq", lists::memq);
+    define_builtin(&mut env, "member", lists::member);
+    define_builtin(&mut env, "assq", lists::assq);
+    define_builtin(&mut env, "assoc", lists::assoc);
+
+    define_builtin(&mut env, "char?", chars::is_char);
+    define_builtin(&mut env, "char->integer", chars::char_to_integer);
+    define_builtin(&mut env, "integer->char", chars::integer_to_char);
+    define_builtin(&mut env, "char-upcase", chars::char_upcase);
+    define_builtin(&mut env, "char-alphabetic?", chars::is_char_alphabetic);
+    define_builtin(&mut env, "char-numeric?", chars::is_char_numeric);
+    define_builtin(&mut env, "char-whitespace?", chars::is_char_whitespace);
+
+    define_builtin(&mut env, "string->list", strings::string_to_list);
+    define_builtin(&mut env, "list->string", strings::list_to_string);
+
+    define_builtin(&mut env, "display", io::display);
+    define_builtin(&mut env, "write", io::write);
+    define_builtin(&mut env, "write-string", io::write_string);
+    define_builtin(&mut env, "newline", io::newline);
+    define_builtin(&mut env, "write-u8", io::write_u8);
+    define_builtin(&mut env, "read-line", io::read_line);
+    define_builtin(&mut env, "read-char", io::read_char);
+    define_builtin(&mut env, "peek-char", io::peek_char);
+    define_builtin(&mut env, "read-u8", io::read_u8);
+    define_builtin(&mut env, "peek-u8", io::peek_u8);
+    define_builtin(&mut env, "read", reader::read);
+    define_builtin(&mut env, "read-string", reader::read_string);
+    define_builtin(&mut env, "open-input-file", io::open_input_file);
+    define_builtin(&mut env, "open-output-file", io::open_output_file);
+    define_builtin(
+        &mut env,
+        "open-binary-input-file",
+        io::open_binary_input_file,
+    );
+    define_builtin(
+        &mut env,
+        "open-binary-output-file",
+        io::open_binary_output_file,
+    );
+    define_builtin(&mut env, "open-input-string", io::open_input_string);
+    define_builtin(&mut env, "open-input-bytevector", io::open_input_bytevector);
+    define_builtin(
+        &mut env,
+        "open-output-bytevector",
+        io::open_output_bytevector,
+    );
+    define_builtin(&mut env, "get-output-bytevector", io::get_output_bytevector);
+    define_builtin(
+        &mut env,
+        "call-with-output-string",
+        io::call_with_output_string,
+    );
+    define_builtin(&mut env, "with-output-to-file", io::with_output_to_file);
+    define_builtin(&mut env, "close-port", io::close_port);
+    define_builtin(&mut env, "current-input-port", io::current_input_port);
+    define_builtin(&mut env, "current-output-port", io::current_output_port);
+    define_builtin(&mut env, "input-port?", io::is_input_port);
+    define_builtin(&mut env, "output-port?", io::is_output_port);
+    define_builtin(&mut env, "eof-object", io::eof_object);
+    define_builtin(&mut env, "eof-object?", io::is_eof_object);
+
+    define_builtin(&mut env, "bytevector?", bytevectors::is_bytevector);
+    define_builtin(&mut env, "bytevector", bytevectors::bytevector);
+    define_builtin(&mut env, "make-bytevector", bytevectors::make_bytevector);
+    define_builtin(
+        &mut env,
+        "bytevector-u8-ref",
+        bytevectors::bytevector_u8_ref,
+    );
+    define_builtin(
+        &mut env,
+        "bytevector-u8-set!",
+        bytevectors::bytevector_u8_set,
+    );
+    define_builtin(
+        &mut env,
+        "bytevector-length",
+        bytevectors::bytevector_length,
+    );
+    define_builtin(&mut env, "utf8->string", bytevectors::utf8_to_string);
+    define_builtin(&mut env, "string->utf8", bytevectors::string_to_utf8);
+
+    define_builtin(&mut env, "vector", vectors::vector);
+    define_builtin(&mut env, "make-vector", vectors::make_vector);
+    define_builtin(&mut env, "vector-ref", vectors::vector_ref);
+    define_builtin(&mut env, "vector-set!", vectors::vector_set);
+    define_builtin(&mut env, "vector-length", vectors::vector_length);
+    define_builtin(&mut env, "vector->list", vectors::vector_to_list);
+    define_builtin(&mut env, "list->vector", vectors::list_to_vector);
+    define_builtin(&mut env, "vector-fill!", vectors::vector_fill);
+    define_builtin(&mut env, "vector-map", vectors::vector_map);
+    define_builtin(&mut env, "vector-for-each", vectors::vector_for_each);
+
+    define_builtin(&mut env, "make-hash-table", hash_tables::make_hash_table);
+    define_builtin(&mut env, "hash-ref", hash_tables::hash_ref);
+    define_builtin(&mut env, "hash-set!", hash_tables::hash_set);
+    define_builtin(&mut env, "hash-delete!", hash_tables::hash_delete);
+    define_builtin(&mut env, "hash-contains?", hash_tables::hash_contains);
+    define_builtin(&mut env, "hash-keys", hash_tables::hash_keys);
+    define_builtin(&mut env, "hash-values", hash_tables::hash_values);
+    define_builtin(&mut env, "hash->alist", hash_tables::hash_to_alist);
+    define_builtin(&mut env, "hash-count", hash_tables::hash_count);
+    define_builtin(&mut env, "hash-update!", hash_tables::hash_update);
+
+    define_builtin(&mut env, "dissoc", persistent::dissoc);
+    define_builtin(&mut env, "get", persistent::get);
+    define_builtin(&mut env, "contains?", persistent::contains);
+    define_builtin(&mut env, "conj", persistent::conj);
+    define_builtin(&mut env, "union", persistent::union);
+    define_builtin(&mut env, "intersection", persistent::intersection);
+    define_builtin(&mut env, "difference", persistent::difference);
+    define_builtin(&mut env, "error", exceptions::error);
+    define_builtin(&mut env, "raise", exceptions::raise);
+    define_builtin(&mut env, "raise-continuable", exceptions::raise_continuable);
+    define_builtin(&mut env, "signal", conditions::signal);
+    define_builtin(&mut env, "invoke-restart", conditions::invoke_restart);
+    define_builtin(
+        &mut env,
+        "with-exception-handler",
+        exceptions::with_exception_handler,
+    );
+    define_builtin(&mut env, "error-object?", exceptions::is_error_object);
+    define_builtin(
+        &mut env,
+        "error-object-message",
+        exceptions::error_object_message,
+    );
+    define_builtin(
+        &mut env,
+        "error-object-irritants",
+        exceptions::error_object_irritants,
+    );
+    define_builtin(&mut env, "call/cc", continuations::call_cc);
+    define_builtin(
+        &mut env,
+        "call-with-current-continuation",
+        continuations::call_cc,
+    );
+    let dynamic_wind = continuations::dynamic_wind(&env);
+    env.define("dynamic-wind", &dynamic_wind).unwrap();
     env
 }
 
@@ -131,4 +558,17 @@ mod test {
 
         assert!(child.lookup("z").is_none());
     }
+
+    #[test]
+    fn assign_through_layers() {
+        let mut parent = Environment::new();
+        parent.define("x", &Exp::Number(1.0)).unwrap();
+        let mut child = parent.extend();
+
+        child.assign("x", &Exp::Number(2.0)).unwrap();
+        let x = parent.lookup("x").unwrap();
+        assert!(matches!(x, Exp::Number(val) if val == 2.0));
+
+        assert!(child.assign("y", &Exp::Number(3.0)).is_err());
+    }
 }
@@ -0,0 +1,57 @@
+use crate::{expression::Exp, list::List};
+
+fn compare_args(args: &List<Exp>, same: fn(&Exp, &Exp) -> bool) -> Result<Exp, String> {
+    let mut args_iter = args.iter();
+    let first = args_iter
+        .next()
+        .ok_or("Missing required argument".to_owned())?;
+    let second = args_iter
+        .next()
+        .ok_or("Missing required argument".to_owned())?;
+    Ok(Exp::Bool(same(first, second)))
+}
+
+pub fn eqv(args: &List<Exp>) -> Result<Exp, String> {
+    compare_args(args, Exp::is_eqv)
+}
+
+pub fn equal(args: &List<Exp>) -> Result<Exp, String> {
+    compare_args(args, Exp::eq)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{cell::RefCell, rc::Rc};
+
+    use crate::{expression::Exp, list::List};
+
+    #[test]
+    fn identity_and_structure() {
+        let list = Exp::List(List::from_vec(vec![Exp::Number(1.0), Exp::Str("a".into())]));
+        let same_contents = Exp::List(List::from_vec(vec![Exp::Number(1.0), Exp::Str("a".into())]));
+        assert!(list.is_eqv(&list.clone()));
+        assert!(!list.is_eqv(&same_contents));
+        assert_eq!(list, same_contents);
+
+        let vec = Exp::Vector(Rc::new(RefCell::new(vec![Exp::Char('x')])));
+        let same_contents = Exp::Vector(Rc::new(RefCell::new(vec![Exp::Char('x')])));
+        assert!(!vec.is_eqv(&same_contents));
+        assert_eq!(vec, same_contents);
+
+        // Vectors that contain themselves
+        let a = Rc::new(RefCell::new(vec![Exp::Number(1.0)]));
+        let b = Rc::new(RefCell::new(vec![Exp::Number(1.0)]));
+        a.borrow_mut()[0] = Exp::Vector(a.clone());
+        b.borrow_mut()[0] = Exp::Vector(b.clone());
+        assert_eq!(Exp::Vector(a.clone()), Exp::Vector(b.clone()));
+        b.borrow_mut().push(Exp::Number(2.0));
+        assert_ne!(Exp::Vector(a.clone()), Exp::Vector(b.clone()));
+        // Break the cycles, so the vectors can be freed
+        a.borrow_mut().clear();
+        b.borrow_mut().clear();
+
+        assert!(Exp::Number(0.0).is_eqv(&Exp::Number(-0.0)));
+        assert!(Exp::Ident("a".to_owned()).is_eqv(&Exp::Ident("a".to_owned())));
+        assert_ne!(Exp::Number(1.0), Exp::Bool(true));
+    }
+}
@@ -0,0 +1,59 @@
+use crate::{
+    environment::Environment,
+    expression::{Exp, Function},
+    list::List,
+    vm,
+};
+
+fn environment_arg(arg: Option<&Exp>) -> Result<Environment, String> {
+    match arg {
+        None => Environment::interaction().ok_or("No interaction environment".to_owned()),
+        Some(Exp::Environment(env)) => Ok(env.clone()),
+        Some(_) => Err("Type error".to_owned()),
+    }
+}
+
+// The function and arguments (apply f a b '(c d)) calls f with, which are a b c d
+pub fn spread(args: &List<Exp>) -> Result<(Function, List<Exp>), String> {
+    let mut args_iter = args.iter();
+    let f = match args_iter.next() {
+        Some(Exp::Function(f)) => f.clone(),
+        _ => return Err("Type error".to_owned()),
+    };
+    let mut call_args = args_iter.cloned().collect::<Vec<Exp>>();
+    match call_args.pop() {
+        Some(Exp::List(rest)) => call_args.extend(rest.iter().cloned()),
+        _ => return Err("Last argument to apply must be a list".to_owned()),
+    }
+    Ok((f, List::from_vec(call_args)))
+}
+
+pub fn apply(args: &List<Exp>) -> Result<Exp, String> {
+    let (f, args) = spread(args)?;
+    f.call(&args)
+}
+
+pub fn eval(args: &List<Exp>) -> Result<Exp, String> {
+    let mut args_iter = args.iter();
+    let exp = args_iter
+        .next()
+        .ok_or("Missing required argument".to_owned())?;
+    let mut env = environment_arg(args_iter.next())?;
+    // Code built at runtime isn't resolved, so the tree-walker looks it up by name
+    vm::run_compiled(exp, &env).unwrap_or_else(|| crate::eval(exp, &mut env))
+}
+
+pub fn interaction_environment(_args: &List<Exp>) -> Result<Exp, String> {
+    environment_arg(None).map(Exp::Environment)
+}
+
+// A new, empty environment whose parent is the given one, or the interaction environment
+pub fn make_environment(args: &List<Exp>) -> Result<Exp, String> {
+    let parent = environment_arg(args.head())?;
+    Ok(Exp::Environment(parent.extend()))
+}
+
+pub fn is_environment(args: &List<Exp>) -> Result<Exp, String> {
+    let arg = args.head().ok_or("Missing required argument".to_owned())?;
+    Ok(Exp::Bool(matches!(arg, Exp::Environment(_))))
+}
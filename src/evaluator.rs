@@ -0,0 +1,39 @@
+use std::cell::Cell;
+
+// Which evaluator runs top-level forms. See the README for how they differ.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Evaluator {
+    // Walks the expression tree directly, kept as the reference implementation
+    TreeWalker,
+    // Compiles each form to bytecode for the stack VM
+    Bytecode,
+}
+
+thread_local! {
+    static CURRENT: Cell<Evaluator> = const { Cell::new(Evaluator::Bytecode) };
+}
+
+impl Evaluator {
+    pub fn current() -> Self {
+        CURRENT.with(Cell::get)
+    }
+
+    pub fn set_current(self) {
+        CURRENT.with(|current| current.set(self))
+    }
+}
+
+impl std::str::FromStr for Evaluator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "tree-walker" => Ok(Evaluator::TreeWalker),
+            "bytecode" => Ok(Evaluator::Bytecode),
+            _ => Err(format!(
+                "Unknown evaluator '{}', expected tree-walker or bytecode",
+                s
+            )),
+        }
+    }
+}
@@ -0,0 +1,342 @@
+use std::{cell::RefCell, rc::Rc};
+
+use crate::{
+    backtrace,
+    continuations::UNWINDING,
+    environment::Environment,
+    expression::Exp,
+    gc::{Trace, Tracer},
+    limits::LimitExceeded,
+    list::List,
+    machine::{self, Native},
+};
+
+// Errors are still returned as messages, so they can pass through every builtin. A raised object
+// is kept to the side with the message it was raised as, and handlers get it back by that
+// message. Any other error, like one a builtin returns, is handed to them as an error object.
+
+pub struct ErrorObject {
+    pub message: String,
+    pub irritants: List<Exp>,
+}
+
+// Something in the dynamic extent of which conditions are handled
+pub enum Handler {
+    // From handler-bind, called where a matching condition is signalled. Returning declines.
+    Bind { predicate: Exp, handler: Exp },
+    // From with-exception-handler
+    Exception(Exp),
+    // From guard, which catches what's raised once it has unwound to it
+    Guard,
+}
+
+thread_local! {
+    static RAISED: RefCell<Option<(String, Exp)>> = const { RefCell::new(None) };
+    // An error that handlers have already been offered, so it isn't signalled again on its way
+    // out through further builtins
+    static SIGNALLED: RefCell<Option<String>> = const { RefCell::new(None) };
+    // Innermost first
+    static HANDLERS: RefCell<List<Rc<Handler>>> = RefCell::new(List::new());
+}
+
+// The error to return for raising an object
+pub fn raise_err(obj: Exp) -> String {
+    let message = match &obj {
+        Exp::Error(error) => error_message(error),
+        obj => format!("Uncaught exception: {}", obj),
+    };
+    raise_as(&message, obj);
+    message
+}
+
+// Makes obj what's caught for an error with the given message
+pub fn raise_as(message: &str, obj: Exp) {
+    RAISED.with(|raised| *raised.borrow_mut() = Some((message.to_owned(), obj)));
+}
+
+// Marks an error as offered to the handlers. Returns whether it already had been.
+pub fn mark_signalled(err: &str) -> bool {
+    SIGNALLED.with(|signalled| {
+        let mut signalled = signalled.borrow_mut();
+        if signalled.as_deref() == Some(err) {
+            true
+        } else {
+            *signalled = Some(err.to_owned());
+            false
+        }
+    })
+}
+
+// Forgets the error last offered to the handlers, once it's been dealt with
+pub fn clear_signalled() {
+    SIGNALLED.with(|signalled| *signalled.borrow_mut() = None);
+}
+
+// The object a handler is given for an error, without taking it from where it's kept
+pub fn condition(err: &str) -> Exp {
+    RAISED
+        .with(|raised| match &*raised.borrow() {
+            Some((message, obj)) if message == err => Some(obj.clone()),
+            _ => None,
+        })
+        .unwrap_or_else(|| error_object(err))
+}
+
+fn error_object(message: &str) -> Exp {
+    Exp::Error(Rc::new(ErrorObject {
+        message: message.to_owned(),
+        irritants: List::new(),
+    }))
+}
+
+// Adds context to an error's message, keeping what was raised with it. Resuming a continuation
+// passes through unchanged.
+pub fn annotate(err: String, f: impl FnOnce(&str) -> String) -> String {
+    if err == UNWINDING {
+        return err;
+    }
+    let annotated = f(&err);
+    RAISED.with(|raised| {
+        if let Some((message, _)) = &mut *raised.borrow_mut() {
+            if *message == err {
+                *message = annotated.clone();
+            }
+        }
+    });
+    SIGNALLED.with(|signalled| {
+        if let Some(message) = &mut *signalled.borrow_mut() {
+            if *message == err {
+                *message = annotated.clone();
+            }
+        }
+    });
+    backtrace::rename(&err, &annotated);
+    annotated
+}
+
+fn error_message(error: &ErrorObject) -> String {
+    error
+        .irritants
+        .iter()
+        .fold(error.message.clone(), |message, irritant| {
+            format!("{} {}", message, irritant)
+        })
+}
+
+// The object an error was raised with, or an error object for one that wasn't raised by Lisp.
+// Resuming a continuation isn't an error and can't be caught, and neither can running into a
+// limit, which has to stop the evaluation.
+pub fn caught(err: &str) -> Option<Exp> {
+    if err == UNWINDING || LimitExceeded::of(err).is_some() {
+        return None;
+    }
+    clear_signalled();
+    backtrace::clear();
+    let raised = RAISED.with(|raised| {
+        let mut raised = raised.borrow_mut();
+        match &*raised {
+            Some((message, _)) if message == err => raised.take().map(|(_, obj)| obj),
+            _ => None,
+        }
+    });
+    Some(raised.unwrap_or_else(|| error_object(err)))
+}
+
+pub fn handlers() -> List<Rc<Handler>> {
+    HANDLERS.with(|handlers| handlers.borrow().clone())
+}
+
+// Installs handlers, returning the ones that were installed before
+pub fn set_handlers(handlers: List<Rc<Handler>>) -> List<Rc<Handler>> {
+    HANDLERS.with(|current| current.replace(handlers))
+}
+
+pub fn push_handler(handler: Handler) -> List<Rc<Handler>> {
+    set_handlers(handlers().prepend(Rc::new(handler)))
+}
+
+pub fn call(f: &Exp, args: Vec<Exp>) -> Result<Exp, String> {
+    match f {
+        Exp::Function(f) => f.call(&List::from_vec(args)),
+        _ => Err("Type error".to_owned()),
+    }
+}
+
+pub fn error(args: &List<Exp>) -> Result<Exp, String> {
+    let message = match args.head().ok_or("Missing required argument".to_owned())? {
+        Exp::Str(message) => message.to_string(),
+        _ => return Err("Type error".to_owned()),
+    };
+    let irritants = args.tail().unwrap_or_default();
+    Err(raise_err(Exp::Error(Rc::new(ErrorObject {
+        message,
+        irritants,
+    }))))
+}
+
+pub fn raise(args: &List<Exp>) -> Result<Exp, String> {
+    let obj = args.head().ok_or("Missing required argument".to_owned())?;
+    Err(raise_err(obj.clone()))
+}
+
+// Calls the current handler right away, with the handlers outside it installed, and returns
+// what it returns. Handlers from handler-bind inside it are offered the object first.
+pub fn raise_continuable(args: &List<Exp>) -> Result<Exp, String> {
+    let obj = args.head().ok_or("Missing required argument".to_owned())?;
+    let mut handlers = handlers();
+    while let Some(handler) = handlers.head().cloned() {
+        handlers = handlers.tail().unwrap();
+        match &*handler {
+            Handler::Bind { .. } => call_bound(&handler, obj, &handlers)?,
+            Handler::Exception(f) => {
+                let outer = set_handlers(handlers);
+                let result = call(f, vec![obj.clone()]);
+                set_handlers(outer);
+                return result;
+            }
+            Handler::Guard => break,
+        }
+    }
+    let err = raise_err(obj.clone());
+    mark_signalled(&err);
+    Err(err)
+}
+
+// Calls a handler-bind handler if its predicate accepts the condition, with the handlers outside
+// it installed
+pub fn call_bound(
+    handler: &Handler,
+    condition: &Exp,
+    outside: &List<Rc<Handler>>,
+) -> Result<(), String> {
+    let Handler::Bind { predicate, handler } = handler else {
+        return Ok(());
+    };
+    let outer = set_handlers(outside.clone());
+    let result = call(predicate, vec![condition.clone()]).and_then(|matched| {
+        if matched.is_truthy() {
+            call(handler, vec![condition.clone()]).map(|_| ())
+        } else {
+            Ok(())
+        }
+    });
+    set_handlers(outer);
+    result
+}
+
+// (with-exception-handler handler thunk) calls thunk, and handler with anything raised while it
+// runs. The handler is called with the handlers outside it installed. If it returns from a raise
+// that isn't continuable, that's an error too.
+pub fn with_exception_handler(args: &List<Exp>) -> Result<Exp, String> {
+    let (handler, thunk) = handler_and_thunk(args)?;
+    let outer = push_handler(Handler::Exception(handler.clone()));
+    let result = call(&thunk, Vec::new());
+    set_handlers(outer);
+    match result {
+        Err(err) => match caught(&err) {
+            Some(obj) => {
+                call(&handler, vec![obj])?;
+                Err("Exception handler returned".to_owned())
+            }
+            None => Err(err),
+        },
+        result => result,
+    }
+}
+
+pub fn handler_and_thunk(args: &List<Exp>) -> Result<(Exp, Exp), String> {
+    let mut args = args.iter();
+    let handler = args.next().ok_or("Missing required argument".to_owned())?;
+    let thunk = args.next().ok_or("Missing required argument".to_owned())?;
+    if !matches!(handler, Exp::Function(_)) {
+        return Err("Type error".to_owned());
+    }
+    Ok((handler.clone(), thunk.clone()))
+}
+
+// (guard (var clause...) body...) evaluates body, and if anything is raised, the clauses with var
+// bound to it. The clauses are like cond's: (test expr...), (test => f) or (else expr...). If
+// none of them match, the object is raised again.
+pub fn guard(args: &List<Exp>, env: &mut Environment) -> Result<Exp, String> {
+    machine::special_form(Native::Guard, args, env)
+}
+
+// The variable, clauses and body of a guard
+pub fn guard_parts(args: &List<Exp>) -> Result<(String, List<Exp>, List<Exp>), String> {
+    match args.head().ok_or("Type error".to_owned())? {
+        Exp::List(spec) => match spec.head() {
+            Some(Exp::Ident(var)) => Ok((var.clone(), spec.tail().unwrap(), args.tail().unwrap())),
+            _ => Err("Type error".to_owned()),
+        },
+        _ => Err("Type error".to_owned()),
+    }
+}
+
+fn error_arg(args: &List<Exp>) -> Result<&ErrorObject, String> {
+    match args.head() {
+        Some(Exp::Error(error)) => Ok(error),
+        Some(_) => Err("Type error".to_owned()),
+        None => Err("Missing required argument".to_owned()),
+    }
+}
+
+pub fn is_error_object(args: &List<Exp>) -> Result<Exp, String> {
+    let arg = args.head().ok_or("Missing required argument".to_owned())?;
+    Ok(Exp::Bool(matches!(arg, Exp::Error(_))))
+}
+
+pub fn error_object_message(args: &List<Exp>) -> Result<Exp, String> {
+    Ok(Exp::Str(error_arg(args)?.message.as_str().into()))
+}
+
+pub fn error_object_irritants(args: &List<Exp>) -> Result<Exp, String> {
+    Ok(Exp::List(error_arg(args)?.irritants.clone()))
+}
+
+impl Trace for ErrorObject {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.irritants.trace(tracer);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{evaluator::Evaluator, Interpreter};
+
+    #[test]
+    fn catches() {
+        let cases = [
+            ("(guard (e (#t (error-object-message e))) (+ 1 \"a\"))", Ok("Type error")),
+            (
+                "(guard (e ((error-object? e) (list (error-object-message e) (error-object-irritants e)))) (error \"Bad thing:\" 1 2))",
+                Ok("(Bad thing: (1 2))"),
+            ),
+            ("(guard (e ((= e 1) 'one)) (guard (e ((= e 2) 'two)) (raise 1)))", Ok("one")),
+            ("(guard (e ((= e 1) 'one)) (raise 42))", Err("Uncaught exception: 42")),
+            (
+                "(with-exception-handler (lambda (e) 10) (lambda () (+ 1 (raise-continuable 5))))",
+                Ok("11"),
+            ),
+            (
+                "(with-exception-handler (lambda (e) 10) (lambda () (+ 1 (raise 5))))",
+                Err("Exception handler returned"),
+            ),
+            (
+                "(call/cc (lambda (k) (with-exception-handler (lambda (e) (k (list 'handled e))) (lambda () (raise 'oops)))))",
+                Ok("(handled oops)"),
+            ),
+            ("(error \"Something broke:\" 'x \"y\")", Err("Something broke: x \"y\"")),
+        ];
+        for evaluator in [Evaluator::Bytecode, Evaluator::TreeWalker] {
+            let mut interpreter = Interpreter::new();
+            interpreter.set_evaluator(evaluator);
+            for (form, expected) in cases {
+                let result = interpreter
+                    .eval_str(form)
+                    .map(|val| val.display_form().to_string());
+                let expected = expected.map(str::to_owned).map_err(str::to_owned);
+                assert_eq!(result, expected, "{:?} {}", evaluator, form);
+            }
+        }
+    }
+}
@@ -1,89 +1,426 @@
-use crate::{environment::Environment, eval, list::List};
+use crate::{
+    conditions, continuations::Continuation, environment::Environment, exceptions::ErrorObject,
+    hamt::Hamt, list::List, machine, macros::Macro, ports::Port, resolver::Resolved,
+    truthiness::Truthiness, vm::Closure,
+};
 use core::fmt;
+use std::{
+    cell::{OnceCell, RefCell},
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    mem, ptr,
+    rc::Rc,
+};
 
 #[derive(Clone)]
 pub struct Lambda {
     pub closing_env: Environment,
     pub params: Vec<String>,
     pub body: Box<Exp>,
+    pub name: OnceCell<String>,
 }
 
 #[derive(Clone)]
 pub enum Function {
-    Lambda(Lambda),
+    Lambda(Rc<Lambda>),
     External(fn(&List<Exp>) -> Result<Exp, String>),
+    Compiled(Rc<Closure>),
+    Continuation(Rc<Continuation>),
 }
 
 impl Function {
+    pub fn ptr_eq(&self, other: &Function) -> bool {
+        match (self, other) {
+            (Function::Lambda(a), Function::Lambda(b)) => Rc::ptr_eq(a, b),
+            (Function::External(a), Function::External(b)) => ptr::fn_addr_eq(*a, *b),
+            (Function::Compiled(a), Function::Compiled(b)) => Rc::ptr_eq(a, b),
+            (Function::Continuation(a), Function::Continuation(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+
+    // The name of the first def that bound the function, for backtraces
+    pub fn name(&self) -> Option<String> {
+        match self {
+            Function::Lambda(lambda) => lambda.name.get().cloned(),
+            Function::Compiled(closure) => closure.name.get().cloned(),
+            Function::External(_) | Function::Continuation(_) => None,
+        }
+    }
+
+    pub fn name_as(&self, name: &str) {
+        let cell = match self {
+            Function::Lambda(lambda) => &lambda.name,
+            Function::Compiled(closure) => &closure.name,
+            Function::External(_) | Function::Continuation(_) => return,
+        };
+        cell.get_or_init(|| name.to_owned());
+    }
+
     pub fn call(&self, args: &List<Exp>) -> Result<Exp, String> {
         match self {
-            Function::External(f) => f(args),
-            Function::Lambda(lambda) => {
-                let mut evaluation_env = lambda.closing_env.extend();
-                let mut remaining_args = args.clone();
-                for ident in &lambda.params {
-                    let arg = remaining_args
-                        .head()
-                        .ok_or("Missing required argument".to_owned())?;
-                    evaluation_env.define(ident, arg)?;
-                    remaining_args = remaining_args.tail().expect("List with head but no tail");
-                }
-                eval(&lambda.body, &mut evaluation_env)
-            }
+            Function::External(f) => conditions::call_builtin(*f, args),
+            Function::Continuation(continuation) => continuation.resume(args),
+            f => machine::call(f, args),
         }
     }
 }
 
 #[derive(Clone)]
 pub enum Exp {
+    // The result of forms that are only evaluated for their side effects
+    Void,
+    // Returned by reads once the input is exhausted
+    Eof,
+    // What the VM keeps in the slot of a local that hasn't been defined yet. Reading it is an
+    // error, so it never gets any further.
+    Unbound,
     Ident(String),
+    // An identifier in a function body whose binding was found before evaluating it
+    Resolved(Rc<Resolved>),
     Number(f32),
     Bool(bool),
+    Char(char),
+    Str(Rc<str>),
     SpecialForm(fn(&List<Exp>, &mut Environment) -> Result<Exp, String>),
     Function(Function),
     List(List<Exp>),
+    Vector(Rc<RefCell<Vec<Exp>>>),
+    HashTable(Rc<RefCell<HashMap<Exp, Exp>>>),
+    Map(Hamt<Exp, Exp>),
+    Set(Hamt<Exp, ()>),
+    Bytevector(Rc<RefCell<Vec<u8>>>),
+    Port(Rc<Port>),
+    Environment(Environment),
+    Macro(Rc<Macro>),
+    Error(Rc<ErrorObject>),
 }
 
 impl Exp {
     pub fn is_truthy(&self) -> bool {
         match self {
-            Exp::Number(x) => *x != 0.0,
             Exp::Bool(b) => *b,
+            _ if Truthiness::current() == Truthiness::Strict => true,
+            Exp::Number(x) => *x != 0.0,
             Exp::List(lst) => lst.iter().next().is_some(),
             _ => true,
         }
     }
+
+    // eqv? semantics: atoms compare by value, everything else by identity. Numbers, characters
+    // and symbols are immediate values here, so this is also what eq? means.
+    pub fn is_eqv(&self, other: &Exp) -> bool {
+        match (self, other) {
+            (Exp::Void, Exp::Void) => true,
+            (Exp::Eof, Exp::Eof) => true,
+            (Exp::Number(a), Exp::Number(b)) => normalized_bits(*a) == normalized_bits(*b),
+            (Exp::Bool(a), Exp::Bool(b)) => a == b,
+            (Exp::Char(a), Exp::Char(b)) => a == b,
+            (Exp::Ident(a), Exp::Ident(b)) => a == b,
+            (Exp::Str(a), Exp::Str(b)) => Rc::ptr_eq(a, b),
+            (Exp::List(a), Exp::List(b)) => a.ptr_eq(b),
+            (Exp::Vector(a), Exp::Vector(b)) => Rc::ptr_eq(a, b),
+            (Exp::HashTable(a), Exp::HashTable(b)) => Rc::ptr_eq(a, b),
+            (Exp::Map(a), Exp::Map(b)) => a.ptr_eq(b),
+            (Exp::Set(a), Exp::Set(b)) => a.ptr_eq(b),
+            (Exp::Bytevector(a), Exp::Bytevector(b)) => Rc::ptr_eq(a, b),
+            (Exp::Port(a), Exp::Port(b)) => Rc::ptr_eq(a, b),
+            (Exp::Environment(a), Exp::Environment(b)) => a.ptr_eq(b),
+            (Exp::Macro(a), Exp::Macro(b)) => Rc::ptr_eq(a, b),
+            (Exp::Error(a), Exp::Error(b)) => Rc::ptr_eq(a, b),
+            (Exp::Function(a), Exp::Function(b)) => a.ptr_eq(b),
+            (Exp::SpecialForm(a), Exp::SpecialForm(b)) => ptr::fn_addr_eq(*a, *b),
+            _ => false,
+        }
+    }
+
+    // Only immutable values can be used as keys, otherwise a key could change hash while stored
+    pub fn is_hashable(&self) -> bool {
+        match self {
+            Exp::Number(_) | Exp::Str(_) | Exp::Ident(_) | Exp::Bool(_) | Exp::Char(_) => true,
+            Exp::List(list) => list.iter().all(Exp::is_hashable),
+            Exp::Map(map) => map.iter().all(|(_, val)| val.is_hashable()),
+            Exp::Set(_) => true,
+            _ => false,
+        }
+    }
+}
+
+// -0.0 and 0.0 are considered equal, so they need the same bit pattern
+fn normalized_bits(val: f32) -> u32 {
+    if val == 0.0 {
+        0
+    } else {
+        val.to_bits()
+    }
+}
+
+// Maps and sets are equal regardless of iteration order, so their hash can't depend on it either
+fn unordered_hash<'a, T: Hash + 'a>(entries: impl Iterator<Item = T>) -> u64 {
+    entries
+        .map(|entry| {
+            let mut hasher = DefaultHasher::new();
+            entry.hash(&mut hasher);
+            hasher.finish()
+        })
+        .fold(0, u64::wrapping_add)
+}
+
+// equal? semantics: compound values are compared by their contents
+impl PartialEq for Exp {
+    fn eq(&self, other: &Self) -> bool {
+        equal(self, other, None)
+    }
+}
+
+type VectorRef = *const RefCell<Vec<Exp>>;
+
+// The pairs of vectors being compared that a comparison is inside of, innermost first. Vectors
+// can contain themselves, so a pair that comes up again inside itself is taken to be equal, and
+// whether it is gets decided by the rest of the comparison.
+struct Comparing<'a> {
+    vectors: (VectorRef, VectorRef),
+    outer: Option<&'a Comparing<'a>>,
+}
+
+impl Comparing<'_> {
+    fn contains(&self, vectors: (VectorRef, VectorRef)) -> bool {
+        self.vectors == vectors || self.outer.is_some_and(|outer| outer.contains(vectors))
+    }
+}
+
+fn equal(a: &Exp, b: &Exp, comparing: Option<&Comparing>) -> bool {
+    match (a, b) {
+        (Exp::Str(a), Exp::Str(b)) => a == b,
+        (Exp::List(a), Exp::List(b)) => {
+            a.iter().count() == b.iter().count()
+                && a.iter().zip(b.iter()).all(|(a, b)| equal(a, b, comparing))
+        }
+        (Exp::Vector(a), Exp::Vector(b)) => {
+            let vectors = (Rc::as_ptr(a), Rc::as_ptr(b));
+            if Rc::ptr_eq(a, b) || comparing.is_some_and(|comparing| comparing.contains(vectors)) {
+                return true;
+            }
+            let comparing = Comparing {
+                vectors,
+                outer: comparing,
+            };
+            let (a, b) = (a.borrow(), b.borrow());
+            a.len() == b.len()
+                && a.iter()
+                    .zip(b.iter())
+                    .all(|(a, b)| equal(a, b, Some(&comparing)))
+        }
+        (Exp::Bytevector(a), Exp::Bytevector(b)) => *a.borrow() == *b.borrow(),
+        (Exp::Map(a), Exp::Map(b)) => {
+            a.len() == b.len()
+                && a.iter()
+                    .all(|(key, val)| b.get(key).is_some_and(|other| equal(val, other, comparing)))
+        }
+        (Exp::Set(a), Exp::Set(b)) => {
+            a.len() == b.len() && a.iter().all(|(key, _)| b.contains_key(key))
+        }
+        _ => a.is_eqv(b),
+    }
+}
+
+impl Eq for Exp {}
+
+impl Hash for Exp {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        mem::discriminant(self).hash(state);
+        match self {
+            Exp::Number(val) => normalized_bits(*val).hash(state),
+            Exp::Bool(val) => val.hash(state),
+            Exp::Char(val) => val.hash(state),
+            Exp::Str(val) => val.hash(state),
+            Exp::Ident(val) => val.hash(state),
+            Exp::Resolved(val) => val.name.hash(state),
+            Exp::List(list) => list.iter().for_each(|exp| exp.hash(state)),
+            // Vectors aren't hashable keys, and can contain themselves, so only the length is
+            // hashed. That still agrees with equality.
+            Exp::Vector(vec) => vec.borrow().len().hash(state),
+            Exp::HashTable(table) => Rc::as_ptr(table).hash(state),
+            Exp::Bytevector(bytes) => bytes.borrow().hash(state),
+            Exp::Port(port) => Rc::as_ptr(port).hash(state),
+            Exp::Map(map) => unordered_hash(map.iter()).hash(state),
+            Exp::Set(set) => unordered_hash(set.iter()).hash(state),
+            Exp::Void
+            | Exp::Eof
+            | Exp::Unbound
+            | Exp::SpecialForm(_)
+            | Exp::Function(_)
+            | Exp::Environment(_)
+            | Exp::Macro(_)
+            | Exp::Error(_) => {}
+        }
+    }
 }
 
 impl fmt::Debug for Exp {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
+            Exp::Void => write!(f, "Void"),
+            Exp::Eof => write!(f, "Eof"),
+            Exp::Unbound => write!(f, "Unbound"),
             Exp::Number(val) => write!(f, "Number({:?})", val),
             Exp::Bool(val) => write!(f, "Bool({:?})", val),
+            Exp::Char(val) => write!(f, "Char({:?})", val),
+            Exp::Str(val) => write!(f, "Str({:?})", val),
             Exp::Ident(val) => write!(f, "Ident({:?})", val),
+            Exp::Resolved(val) => write!(f, "Resolved({:?}, {:?})", val.name, val.address),
             Exp::Function(_val) => write!(f, "Function"),
             Exp::SpecialForm(_val) => write!(f, "SpecialForm"),
             Exp::List(val) => write!(f, "List({:?})", val),
+            Exp::Vector(val) => write!(f, "Vector({:?})", val.borrow()),
+            Exp::HashTable(val) => write!(f, "HashTable({:?})", val.borrow()),
+            Exp::Map(val) => f.debug_map().entries(val.iter()).finish(),
+            Exp::Set(val) => f
+                .debug_set()
+                .entries(val.iter().map(|(key, _)| key))
+                .finish(),
+            Exp::Bytevector(val) => write!(f, "Bytevector({:?})", val.borrow()),
+            Exp::Port(_val) => write!(f, "Port"),
+            Exp::Environment(_val) => write!(f, "Environment"),
+            Exp::Macro(_val) => write!(f, "Macro"),
+            Exp::Error(val) => write!(f, "Error({:?})", val.message),
+        }
+    }
+}
+
+// Prints an expression either as write would (readable back in) or as display would
+struct Printer<'a> {
+    exp: &'a Exp,
+    readable: bool,
+    // The vectors being printed that this is inside of, innermost first. A vector can contain
+    // itself, and is only printed as #(...) inside itself.
+    within: Option<&'a Within<'a>>,
+}
+
+struct Within<'a> {
+    vector: *const RefCell<Vec<Exp>>,
+    outer: Option<&'a Within<'a>>,
+}
+
+impl Within<'_> {
+    fn contains(&self, vector: *const RefCell<Vec<Exp>>) -> bool {
+        self.vector == vector || self.outer.is_some_and(|outer| outer.contains(vector))
+    }
+}
+
+impl Exp {
+    // The human-readable form, where strings and characters are printed as their raw text
+    pub fn display_form(&self) -> impl fmt::Display + '_ {
+        Printer {
+            exp: self,
+            readable: false,
+            within: None,
         }
     }
 }
 
 impl fmt::Display for Exp {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
+        let printer = Printer {
+            exp: self,
+            readable: true,
+            within: None,
+        };
+        write!(f, "{}", printer)
+    }
+}
+
+// Writes elements as they go, so a writer that has seen enough can stop it
+fn write_seq<T: fmt::Display>(
+    f: &mut fmt::Formatter,
+    open: &str,
+    elems: impl Iterator<Item = T>,
+    close: &str,
+) -> fmt::Result {
+    write!(f, "{}", open)?;
+    for (index, elem) in elems.enumerate() {
+        if index > 0 {
+            write!(f, " ")?;
+        }
+        write!(f, "{}", elem)?;
+    }
+    write!(f, "{}", close)
+}
+
+impl fmt::Display for Printer<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let nested = |exp| Printer {
+            exp,
+            readable: self.readable,
+            within: self.within,
+        };
+        match self.exp {
+            Exp::Void => write!(f, "#void#"),
+            Exp::Eof => write!(f, "#eof#"),
+            Exp::Unbound => write!(f, "#unbound#"),
             Exp::Number(val) => write!(f, "{}", val),
             Exp::Bool(val) => write!(f, "#{}", val.to_string().chars().next().unwrap()),
+            Exp::Char(val) if !self.readable => write!(f, "{}", val),
+            Exp::Char(val) => match val {
+                ' ' => write!(f, "#\\space"),
+                '\n' => write!(f, "#\\newline"),
+                '\t' => write!(f, "#\\tab"),
+                '\0' => write!(f, "#\\nul"),
+                c => write!(f, "#\\{}", c),
+            },
+            Exp::Str(val) if !self.readable => write!(f, "{}", val),
+            Exp::Str(val) => {
+                write!(f, "\"")?;
+                for c in val.chars() {
+                    match c {
+                        '"' => write!(f, "\\\"")?,
+                        '\\' => write!(f, "\\\\")?,
+                        '\n' => write!(f, "\\n")?,
+                        '\t' => write!(f, "\\t")?,
+                        '\r' => write!(f, "\\r")?,
+                        c => write!(f, "{}", c)?,
+                    }
+                }
+                write!(f, "\"")
+            }
             Exp::Ident(val) => write!(f, "{}", val),
+            Exp::Resolved(val) => write!(f, "{}", val.name),
             Exp::Function(_val) => write!(f, "#function#"),
             Exp::SpecialForm(_val) => write!(f, "#specialform#"),
-            Exp::List(list) => {
-                let body = list
+            Exp::List(list) => write_seq(f, "(", list.iter().map(nested), ")"),
+            Exp::Vector(vec)
+                if self
+                    .within
+                    .is_some_and(|within| within.contains(Rc::as_ptr(vec))) =>
+            {
+                write!(f, "#(...)")
+            }
+            Exp::Vector(vec) => {
+                let within = Within {
+                    vector: Rc::as_ptr(vec),
+                    outer: self.within,
+                };
+                let elems = vec.borrow();
+                let elems = elems.iter().map(|exp| Printer {
+                    exp,
+                    readable: self.readable,
+                    within: Some(&within),
+                });
+                write_seq(f, "#(", elems, ")")
+            }
+            Exp::HashTable(_val) => write!(f, "#hashtable#"),
+            Exp::Map(map) => {
+                let pairs = map
                     .iter()
-                    .map(|x| format!("{}", x))
-                    .collect::<Vec<String>>()
-                    .join(" ");
-                write!(f, "({})", body)
+                    .map(|(key, val)| format!("{} {}", nested(key), nested(val)));
+                write_seq(f, "{", pairs, "}")
             }
+            Exp::Set(set) => write_seq(f, "#{", set.iter().map(|(key, _)| nested(key)), "}"),
+            Exp::Bytevector(bytes) => write_seq(f, "#u8(", bytes.borrow().iter(), ")"),
+            Exp::Port(_val) => write!(f, "#port#"),
+            Exp::Environment(_val) => write!(f, "#environment#"),
+            Exp::Macro(_val) => write!(f, "#macro#"),
+            Exp::Error(_val) => write!(f, "#error#"),
         }
     }
 }
@@ -0,0 +1,340 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::{hash_map::Entry, HashMap, HashSet},
+    mem,
+    rc::Rc,
+};
+
+use crate::{
+    environment::Environment,
+    expression::{Exp, Function, Lambda},
+    hamt::Hamt,
+    list::List,
+    vm,
+};
+
+// A backup cycle collector. Values are reference counted, which frees everything except cycles,
+// and the cycles that matter all run through a frame of variables: a closure defined in a frame
+// keeps that frame alive through its closing environment, or for compiled code through the
+// captured variables it shares. So collection starts from every live frame and traces what they
+// reference, counting the references found along the way. An object with more references than
+// were found is also referenced from outside the traced graph (the Rust stack, an embedder, the
+// interpreter itself) and is a root. Frames that can't be reached from a root are garbage, and
+// emptying them breaks the cycles they're part of.
+
+pub trait Trace {
+    fn trace(&self, tracer: &mut Tracer);
+}
+
+struct Object {
+    strong: usize,
+    internal: usize,
+    children: Vec<*const ()>,
+}
+
+pub struct Tracer {
+    current: *const (),
+    objects: HashMap<*const (), Object>,
+}
+
+impl Tracer {
+    // Records a reference from the object being traced to another. Returns whether this is the
+    // first time the other object has been seen, in which case its contents should be traced.
+    pub fn edge(&mut self, ptr: *const (), strong: usize) -> bool {
+        if let Some(current) = self.objects.get_mut(&self.current) {
+            current.children.push(ptr);
+        }
+        match self.objects.entry(ptr) {
+            Entry::Occupied(mut object) => {
+                object.get_mut().internal += 1;
+                false
+            }
+            Entry::Vacant(object) => {
+                object.insert(Object {
+                    strong,
+                    internal: 1,
+                    children: Vec::new(),
+                });
+                true
+            }
+        }
+    }
+
+    pub fn within(&mut self, ptr: *const (), f: impl FnOnce(&mut Self)) {
+        let outer = mem::replace(&mut self.current, ptr);
+        f(self);
+        self.current = outer;
+    }
+}
+
+impl<T: Trace> Trace for Rc<T> {
+    fn trace(&self, tracer: &mut Tracer) {
+        let ptr = Rc::as_ptr(self) as *const ();
+        if tracer.edge(ptr, Rc::strong_count(self)) {
+            tracer.within(ptr, |tracer| (**self).trace(tracer));
+        }
+    }
+}
+
+// Something that is mutably borrowed right now is skipped. Its contents then look like they're
+// referenced from outside, which keeps them alive.
+impl<T: Trace> Trace for RefCell<T> {
+    fn trace(&self, tracer: &mut Tracer) {
+        if let Ok(val) = self.try_borrow() {
+            val.trace(tracer);
+        }
+    }
+}
+
+impl Trace for () {
+    fn trace(&self, _tracer: &mut Tracer) {}
+}
+
+impl<T: Trace> Trace for Vec<T> {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.iter().for_each(|elem| elem.trace(tracer));
+    }
+}
+
+impl<K: Trace, V: Trace> Trace for HashMap<K, V> {
+    fn trace(&self, tracer: &mut Tracer) {
+        for (key, val) in self {
+            key.trace(tracer);
+            val.trace(tracer);
+        }
+    }
+}
+
+impl Trace for Lambda {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.closing_env.trace(tracer);
+        self.body.trace(tracer);
+    }
+}
+
+impl Trace for Function {
+    fn trace(&self, tracer: &mut Tracer) {
+        match self {
+            Function::Lambda(lambda) => lambda.trace(tracer),
+            Function::Compiled(closure) => closure.trace(tracer),
+            Function::Continuation(continuation) => continuation.trace(tracer),
+            Function::External(_) => (),
+        }
+    }
+}
+
+impl Trace for Exp {
+    fn trace(&self, tracer: &mut Tracer) {
+        match self {
+            Exp::Function(f) => f.trace(tracer),
+            Exp::List(list) => list.trace(tracer),
+            Exp::Vector(vec) => vec.trace(tracer),
+            Exp::HashTable(table) => table.trace(tracer),
+            Exp::Map(map) => map.trace(tracer),
+            Exp::Set(set) => set.trace(tracer),
+            Exp::Environment(env) => env.trace(tracer),
+            Exp::Macro(macro_) => macro_.trace(tracer),
+            Exp::Error(error) => error.trace(tracer),
+            _ => (),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HeapStats {
+    // Environments currently alive, counting the captured frames of compiled code
+    pub environments: usize,
+    pub collections: usize,
+    // Environments freed by all collections so far
+    pub collected: usize,
+}
+
+thread_local! {
+    static STATS: Cell<HeapStats> = const { Cell::new(HeapStats {
+        environments: 0,
+        collections: 0,
+        collected: 0,
+    }) };
+    // Collect automatically once this many environments are alive
+    static THRESHOLD: Cell<usize> = const { Cell::new(MIN_THRESHOLD) };
+}
+
+const MIN_THRESHOLD: usize = 10_000;
+
+pub fn heap_stats() -> HeapStats {
+    HeapStats {
+        environments: tracked_count(),
+        ..STATS.with(Cell::get)
+    }
+}
+
+fn tracked_count() -> usize {
+    Environment::tracked_count() + vm::tracked_count()
+}
+
+impl Tracer {
+    // Starts tracing from a frame, unless it was reached from an earlier one
+    fn trace_frame(&mut self, ptr: *const (), trace: impl FnOnce(&mut Self)) {
+        if self.objects.contains_key(&ptr) {
+            return;
+        }
+        self.objects.insert(
+            ptr,
+            Object {
+                strong: 0,
+                internal: 0,
+                children: Vec::new(),
+            },
+        );
+        self.within(ptr, trace);
+    }
+
+    // Counts taken while tracing could include the temporary references upgrading made, so the
+    // frames' counts are taken again once those have been dropped
+    fn recount(&mut self, ptr: *const (), strong: usize) {
+        if let Some(object) = self.objects.get_mut(&ptr) {
+            object.strong = strong;
+        }
+    }
+}
+
+// Runs a collection and returns how many environments were freed
+pub fn collect() -> usize {
+    let nodes = Environment::tracked();
+    let captured = vm::tracked();
+    let mut tracer = Tracer {
+        current: std::ptr::null(),
+        objects: HashMap::new(),
+    };
+    for node in &nodes {
+        if let Some(env) = node.upgrade() {
+            tracer.trace_frame(node.as_ptr(), |tracer| env.trace_bindings(tracer));
+        }
+    }
+    for frame in &captured {
+        if let Some(frame) = frame.upgrade() {
+            let ptr = Rc::as_ptr(&frame) as *const ();
+            tracer.trace_frame(ptr, |tracer| frame.as_ref().trace(tracer));
+        }
+    }
+    for node in &nodes {
+        tracer.recount(node.as_ptr(), node.strong_count());
+    }
+    for frame in &captured {
+        tracer.recount(frame.as_ptr() as *const (), frame.strong_count());
+    }
+
+    let mut marked = HashSet::new();
+    let mut pending = tracer
+        .objects
+        .iter()
+        .filter(|(_, object)| object.strong > object.internal)
+        .map(|(ptr, _)| *ptr)
+        .collect::<Vec<*const ()>>();
+    while let Some(ptr) = pending.pop() {
+        if marked.insert(ptr) {
+            pending.extend(&tracer.objects[&ptr].children);
+        }
+    }
+
+    let garbage = nodes
+        .iter()
+        .filter(|node| !marked.contains(&node.as_ptr()))
+        .filter_map(|node| node.upgrade())
+        .collect::<Vec<Environment>>();
+    let garbage_captured = captured
+        .iter()
+        .filter(|frame| !marked.contains(&(frame.as_ptr() as *const ())))
+        .filter_map(|frame| frame.upgrade())
+        .collect::<Vec<_>>();
+    let freed = garbage.len() + garbage_captured.len();
+    // Emptying the frames drops what they reference, which drops everything else
+    let contents = garbage
+        .iter()
+        .filter_map(Environment::take_bindings)
+        .collect::<Vec<_>>();
+    let captured_contents = garbage_captured
+        .iter()
+        .filter_map(|frame| {
+            frame
+                .try_borrow_mut()
+                .ok()
+                .map(|mut vals| mem::take(&mut *vals))
+        })
+        .collect::<Vec<_>>();
+    drop(garbage);
+    drop(garbage_captured);
+    drop(contents);
+    drop(captured_contents);
+
+    STATS.with(|stats| {
+        let mut updated = stats.get();
+        updated.collections += 1;
+        updated.collected += freed;
+        stats.set(updated);
+    });
+    freed
+}
+
+// Collects if enough environments have accumulated since the last collection
+pub fn maybe_collect() {
+    if tracked_count() >= THRESHOLD.with(Cell::get) {
+        collect();
+        let live = tracked_count();
+        THRESHOLD.with(|threshold| threshold.set((live * 2).max(MIN_THRESHOLD)));
+    }
+}
+
+pub fn gc(_args: &List<Exp>) -> Result<Exp, String> {
+    Ok(Exp::Number(collect() as f32))
+}
+
+pub fn heap_stats_builtin(_args: &List<Exp>) -> Result<Exp, String> {
+    let stats = heap_stats();
+    let map = Hamt::new()
+        .insert(
+            Exp::Ident("environments".to_owned()),
+            Exp::Number(stats.environments as f32),
+        )
+        .insert(
+            Exp::Ident("collections".to_owned()),
+            Exp::Number(stats.collections as f32),
+        )
+        .insert(
+            Exp::Ident("collected".to_owned()),
+            Exp::Number(stats.collected as f32),
+        );
+    Ok(Exp::Map(map))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{evaluator::Evaluator, expression::Exp, Interpreter};
+
+    #[test]
+    fn collects_closure_cycles() {
+        // The tree-walker leaves a call environment and a closing environment behind per call,
+        // compiled code a single frame of captured variables
+        for (evaluator, per_call) in [(Evaluator::TreeWalker, 2), (Evaluator::Bytecode, 1)] {
+            let mut interpreter = Interpreter::new();
+            interpreter.set_evaluator(evaluator);
+            // Each call defines a closure in the call's own frame, which then refers to itself
+            interpreter
+                .eval_str(
+                    "(def make (lambda (n) ((lambda (ignored) helper) (def helper (lambda () n)))))",
+                )
+                .unwrap();
+            // Clears out what the previous interpreter left behind, now that it's not current
+            interpreter.collect_garbage();
+            interpreter.eval_str("(def kept (make 1))").unwrap();
+            interpreter.eval_str("(make 2)").unwrap();
+            interpreter.eval_str("(make 3)").unwrap();
+
+            let before = interpreter.heap_stats().environments;
+            assert_eq!(interpreter.collect_garbage(), 2 * per_call);
+            assert_eq!(interpreter.heap_stats().environments, before - 2 * per_call);
+            assert_eq!(interpreter.eval_str("(kept)"), Ok(Exp::Number(1.0)));
+            assert_eq!(interpreter.collect_garbage(), 0);
+        }
+    }
+}
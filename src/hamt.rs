@@ -0,0 +1,424 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    rc::Rc,
+    slice,
+};
+
+use crate::gc::{Trace, Tracer};
+
+// Each level of the trie consumes this many bits of the hash
+const BITS: u32 = 5;
+const MASK: u64 = (1 << BITS) - 1;
+
+pub struct Hamt<K, V> {
+    root: Rc<Node<K, V>>,
+    len: usize,
+}
+
+pub struct Iter<'a, K, V> {
+    stack: Vec<slice::Iter<'a, Child<K, V>>>,
+    collisions: Option<slice::Iter<'a, (K, V)>>,
+}
+
+enum Node<K, V> {
+    // The bitmap records which of the 32 slots are occupied, children are stored densely
+    Branch(u32, Vec<Child<K, V>>),
+    // Keys whose full hashes are identical
+    Collision(u64, Vec<(K, V)>),
+}
+
+enum Child<K, V> {
+    Leaf(u64, K, V),
+    Node(Rc<Node<K, V>>),
+}
+
+fn hash_of<K: Hash>(key: &K) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn slot(hash: u64, shift: u32) -> u32 {
+    ((hash >> shift) & MASK) as u32
+}
+
+fn position(bitmap: u32, bit: u32) -> usize {
+    (bitmap & (bit - 1)).count_ones() as usize
+}
+
+impl<K: Clone, V: Clone> Clone for Child<K, V> {
+    fn clone(&self) -> Self {
+        match self {
+            Child::Leaf(hash, key, val) => Child::Leaf(*hash, key.clone(), val.clone()),
+            Child::Node(node) => Child::Node(node.clone()),
+        }
+    }
+}
+
+impl<K: Clone, V: Clone> Clone for Node<K, V> {
+    fn clone(&self) -> Self {
+        match self {
+            Node::Branch(bitmap, children) => Node::Branch(*bitmap, children.clone()),
+            Node::Collision(hash, entries) => Node::Collision(*hash, entries.clone()),
+        }
+    }
+}
+
+impl<K: Eq + Clone, V: Clone> Node<K, V> {
+    fn get(&self, hash: u64, shift: u32, key: &K) -> Option<&V> {
+        match self {
+            Node::Branch(bitmap, children) => {
+                let bit = 1 << slot(hash, shift);
+                if bitmap & bit == 0 {
+                    return None;
+                }
+                match &children[position(*bitmap, bit)] {
+                    Child::Leaf(_, k, v) if k == key => Some(v),
+                    Child::Leaf(..) => None,
+                    Child::Node(node) => node.get(hash, shift + BITS, key),
+                }
+            }
+            Node::Collision(_, entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+        }
+    }
+
+    // Builds the smallest subtree that tells two leaves apart
+    fn merge(a: (u64, K, V), b: (u64, K, V), shift: u32) -> Self {
+        if a.0 == b.0 {
+            return Node::Collision(a.0, vec![(a.1, a.2), (b.1, b.2)]);
+        }
+        let (slot_a, slot_b) = (slot(a.0, shift), slot(b.0, shift));
+        if slot_a == slot_b {
+            let child = Child::Node(Rc::new(Self::merge(a, b, shift + BITS)));
+            Node::Branch(1 << slot_a, vec![child])
+        } else {
+            let bitmap = (1 << slot_a) | (1 << slot_b);
+            let leaf_a = Child::Leaf(a.0, a.1, a.2);
+            let leaf_b = Child::Leaf(b.0, b.1, b.2);
+            let children = if slot_a < slot_b {
+                vec![leaf_a, leaf_b]
+            } else {
+                vec![leaf_b, leaf_a]
+            };
+            Node::Branch(bitmap, children)
+        }
+    }
+
+    // Returns the updated copy of this node and whether a new key was added
+    fn insert(&self, hash: u64, shift: u32, key: K, val: V) -> (Self, bool) {
+        match self {
+            Node::Branch(bitmap, children) => {
+                let bit = 1 << slot(hash, shift);
+                let pos = position(*bitmap, bit);
+                let mut children = children.clone();
+                if bitmap & bit == 0 {
+                    children.insert(pos, Child::Leaf(hash, key, val));
+                    return (Node::Branch(bitmap | bit, children), true);
+                }
+                let added = match &children[pos] {
+                    Child::Leaf(h, k, _) if *k == key => {
+                        children[pos] = Child::Leaf(*h, key, val);
+                        false
+                    }
+                    Child::Leaf(h, k, v) => {
+                        let merged =
+                            Self::merge((*h, k.clone(), v.clone()), (hash, key, val), shift + BITS);
+                        children[pos] = Child::Node(Rc::new(merged));
+                        true
+                    }
+                    Child::Node(node) => {
+                        let (node, added) = node.insert(hash, shift + BITS, key, val);
+                        children[pos] = Child::Node(Rc::new(node));
+                        added
+                    }
+                };
+                (Node::Branch(*bitmap, children), added)
+            }
+            Node::Collision(collision_hash, entries) if *collision_hash == hash => {
+                let mut entries = entries.clone();
+                match entries.iter().position(|(k, _)| *k == key) {
+                    Some(pos) => {
+                        entries[pos] = (key, val);
+                        (Node::Collision(hash, entries), false)
+                    }
+                    None => {
+                        entries.push((key, val));
+                        (Node::Collision(hash, entries), true)
+                    }
+                }
+            }
+            // A different hash reached this collision, so push the collision one level down
+            Node::Collision(collision_hash, _) => {
+                let child = Child::Node(Rc::new(self.clone()));
+                let branch = Node::Branch(1 << slot(*collision_hash, shift), vec![child]);
+                branch.insert(hash, shift, key, val)
+            }
+        }
+    }
+
+    // Returns None if the key wasn't present, otherwise the child that should replace this node
+    // (None if it's now empty, or a lone leaf so that the parent can absorb it)
+    fn remove(&self, hash: u64, shift: u32, key: &K) -> Option<Option<Child<K, V>>> {
+        match self {
+            Node::Branch(bitmap, children) => {
+                let bit = 1 << slot(hash, shift);
+                if bitmap & bit == 0 {
+                    return None;
+                }
+                let pos = position(*bitmap, bit);
+                let replacement = match &children[pos] {
+                    Child::Leaf(_, k, _) if k == key => None,
+                    Child::Leaf(..) => return None,
+                    Child::Node(node) => node.remove(hash, shift + BITS, key)?,
+                };
+
+                let mut children = children.clone();
+                let bitmap = match replacement {
+                    Some(child) => {
+                        children[pos] = child;
+                        *bitmap
+                    }
+                    None => {
+                        children.remove(pos);
+                        bitmap & !bit
+                    }
+                };
+                Some(match children.as_slice() {
+                    [] => None,
+                    [Child::Leaf(..)] => children.pop(),
+                    _ => Some(Child::Node(Rc::new(Node::Branch(bitmap, children)))),
+                })
+            }
+            Node::Collision(collision_hash, entries) => {
+                let pos = entries.iter().position(|(k, _)| k == key)?;
+                let mut entries = entries.clone();
+                entries.remove(pos);
+                Some(match entries.as_slice() {
+                    [(k, v)] => Some(Child::Leaf(*collision_hash, k.clone(), v.clone())),
+                    _ => Some(Child::Node(Rc::new(Node::Collision(
+                        *collision_hash,
+                        entries,
+                    )))),
+                })
+            }
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> Hamt<K, V> {
+    pub fn new() -> Self {
+        Hamt {
+            root: Rc::new(Node::Branch(0, Vec::new())),
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.root, &other.root)
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.root.get(hash_of(key), 0, key)
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn insert(&self, key: K, val: V) -> Self {
+        let (root, added) = self.root.insert(hash_of(&key), 0, key, val);
+        Hamt {
+            root: Rc::new(root),
+            len: if added { self.len + 1 } else { self.len },
+        }
+    }
+
+    pub fn remove(&self, key: &K) -> Self {
+        let root = match self.root.remove(hash_of(key), 0, key) {
+            None => return self.clone(),
+            Some(None) => Rc::new(Node::Branch(0, Vec::new())),
+            Some(Some(Child::Node(node))) => node,
+            Some(Some(leaf)) => {
+                let bit = match &leaf {
+                    Child::Leaf(hash, ..) => 1 << slot(*hash, 0),
+                    Child::Node(_) => unreachable!(),
+                };
+                Rc::new(Node::Branch(bit, vec![leaf]))
+            }
+        };
+        Hamt {
+            root,
+            len: self.len - 1,
+        }
+    }
+
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        let mut iter = Iter {
+            stack: Vec::new(),
+            collisions: None,
+        };
+        iter.descend(&self.root);
+        iter
+    }
+}
+
+impl<K: Trace, V: Trace> Trace for Node<K, V> {
+    fn trace(&self, tracer: &mut Tracer) {
+        match self {
+            Node::Branch(_, children) => {
+                for child in children {
+                    match child {
+                        Child::Leaf(_, key, val) => {
+                            key.trace(tracer);
+                            val.trace(tracer);
+                        }
+                        Child::Node(node) => node.trace(tracer),
+                    }
+                }
+            }
+            Node::Collision(_, entries) => {
+                for (key, val) in entries {
+                    key.trace(tracer);
+                    val.trace(tracer);
+                }
+            }
+        }
+    }
+}
+
+impl<K: Trace, V: Trace> Trace for Hamt<K, V> {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.root.trace(tracer);
+    }
+}
+
+impl<K, V> Clone for Hamt<K, V> {
+    fn clone(&self) -> Self {
+        Hamt {
+            root: self.root.clone(),
+            len: self.len,
+        }
+    }
+}
+
+impl<'a, K, V> Iter<'a, K, V> {
+    fn descend(&mut self, node: &'a Node<K, V>) {
+        match node {
+            Node::Branch(_, children) => self.stack.push(children.iter()),
+            Node::Collision(_, entries) => self.collisions = Some(entries.iter()),
+        }
+    }
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entries) = &mut self.collisions {
+                match entries.next() {
+                    Some((k, v)) => return Some((k, v)),
+                    None => self.collisions = None,
+                }
+            }
+            match self.stack.last_mut()?.next() {
+                Some(Child::Leaf(_, k, v)) => return Some((k, v)),
+                Some(Child::Node(node)) => self.descend(node),
+                None => {
+                    self.stack.pop();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Hamt;
+    use std::hash::{Hash, Hasher};
+
+    // Only the low bits feed the hash, so many keys share a full hash and collide
+    #[derive(Clone, PartialEq, Eq, Debug)]
+    struct Colliding(u32);
+
+    impl Hash for Colliding {
+        fn hash<H: Hasher>(&self, state: &mut H) {
+            (self.0 % 4).hash(state);
+        }
+    }
+
+    #[test]
+    fn insert_and_get() {
+        let mut map = Hamt::new();
+        for i in 0..2000 {
+            map = map.insert(i, i * 2);
+        }
+        assert_eq!(map.len(), 2000);
+        for i in 0..2000 {
+            assert_eq!(map.get(&i), Some(&(i * 2)));
+        }
+        assert_eq!(map.get(&2000), None);
+        assert_eq!(map.iter().count(), 2000);
+
+        let map = map.insert(7, 0);
+        assert_eq!(map.len(), 2000);
+        assert_eq!(map.get(&7), Some(&0));
+    }
+
+    #[test]
+    fn structure_sharing() {
+        let before = Hamt::new().insert("a", 1).insert("b", 2);
+        let after = before.insert("c", 3).remove(&"a");
+
+        assert_eq!(before.len(), 2);
+        assert_eq!(before.get(&"a"), Some(&1));
+        assert!(!before.contains_key(&"c"));
+
+        assert_eq!(after.len(), 2);
+        assert!(!after.contains_key(&"a"));
+        assert_eq!(after.get(&"c"), Some(&3));
+    }
+
+    #[test]
+    fn remove() {
+        let mut map = Hamt::new();
+        for i in 0..500 {
+            map = map.insert(i, ());
+        }
+        for i in (0..500).step_by(2) {
+            map = map.remove(&i);
+        }
+        assert_eq!(map.len(), 250);
+        assert!((0..500).all(|i| map.contains_key(&i) == (i % 2 == 1)));
+        assert_eq!(map.remove(&0).len(), 250);
+
+        for i in (1..500).step_by(2) {
+            map = map.remove(&i);
+        }
+        assert_eq!(map.len(), 0);
+        assert_eq!(map.iter().count(), 0);
+    }
+
+    #[test]
+    fn collisions() {
+        let mut map = Hamt::new();
+        for i in 0..40 {
+            map = map.insert(Colliding(i), i);
+        }
+        assert_eq!(map.len(), 40);
+        assert!((0..40).all(|i| map.get(&Colliding(i)) == Some(&i)));
+        assert_eq!(map.iter().count(), 40);
+
+        for i in 0..39 {
+            map = map.remove(&Colliding(i));
+        }
+        assert_eq!(map.len(), 1);
+        assert_eq!(map.get(&Colliding(39)), Some(&39));
+        assert_eq!(map.iter().count(), 1);
+    }
+}
@@ -0,0 +1,157 @@
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use crate::{expression::Exp, list::List};
+
+fn table_arg(arg: Option<&Exp>) -> Result<&Rc<RefCell<HashMap<Exp, Exp>>>, String> {
+    match arg {
+        Some(Exp::HashTable(table)) => Ok(table),
+        _ => Err("Type error".to_owned()),
+    }
+}
+
+fn key_arg(arg: Option<&Exp>) -> Result<&Exp, String> {
+    match arg {
+        Some(key) if key.is_hashable() => Ok(key),
+        Some(_) => Err("Unhashable key".to_owned()),
+        None => Err("Missing required argument".to_owned()),
+    }
+}
+
+pub fn make_hash_table(_args: &List<Exp>) -> Result<Exp, String> {
+    Ok(Exp::HashTable(Rc::new(RefCell::new(HashMap::new()))))
+}
+
+pub fn hash_ref(args: &List<Exp>) -> Result<Exp, String> {
+    let mut args_iter = args.iter();
+    let table = table_arg(args_iter.next())?.borrow();
+    let key = key_arg(args_iter.next())?;
+    table
+        .get(key)
+        .or(args_iter.next())
+        .cloned()
+        .ok_or("Key not found".to_owned())
+}
+
+pub fn hash_set(args: &List<Exp>) -> Result<Exp, String> {
+    let mut args_iter = args.iter();
+    let table = table_arg(args_iter.next())?;
+    let key = key_arg(args_iter.next())?;
+    let val = args_iter
+        .next()
+        .ok_or("Missing required argument".to_owned())?;
+    table.borrow_mut().insert(key.clone(), val.clone());
+    Ok(Exp::Void)
+}
+
+pub fn hash_delete(args: &List<Exp>) -> Result<Exp, String> {
+    let mut args_iter = args.iter();
+    let table = table_arg(args_iter.next())?;
+    let key = key_arg(args_iter.next())?;
+    table.borrow_mut().remove(key);
+    Ok(Exp::Void)
+}
+
+pub fn hash_contains(args: &List<Exp>) -> Result<Exp, String> {
+    let mut args_iter = args.iter();
+    let table = table_arg(args_iter.next())?.borrow();
+    let key = key_arg(args_iter.next())?;
+    Ok(Exp::Bool(table.contains_key(key)))
+}
+
+pub fn hash_keys(args: &List<Exp>) -> Result<Exp, String> {
+    let table = table_arg(args.head())?.borrow();
+    Ok(Exp::List(List::from_vec(table.keys().cloned().collect())))
+}
+
+pub fn hash_values(args: &List<Exp>) -> Result<Exp, String> {
+    let table = table_arg(args.head())?.borrow();
+    Ok(Exp::List(List::from_vec(table.values().cloned().collect())))
+}
+
+// There are no dotted pairs, so each association is a two element list
+pub fn hash_to_alist(args: &List<Exp>) -> Result<Exp, String> {
+    let table = table_arg(args.head())?.borrow();
+    let entries = table
+        .iter()
+        .map(|(key, val)| Exp::List(List::from_vec(vec![key.clone(), val.clone()])))
+        .collect();
+    Ok(Exp::List(List::from_vec(entries)))
+}
+
+pub fn hash_count(args: &List<Exp>) -> Result<Exp, String> {
+    let table = table_arg(args.head())?.borrow();
+    Ok(Exp::Number(table.len() as f32))
+}
+
+pub fn hash_update(args: &List<Exp>) -> Result<Exp, String> {
+    let mut args_iter = args.iter();
+    let table = table_arg(args_iter.next())?;
+    let key = key_arg(args_iter.next())?;
+    let f = match args_iter.next() {
+        Some(Exp::Function(f)) => f,
+        _ => return Err("Type error".to_owned()),
+    };
+
+    // The borrow is released before the call so f is free to use the table
+    let current = table.borrow().get(key).cloned();
+    let current = current
+        .or(args_iter.next().cloned())
+        .ok_or("Key not found".to_owned())?;
+    let updated = f.call(&List::new().prepend(current))?;
+    table.borrow_mut().insert(key.clone(), updated);
+    Ok(Exp::Void)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::Interpreter;
+
+    #[test]
+    fn hash_table_builtins() {
+        let cases = [
+            ("(def h (make-hash-table))", Ok("#void#")),
+            ("(hash-set! h 'a 1)", Ok("#void#")),
+            ("(hash-ref h 'a)", Ok("1")),
+            ("(hash-ref h 'b)", Err("Key not found")),
+            ("(hash-ref h 'b 0)", Ok("0")),
+            ("(hash-set! h #(1) 2)", Err("Unhashable key")),
+            ("(hash-ref '((a 1)) 'a)", Err("Type error")),
+            // Keys are compared by structure, and 0 and -0 are the same key
+            ("(hash-set! h (list 1 \"x\") 'structural)", Ok("#void#")),
+            ("(hash-ref h '(1 \"x\"))", Ok("structural")),
+            ("(hash-set! h 0 'zero)", Ok("#void#")),
+            ("(hash-ref h -0)", Ok("zero")),
+            ("(hash-update! h 'a (lambda (n) (+ n 10)))", Ok("#void#")),
+            ("(hash-ref h 'a)", Ok("11")),
+            (
+                "(hash-update! h 'c (lambda (n) (+ n 1)))",
+                Err("Key not found"),
+            ),
+            ("(hash-contains? h 'c)", Ok("#f")),
+            ("(hash-update! h 'c (lambda (n) (+ n 1)) 100)", Ok("#void#")),
+            ("(hash-ref h 'c)", Ok("101")),
+            ("(hash-update! h 'c 1)", Err("Type error")),
+            // The function is free to use the table while it's being updated
+            (
+                "(hash-update! h 'c (lambda (n) ((lambda (ignored) (+ n 1)) (hash-set! h 'd n))))",
+                Ok("#void#"),
+            ),
+            ("(list (hash-ref h 'c) (hash-ref h 'd))", Ok("(102 101)")),
+            ("(hash-count h)", Ok("5")),
+            ("(hash-delete! h 'd)", Ok("#void#")),
+            ("(hash-contains? h 'd)", Ok("#f")),
+            ("(hash-count h)", Ok("4")),
+            // Tables are only equal to themselves
+            ("(equal? h h)", Ok("#t")),
+            ("(equal? (make-hash-table) (make-hash-table))", Ok("#f")),
+        ];
+        let mut interpreter = Interpreter::new();
+        for (form, expected) in cases {
+            let result = interpreter
+                .eval_str(form)
+                .map(|val| val.display_form().to_string());
+            let expected = expected.map(str::to_owned).map_err(str::to_owned);
+            assert_eq!(result, expected, "{}", form);
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use std::rc::Rc;
+
+use crate::{
+    backtrace::{self, Backtrace},
+    conditions::{self, Debugger, RestartInfo},
+    environment::{build_global_env, Environment},
+    evaluator::Evaluator,
+    exceptions,
+    expression::Exp,
+    gc::{self, HeapStats},
+    lexer::tokenize,
+    limits::{self, Interrupt, Limits},
+    loader::Loader,
+    parser::parse,
+    ports::Port,
+    truthiness::Truthiness,
+    vm,
+};
+
+pub struct Interpreter {
+    global_env: Environment,
+    truthiness: Truthiness,
+    evaluator: Evaluator,
+    input: Rc<Port>,
+    output: Rc<Port>,
+    loader: Rc<Loader>,
+    debugger: Option<Debugger>,
+    backtrace_depth: usize,
+    backtrace: Option<Backtrace>,
+    limits: Limits,
+    interrupt: Interrupt,
+}
+
+impl Interpreter {
+    pub fn new() -> Self {
+        Interpreter {
+            global_env: build_global_env(),
+            truthiness: Truthiness::CLike,
+            evaluator: Evaluator::Bytecode,
+            input: Rc::new(Port::stdin()),
+            output: Rc::new(Port::stdout()),
+            loader: Rc::new(Loader::default()),
+            debugger: None,
+            backtrace_depth: 20,
+            backtrace: None,
+            limits: Limits::default(),
+            interrupt: Interrupt::default(),
+        }
+    }
+
+    pub fn truthiness(&self) -> Truthiness {
+        self.truthiness
+    }
+
+    pub fn set_truthiness(&mut self, truthiness: Truthiness) {
+        self.truthiness = truthiness;
+    }
+
+    pub fn evaluator(&self) -> Evaluator {
+        self.evaluator
+    }
+
+    pub fn set_evaluator(&mut self, evaluator: Evaluator) {
+        self.evaluator = evaluator;
+    }
+
+    // Where read-line and friends read from, stdin by default
+    pub fn set_input_port(&mut self, port: Rc<Port>) {
+        self.input = port;
+    }
+
+    // Where display and friends write to, stdout by default
+    pub fn set_output_port(&mut self, port: Rc<Port>) {
+        self.output = port;
+    }
+
+    // Consulted when an error is about to go unhandled, to pick one of the restarts available
+    pub fn set_debugger(
+        &mut self,
+        debugger: impl Fn(&str, &[RestartInfo]) -> Option<(usize, Vec<String>)> + 'static,
+    ) {
+        self.debugger = Some(Rc::new(debugger));
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    // Budgets that each evaluation starts over with
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    // For stopping an evaluation from elsewhere, which then fails with "Interrupted"
+    pub fn interrupt_handle(&self) -> Interrupt {
+        self.interrupt.clone()
+    }
+
+    // How many frames of a backtrace are shown
+    pub fn set_backtrace_depth(&mut self, depth: usize) {
+        self.backtrace_depth = depth;
+    }
+
+    // The calls the last error unwound through, if the last evaluation failed
+    pub fn backtrace(&self) -> Option<&Backtrace> {
+        self.backtrace.as_ref()
+    }
+
+    pub fn global_env(&mut self) -> &mut Environment {
+        &mut self.global_env
+    }
+
+    pub fn eval(&mut self, exp: &Exp) -> Result<Exp, String> {
+        // Settings are per interpreter, so they're reinstated for every evaluation
+        self.truthiness.set_current();
+        self.evaluator.set_current();
+        Port::set_current_input(self.input.clone());
+        Port::set_current_output(self.output.clone());
+        Environment::set_interaction(self.global_env.clone());
+        Loader::set_current(self.loader.clone());
+        conditions::set_debugger(self.debugger.clone());
+        backtrace::clear();
+        limits::start(&self.limits, &self.interrupt);
+        let result = vm::eval_rooted(exp, &mut self.global_env);
+        exceptions::clear_signalled();
+        self.backtrace = match &result {
+            Err(err) => backtrace::take(err, self.backtrace_depth),
+            Ok(_) => None,
+        };
+        self.output.flush()?;
+        // Between evaluations is a good time to collect, since little is alive on the stack
+        gc::maybe_collect();
+        result
+    }
+
+    // Frees environments that are only kept alive by reference cycles, and returns how many
+    pub fn collect_garbage(&mut self) -> usize {
+        gc::collect()
+    }
+
+    pub fn heap_stats(&self) -> HeapStats {
+        gc::heap_stats()
+    }
+
+    pub fn eval_str(&mut self, text: &str) -> Result<Exp, String> {
+        let exp = tokenize(text).and_then(|tokens| parse(&tokens))?;
+        self.eval(&exp)
+    }
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io::Cursor, rc::Rc};
+
+    use super::Interpreter;
+    use crate::{expression::Exp, ports::Port, truthiness::Truthiness};
+
+    #[test]
+    fn truthiness_setting() {
+        let mut interpreter = Interpreter::new();
+        let zero_is_false = "(if 0 #f #t)";
+        let empty_is_false = "(if (list) #f #t)";
+
+        assert!(matches!(
+            interpreter.eval_str(zero_is_false),
+            Ok(Exp::Bool(true))
+        ));
+        assert!(matches!(
+            interpreter.eval_str(empty_is_false),
+            Ok(Exp::Bool(true))
+        ));
+
+        interpreter.set_truthiness(Truthiness::Strict);
+        assert!(matches!(
+            interpreter.eval_str(zero_is_false),
+            Ok(Exp::Bool(false))
+        ));
+        assert!(matches!(
+            interpreter.eval_str(empty_is_false),
+            Ok(Exp::Bool(false))
+        ));
+        assert!(matches!(interpreter.eval_str("(if #f 1 2)"), Ok(Exp::Number(val)) if val == 2.0));
+
+        // guard clauses and handler-bind predicates are tested the same way
+        let guarded = "(guard (e ((list) => (lambda (x) 'empty)) (else 'other)) (raise 1))";
+        let bound = "(handler-bind (((lambda (c) 0) (lambda (c) (invoke-restart 'use-value 1)))) \
+                     (restart-case (signal 'c) (use-value (v) v)))";
+        assert_eq!(interpreter.eval_str(guarded).unwrap().to_string(), "empty");
+        assert_eq!(interpreter.eval_str(bound), Ok(Exp::Number(1.0)));
+        interpreter.set_truthiness(Truthiness::CLike);
+        assert_eq!(interpreter.eval_str(guarded).unwrap().to_string(), "other");
+        assert_eq!(interpreter.eval_str(bound), Ok(Exp::Void));
+    }
+
+    #[test]
+    fn captured_console_io() {
+        let mut interpreter = Interpreter::new();
+        let output = Rc::new(Port::string_output());
+        interpreter.set_output_port(output.clone());
+        interpreter.set_input_port(Rc::new(Port::input(Cursor::new("first line\nx"))));
+
+        interpreter
+            .eval_str("(display (list \"a\" #\\b 1))")
+            .unwrap();
+        interpreter.eval_str("(newline)").unwrap();
+        interpreter.eval_str("(write (list \"a\" #\\b 1))").unwrap();
+        assert_eq!(output.output_string().unwrap(), "(a b 1)\n(\"a\" #\\b 1)");
+
+        let line = interpreter.eval_str("(read-line)").unwrap();
+        assert!(matches!(line, Exp::Str(s) if &*s == "first line"));
+        let c = interpreter.eval_str("(read-char)").unwrap();
+        assert!(matches!(c, Exp::Char('x')));
+        let eof = interpreter.eval_str("(read-line)").unwrap();
+        assert!(matches!(eof, Exp::Eof));
+    }
+
+    #[test]
+    fn first_class_environments() {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .eval_str("(def env (make-environment))")
+            .unwrap();
+        interpreter.eval_str("(eval '(def x 1) env)").unwrap();
+        assert_eq!(interpreter.eval_str("(eval 'x env)"), Ok(Exp::Number(1.0)));
+        assert!(interpreter.eval_str("x").is_err());
+
+        let captured = "(eval 'y ((lambda (y) (the-environment)) 2))";
+        assert_eq!(interpreter.eval_str(captured), Ok(Exp::Number(2.0)));
+        let applied = "(apply + 1 (list 2 3))";
+        assert_eq!(interpreter.eval_str(applied), Ok(Exp::Number(6.0)));
+    }
+
+    #[test]
+    fn special_forms_are_bindings() {
+        let mut interpreter = Interpreter::new();
+        let quoted = interpreter.eval_str("'(if a b)").unwrap();
+        assert!(
+            matches!(quoted, Exp::List(list) if matches!(list.head(), Some(Exp::Ident(ident)) if ident == "if"))
+        );
+
+        let shadowed = "((lambda (if) (+ if 1)) 2)";
+        assert_eq!(interpreter.eval_str(shadowed), Ok(Exp::Number(3.0)));
+        assert_eq!(interpreter.eval_str("(if #t 1 2)"), Ok(Exp::Number(1.0)));
+    }
+}
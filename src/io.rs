@@ -0,0 +1,209 @@
+use std::{cell::RefCell, rc::Rc};
+
+use crate::{
+    expression::{Exp, Function},
+    list::List,
+    math,
+    ports::Port,
+};
+
+fn input_port_arg(arg: Option<&Exp>) -> Result<Rc<Port>, String> {
+    match arg {
+        None => Ok(Port::current_input()),
+        Some(Exp::Port(port)) => Ok(port.clone()),
+        Some(_) => Err("Type error".to_owned()),
+    }
+}
+
+fn output_port_arg(arg: Option<&Exp>) -> Result<Rc<Port>, String> {
+    match arg {
+        None => Ok(Port::current_output()),
+        Some(Exp::Port(port)) => Ok(port.clone()),
+        Some(_) => Err("Type error".to_owned()),
+    }
+}
+
+fn str_arg(arg: Option<&Exp>) -> Result<&str, String> {
+    match arg {
+        Some(Exp::Str(s)) => Ok(s),
+        _ => Err("Type error".to_owned()),
+    }
+}
+
+fn function_arg(arg: Option<&Exp>) -> Result<&Function, String> {
+    match arg {
+        Some(Exp::Function(f)) => Ok(f),
+        _ => Err("Type error".to_owned()),
+    }
+}
+
+pub fn display(args: &List<Exp>) -> Result<Exp, String> {
+    let mut args_iter = args.iter();
+    let arg = args_iter
+        .next()
+        .ok_or("Missing required argument".to_owned())?;
+    output_port_arg(args_iter.next())?.write_str(&arg.display_form().to_string())?;
+    Ok(Exp::Void)
+}
+
+pub fn write(args: &List<Exp>) -> Result<Exp, String> {
+    let mut args_iter = args.iter();
+    let arg = args_iter
+        .next()
+        .ok_or("Missing required argument".to_owned())?;
+    output_port_arg(args_iter.next())?.write_str(&arg.to_string())?;
+    Ok(Exp::Void)
+}
+
+pub fn write_string(args: &List<Exp>) -> Result<Exp, String> {
+    let mut args_iter = args.iter();
+    let text = str_arg(args_iter.next())?;
+    output_port_arg(args_iter.next())?.write_str(text)?;
+    Ok(Exp::Void)
+}
+
+pub fn newline(args: &List<Exp>) -> Result<Exp, String> {
+    output_port_arg(args.head())?.write_str("\n")?;
+    Ok(Exp::Void)
+}
+
+pub fn write_u8(args: &List<Exp>) -> Result<Exp, String> {
+    let mut args_iter = args.iter();
+    let byte = math::integer_arg(args_iter.next().ok_or("Type error".to_owned())?)?;
+    let byte = u8::try_from(byte).map_err(|_| "Not a byte".to_owned())?;
+    output_port_arg(args_iter.next())?.write_u8(byte)?;
+    Ok(Exp::Void)
+}
+
+pub fn read_line(args: &List<Exp>) -> Result<Exp, String> {
+    let line = input_port_arg(args.head())?.read_line()?;
+    Ok(line.map_or(Exp::Eof, |line| Exp::Str(line.into())))
+}
+
+pub fn read_char(args: &List<Exp>) -> Result<Exp, String> {
+    let c = input_port_arg(args.head())?.read_char()?;
+    Ok(c.map_or(Exp::Eof, Exp::Char))
+}
+
+pub fn peek_char(args: &List<Exp>) -> Result<Exp, String> {
+    let c = input_port_arg(args.head())?.peek_char()?;
+    Ok(c.map_or(Exp::Eof, Exp::Char))
+}
+
+pub fn read_u8(args: &List<Exp>) -> Result<Exp, String> {
+    let byte = input_port_arg(args.head())?.read_u8()?;
+    Ok(byte.map_or(Exp::Eof, |byte| Exp::Number(byte as f32)))
+}
+
+pub fn peek_u8(args: &List<Exp>) -> Result<Exp, String> {
+    let byte = input_port_arg(args.head())?.peek_u8()?;
+    Ok(byte.map_or(Exp::Eof, |byte| Exp::Number(byte as f32)))
+}
+
+pub fn open_input_file(args: &List<Exp>) -> Result<Exp, String> {
+    let port = Port::open_input_file(str_arg(args.head())?, false)?;
+    Ok(Exp::Port(Rc::new(port)))
+}
+
+pub fn open_output_file(args: &List<Exp>) -> Result<Exp, String> {
+    let port = Port::open_output_file(str_arg(args.head())?, false)?;
+    Ok(Exp::Port(Rc::new(port)))
+}
+
+pub fn open_binary_input_file(args: &List<Exp>) -> Result<Exp, String> {
+    let port = Port::open_input_file(str_arg(args.head())?, true)?;
+    Ok(Exp::Port(Rc::new(port)))
+}
+
+pub fn open_binary_output_file(args: &List<Exp>) -> Result<Exp, String> {
+    let port = Port::open_output_file(str_arg(args.head())?, true)?;
+    Ok(Exp::Port(Rc::new(port)))
+}
+
+pub fn open_input_string(args: &List<Exp>) -> Result<Exp, String> {
+    let port = Port::string_input(str_arg(args.head())?);
+    Ok(Exp::Port(Rc::new(port)))
+}
+
+pub fn open_input_bytevector(args: &List<Exp>) -> Result<Exp, String> {
+    match args.head() {
+        Some(Exp::Bytevector(bytes)) => {
+            let port = Port::bytevector_input(bytes.borrow().clone());
+            Ok(Exp::Port(Rc::new(port)))
+        }
+        _ => Err("Type error".to_owned()),
+    }
+}
+
+pub fn open_output_bytevector(_args: &List<Exp>) -> Result<Exp, String> {
+    Ok(Exp::Port(Rc::new(Port::bytevector_output())))
+}
+
+pub fn get_output_bytevector(args: &List<Exp>) -> Result<Exp, String> {
+    let bytes = match args.head() {
+        Some(Exp::Port(port)) => port.output_bytes(),
+        _ => None,
+    };
+    bytes
+        .map(|bytes| Exp::Bytevector(Rc::new(RefCell::new(bytes))))
+        .ok_or("Not a bytevector output port".to_owned())
+}
+
+pub fn call_with_output_string(args: &List<Exp>) -> Result<Exp, String> {
+    let f = function_arg(args.head())?;
+    let port = Rc::new(Port::string_output());
+    f.call(&List::new().prepend(Exp::Port(port.clone())))?;
+    // Nothing is left of what was written if the procedure closed the port
+    let text = port.output_string().ok_or("Port is closed".to_owned())?;
+    Ok(Exp::Str(text.into()))
+}
+
+pub fn with_output_to_file(args: &List<Exp>) -> Result<Exp, String> {
+    let mut args_iter = args.iter();
+    let path = str_arg(args_iter.next())?;
+    let thunk = function_arg(args_iter.next())?;
+
+    let port = Rc::new(Port::open_output_file(path, false)?);
+    let previous = Port::set_current_output(port.clone());
+    let result = thunk.call(&List::new());
+    // The previous port is reinstated even if the thunk failed
+    Port::set_current_output(previous);
+    port.close()?;
+    result
+}
+
+pub fn close_port(args: &List<Exp>) -> Result<Exp, String> {
+    match args.head() {
+        Some(Exp::Port(port)) => port.close().map(|_| Exp::Void),
+        _ => Err("Type error".to_owned()),
+    }
+}
+
+pub fn current_input_port(_args: &List<Exp>) -> Result<Exp, String> {
+    Ok(Exp::Port(Port::current_input()))
+}
+
+pub fn current_output_port(_args: &List<Exp>) -> Result<Exp, String> {
+    Ok(Exp::Port(Port::current_output()))
+}
+
+pub fn is_input_port(args: &List<Exp>) -> Result<Exp, String> {
+    let arg = args.head().ok_or("Missing required argument".to_owned())?;
+    Ok(Exp::Bool(matches!(arg, Exp::Port(port) if port.is_input())))
+}
+
+pub fn is_output_port(args: &List<Exp>) -> Result<Exp, String> {
+    let arg = args.head().ok_or("Missing required argument".to_owned())?;
+    Ok(Exp::Bool(
+        matches!(arg, Exp::Port(port) if port.is_output()),
+    ))
+}
+
+pub fn eof_object(_args: &List<Exp>) -> Result<Exp, String> {
+    Ok(Exp::Eof)
+}
+
+pub fn is_eof_object(args: &List<Exp>) -> Result<Exp, String> {
+    let arg = args.head().ok_or("Missing required argument".to_owned())?;
+    Ok(Exp::Bool(matches!(arg, Exp::Eof)))
+}
@@ -5,14 +5,89 @@ use itertools::Itertools;
 #[derive(Debug, PartialEq)]
 pub enum Token {
     OpenParen,
+    VectorOpen,
+    BytevectorOpen,
+    MapOpen,
+    SetOpen,
     CloseParen,
-    If,
-    Def,
-    Lambda,
+    CloseBrace,
+    QuoteMark,
     Ident(String),
     Dot,
     Bool(bool),
     Number(f32),
+    Char(char),
+    Str(String),
+}
+
+impl Token {
+    pub fn is_delimiter(&self) -> bool {
+        matches!(
+            self,
+            Token::OpenParen
+                | Token::VectorOpen
+                | Token::BytevectorOpen
+                | Token::MapOpen
+                | Token::SetOpen
+                | Token::CloseParen
+                | Token::CloseBrace
+                | Token::QuoteMark
+        )
+    }
+}
+
+// Where something is in the source text, counting from 1
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Position {
+    pub fn start() -> Self {
+        Position { line: 1, column: 1 }
+    }
+
+    // Moves past a character
+    pub fn advance(&mut self, c: char) {
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+    }
+}
+
+// The text being tokenized. Like itertools' MultiPeek, each peek looks one character further
+// ahead until the next call of next, which also keeps track of where it's got to.
+#[derive(Clone)]
+struct Scanner<'a> {
+    chars: Chars<'a>,
+    peeked: usize,
+    position: Position,
+}
+
+impl Iterator for Scanner<'_> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        self.peeked = 0;
+        let c = self.chars.next()?;
+        self.position.advance(c);
+        Some(c)
+    }
+}
+
+impl Scanner<'_> {
+    fn peek(&mut self) -> Option<char> {
+        self.peeked += 1;
+        self.chars.clone().nth(self.peeked - 1)
+    }
+
+    fn reset_peek(&mut self) {
+        self.peeked = 0;
+    }
 }
 
 fn is_ident_initial(c: char) -> bool {
@@ -23,10 +98,7 @@ fn is_ident_subsequent(c: char) -> bool {
     is_ident_initial(c) || c.is_ascii_digit() || c == '.'
 }
 
-fn tokenize_num(
-    current_char: char,
-    iter: &mut itertools::MultiPeek<Chars>,
-) -> Result<Token, String> {
+fn tokenize_num(current_char: char, iter: &mut Scanner) -> Result<Token, String> {
     iter.reset_peek();
     let mut digits = current_char.to_string();
     digits.push_str(
@@ -60,10 +132,7 @@ fn tokenize_num(
         .map_err(|_| "Number parsing error".to_owned())
 }
 
-fn tokenize_ident_or_reserved(
-    current_char: char,
-    iter: &mut itertools::MultiPeek<Chars>,
-) -> Result<Token, String> {
+fn tokenize_ident(current_char: char, iter: &mut Scanner) -> Result<Token, String> {
     iter.reset_peek();
     let mut chars = current_char.to_string();
     chars.push_str(
@@ -72,24 +141,84 @@ fn tokenize_ident_or_reserved(
             .collect::<String>(),
     );
 
-    match chars.as_str() {
-        "if" => Ok(Token::If),
-        "def" => Ok(Token::Def),
-        "lambda" => Ok(Token::Lambda),
-        _ => Ok(Token::Ident(chars)),
+    Ok(Token::Ident(chars))
+}
+
+fn char_from_name(name: &str) -> Option<char> {
+    let mut chars = name.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Some(c),
+        _ => match name {
+            "space" => Some(' '),
+            "newline" => Some('\n'),
+            "tab" => Some('\t'),
+            "nul" => Some('\0'),
+            // Hex scalar values, e.g. #\x41
+            _ => name
+                .strip_prefix('x')
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .and_then(char::from_u32),
+        },
     }
 }
 
-fn consume_whitespace(iter: &mut itertools::MultiPeek<Chars>) -> usize {
+fn tokenize_char(iter: &mut Scanner) -> Result<Token, String> {
+    iter.reset_peek();
+    let first = iter.next().ok_or("Error while tokenizing".to_owned())?;
+    let mut name = first.to_string();
+    if first.is_ascii_alphanumeric() {
+        name.push_str(
+            &iter
+                .take_while_ref(|c| c.is_ascii_alphanumeric())
+                .collect::<String>(),
+        );
+    }
+    char_from_name(&name)
+        .map(Token::Char)
+        .ok_or("Unknown character name".to_owned())
+}
+
+fn tokenize_str(iter: &mut Scanner) -> Result<Token, String> {
+    iter.reset_peek();
+    let mut text = String::new();
+    loop {
+        match iter.next() {
+            Some('"') => return Ok(Token::Str(text)),
+            Some('\\') => match iter.next() {
+                Some('n') => text.push('\n'),
+                Some('t') => text.push('\t'),
+                Some('r') => text.push('\r'),
+                Some('0') => text.push('\0'),
+                Some(c) if c == '\\' || c == '"' => text.push(c),
+                _ => return Err("Invalid escape sequence".to_owned()),
+            },
+            Some(c) => text.push(c),
+            None => return Err("Unterminated string".to_owned()),
+        }
+    }
+}
+
+fn consume_whitespace(iter: &mut Scanner) -> usize {
     iter.take_while_ref(|c| c.is_whitespace()).count()
 }
 
 pub fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    tokenize_from(text, Position::start()).map(|(tokens, _)| tokens)
+}
+
+// Also returns where each token starts, given where the text does
+pub fn tokenize_from(text: &str, start: Position) -> Result<(Vec<Token>, Vec<Position>), String> {
     let mut result = Vec::new();
-    let mut iter = itertools::multipeek(text.chars());
+    let mut positions = Vec::new();
+    let mut iter = Scanner {
+        chars: text.chars(),
+        peeked: 0,
+        position: start,
+    };
 
-    while let Some(c) = iter.next() {
-        iter.reset_peek();
+    loop {
+        let position = iter.position;
+        let Some(c) = iter.next() else { break };
         let token = match c {
             '(' => {
                 consume_whitespace(&mut iter);
@@ -99,13 +228,31 @@ pub fn tokenize(text: &str) -> Result<Vec<Token>, String> {
                 consume_whitespace(&mut iter);
                 Some(Token::CloseParen)
             }
+            '\'' => Some(Token::QuoteMark),
+            '{' => {
+                consume_whitespace(&mut iter);
+                Some(Token::MapOpen)
+            }
+            '}' => {
+                consume_whitespace(&mut iter);
+                Some(Token::CloseBrace)
+            }
             '.' => match iter.peek() {
                 Some(peeked) if peeked.is_ascii_digit() => Some(tokenize_num('.', &mut iter)?),
+                // The ellipsis used by syntax-rules
+                Some('.') => match iter.peek() {
+                    Some('.') => {
+                        iter.next();
+                        iter.next();
+                        Some(Token::Ident("...".to_owned()))
+                    }
+                    _ => return Err("Error while tokenizing".to_owned()),
+                },
                 _ => Some(Token::Dot),
             },
             '-' => match iter.peek() {
                 Some(peeked) if peeked.is_ascii_digit() => Some(tokenize_num('-', &mut iter)?),
-                Some(peeked) if *peeked == '.' => match iter.peek() {
+                Some('.') => match iter.peek() {
                     Some(peeked) if peeked.is_ascii_digit() => Some(tokenize_num('-', &mut iter)?),
                     _ => return Err("Error while tokenizing".to_owned()),
                 },
@@ -115,28 +262,76 @@ pub fn tokenize(text: &str) -> Result<Vec<Token>, String> {
             '#' => match iter.next() {
                 Some('t') => Some(Token::Bool(true)),
                 Some('f') => Some(Token::Bool(false)),
+                Some('\\') => Some(tokenize_char(&mut iter)?),
+                Some('(') => {
+                    consume_whitespace(&mut iter);
+                    Some(Token::VectorOpen)
+                }
+                Some('{') => {
+                    consume_whitespace(&mut iter);
+                    Some(Token::SetOpen)
+                }
+                Some('u') => match (iter.next(), iter.next()) {
+                    (Some('8'), Some('(')) => {
+                        consume_whitespace(&mut iter);
+                        Some(Token::BytevectorOpen)
+                    }
+                    _ => return Err("Error while tokenizing".to_owned()),
+                },
                 _ => return Err("Error while tokenizing".to_owned()),
             },
+            '"' => Some(tokenize_str(&mut iter)?),
             c if c.is_ascii_digit() => Some(tokenize_num(c, &mut iter)?),
-            c if is_ident_initial(c) => Some(tokenize_ident_or_reserved(c, &mut iter)?),
+            c if is_ident_initial(c) => Some(tokenize_ident(c, &mut iter)?),
             _ if c.is_whitespace() => None,
             _ => return Err("Error while tokenizing".to_owned()),
         };
         if let Some(t) = token {
             // Non-parentheses followed by non-parentheses must have space between
-            if t != Token::OpenParen && t != Token::CloseParen {
-                match iter.peek() {
-                    Some(peeked) if *peeked != ')' && *peeked != '(' => {
-                        if consume_whitespace(&mut iter) < 1 {
-                            return Err("Error while tokenizing".to_owned());
-                        }
-                    }
-                    _ => (),
+            if !t.is_delimiter() {
+                let needs_separator =
+                    matches!(iter.peek(), Some(peeked) if !"(){}".contains(peeked));
+                if needs_separator && consume_whitespace(&mut iter) < 1 {
+                    return Err("Error while tokenizing".to_owned());
                 }
                 iter.reset_peek();
             }
             result.push(t);
+            positions.push(position);
         }
     }
-    Ok(result)
+    Ok((result, positions))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{tokenize, Token};
+
+    #[test]
+    fn char_literals() {
+        let tokens = tokenize("(#\\a #\\space #\\newline #\\x41 #\\( #\\))").unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::OpenParen,
+                Token::Char('a'),
+                Token::Char(' '),
+                Token::Char('\n'),
+                Token::Char('A'),
+                Token::Char('('),
+                Token::Char(')'),
+                Token::CloseParen,
+            ]
+        );
+
+        assert!(tokenize("#\\bogus").is_err());
+    }
+
+    #[test]
+    fn string_literals() {
+        let tokens = tokenize("\"say \\\"hi\\\"\\n\"").unwrap();
+        assert_eq!(tokens, vec![Token::Str("say \"hi\"\n".to_owned())]);
+
+        assert!(tokenize("\"unterminated").is_err());
+    }
 }
@@ -0,0 +1,46 @@
+pub mod backtrace;
+mod bytevectors;
+mod chars;
+mod compiler;
+pub mod conditions;
+mod continuations;
+pub mod environment;
+mod equality;
+mod evaluation;
+pub mod evaluator;
+mod exceptions;
+pub mod expression;
+pub mod gc;
+mod hamt;
+mod hash_tables;
+pub mod interpreter;
+mod io;
+pub mod lexer;
+pub mod limits;
+pub mod list;
+mod lists;
+mod loader;
+pub mod locations;
+mod machine;
+mod macros;
+mod math;
+mod modules;
+pub mod parser;
+mod persistent;
+pub mod ports;
+mod reader;
+mod resolver;
+mod special_forms;
+mod strings;
+pub mod truthiness;
+mod vectors;
+mod vm;
+
+pub use interpreter::Interpreter;
+
+use environment::Environment;
+use expression::Exp;
+
+pub fn eval(exp: &Exp, env: &mut Environment) -> Result<Exp, String> {
+    machine::eval(exp, env)
+}
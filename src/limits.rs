@@ -0,0 +1,344 @@
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::{Cell, RefCell},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+// Budgets for a single evaluation, for running code that can't be trusted to finish. Fuel and the
+// clock are charged for every function call, and the clock is only read now and then. Calls nest
+// only as deep as the depth allows, which keeps the tree-walker from overflowing the Rust stack.
+// Memory is counted by CountingAllocator, which has to be installed as the global allocator for
+// the memory limit to see anything but requests for big vectors. An evaluation can also be
+// interrupted from outside, which stops it like running out of a budget would.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Limits {
+    // How many function calls can be made
+    pub fuel: Option<u64>,
+    pub depth: Option<usize>,
+    // How many bytes the heap can grow by
+    pub memory: Option<usize>,
+    pub time: Option<Duration>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LimitExceeded {
+    Fuel,
+    Depth,
+    Memory,
+    Time,
+    Interrupted,
+}
+
+impl LimitExceeded {
+    pub fn message(self) -> &'static str {
+        match self {
+            LimitExceeded::Fuel => "Out of fuel",
+            LimitExceeded::Depth => "Maximum call depth exceeded",
+            LimitExceeded::Memory => "Memory limit exceeded",
+            LimitExceeded::Time => "Time limit exceeded",
+            LimitExceeded::Interrupted => "Interrupted",
+        }
+    }
+
+    // Which limit an error is about, if any. Lisp can raise an error with the same message, so
+    // it only counts if that limit was actually exceeded during the evaluation. Only the end is
+    // compared, since loading a file prefixes errors with where they happened.
+    pub fn of(err: &str) -> Option<Self> {
+        EXCEEDED
+            .with(Cell::get)
+            .filter(|limit| err.ends_with(limit.message()))
+    }
+
+    fn err<T>(self) -> Result<T, String> {
+        EXCEEDED.with(|exceeded| exceeded.set(Some(self)));
+        Err(self.message().to_owned())
+    }
+}
+
+// Calls between looking at the clock and for interrupts
+const CHECK_INTERVAL: u64 = 1024;
+
+thread_local! {
+    // Fuel is handed out CHECK_INTERVAL at a time, so only a countdown is kept per call
+    static COUNTDOWN: Cell<u64> = const { Cell::new(CHECK_INTERVAL) };
+    static FUEL: Cell<u64> = const { Cell::new(u64::MAX) };
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+    static MAX_DEPTH: Cell<usize> = const { Cell::new(usize::MAX) };
+    static INTERRUPT: RefCell<Interrupt> = RefCell::new(Interrupt::default());
+    // The limit the evaluation has run into, if any
+    static EXCEEDED: Cell<Option<LimitExceeded>> = const { Cell::new(None) };
+}
+
+// Stops an interpreter's evaluation the next time the clock would be looked at. It only sets a
+// flag, so it can be used from another thread or a signal handler.
+#[derive(Clone, Debug, Default)]
+pub struct Interrupt(Arc<AtomicBool>);
+
+impl Interrupt {
+    pub fn interrupt(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    fn interrupted(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// The allocator counts for the whole process, so only one memory limit applies at a time
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static MEMORY_CAP: AtomicUsize = AtomicUsize::new(usize::MAX);
+static OVER_CAP: AtomicBool = AtomicBool::new(false);
+
+pub struct CountingAllocator;
+
+fn allocated(size: usize) {
+    let total = ALLOCATED.fetch_add(size, Ordering::Relaxed) + size;
+    if total > MEMORY_CAP.load(Ordering::Relaxed) {
+        OVER_CAP.store(true, Ordering::Relaxed);
+    }
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            allocated(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            allocated(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = System.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+            allocated(new_size);
+        }
+        new_ptr
+    }
+}
+
+// Starts the budgets of an evaluation
+pub fn start(limits: &Limits, interrupt: &Interrupt) {
+    // Interrupting applies to the evaluation that's running, not the next one
+    interrupt.0.store(false, Ordering::Relaxed);
+    INTERRUPT.with(|current| *current.borrow_mut() = interrupt.clone());
+    let fuel = limits.fuel.unwrap_or(u64::MAX);
+    let countdown = fuel.min(CHECK_INTERVAL);
+    COUNTDOWN.with(|current| current.set(countdown));
+    FUEL.with(|current| current.set(fuel - countdown));
+    DEADLINE.with(|deadline| deadline.set(limits.time.map(|time| Instant::now() + time)));
+    DEPTH.with(|depth| depth.set(0));
+    MAX_DEPTH.with(|max| max.set(limits.depth.unwrap_or(usize::MAX)));
+    let cap = limits.memory.map_or(usize::MAX, |memory| {
+        ALLOCATED.load(Ordering::Relaxed).saturating_add(memory)
+    });
+    MEMORY_CAP.store(cap, Ordering::Relaxed);
+    OVER_CAP.store(false, Ordering::Relaxed);
+    EXCEEDED.with(|exceeded| exceeded.set(None));
+}
+
+// Charges for a function call
+#[inline(always)]
+pub fn tick() -> Result<(), String> {
+    let countdown = COUNTDOWN.with(Cell::get);
+    if countdown == 0 || OVER_CAP.load(Ordering::Relaxed) {
+        return check();
+    }
+    COUNTDOWN.with(|current| current.set(countdown - 1));
+    Ok(())
+}
+
+fn check() -> Result<(), String> {
+    if INTERRUPT.with(|interrupt| interrupt.borrow().interrupted()) {
+        return LimitExceeded::Interrupted.err();
+    }
+    if OVER_CAP.load(Ordering::Relaxed) {
+        return LimitExceeded::Memory.err();
+    }
+    if let Some(deadline) = DEADLINE.with(Cell::get) {
+        if Instant::now() >= deadline {
+            return LimitExceeded::Time.err();
+        }
+    }
+    if COUNTDOWN.with(Cell::get) == 0 {
+        let fuel = FUEL.with(Cell::get);
+        if fuel == 0 {
+            return LimitExceeded::Fuel.err();
+        }
+        let countdown = fuel.min(CHECK_INTERVAL);
+        FUEL.with(|current| current.set(fuel - countdown));
+        COUNTDOWN.with(|current| current.set(countdown - 1));
+    }
+    Ok(())
+}
+
+// Fails if allocating this many more bytes would go over the memory limit, for builtins that
+// allocate an amount they're asked for
+pub fn reserve(bytes: usize) -> Result<(), String> {
+    let total = ALLOCATED.load(Ordering::Relaxed).saturating_add(bytes);
+    if total > MEMORY_CAP.load(Ordering::Relaxed) {
+        return LimitExceeded::Memory.err();
+    }
+    Ok(())
+}
+
+// Nesting calls levels deeper, until it's dropped
+pub struct Nested(usize);
+
+pub fn nest(levels: usize) -> Result<Nested, String> {
+    let depth = DEPTH.with(Cell::get) + levels;
+    if depth > MAX_DEPTH.with(Cell::get) {
+        return LimitExceeded::Depth.err();
+    }
+    DEPTH.with(|current| current.set(depth));
+    Ok(Nested(levels))
+}
+
+impl Drop for Nested {
+    fn drop(&mut self) {
+        DEPTH.with(|depth| depth.set(depth.get() - self.0));
+    }
+}
+
+pub fn depth() -> usize {
+    DEPTH.with(Cell::get)
+}
+
+pub fn max_depth() -> usize {
+    MAX_DEPTH.with(Cell::get)
+}
+
+pub fn depth_exceeded<T>() -> Result<T, String> {
+    LimitExceeded::Depth.err()
+}
+
+#[cfg(test)]
+mod test {
+    use std::{thread, time::Duration};
+
+    use super::{LimitExceeded, Limits};
+    use crate::{evaluator::Evaluator, Interpreter};
+
+    #[test]
+    fn limits_evaluation() {
+        let cases = [
+            (
+                Limits {
+                    fuel: Some(1000),
+                    ..Limits::default()
+                },
+                "(spin 40)",
+                LimitExceeded::Fuel,
+            ),
+            (
+                Limits {
+                    depth: Some(100),
+                    ..Limits::default()
+                },
+                "(deep 0)",
+                LimitExceeded::Depth,
+            ),
+            (
+                Limits {
+                    memory: Some(1_000_000),
+                    ..Limits::default()
+                },
+                "(make-vector 1000000000)",
+                LimitExceeded::Memory,
+            ),
+            (
+                Limits {
+                    time: Some(Duration::from_millis(50)),
+                    ..Limits::default()
+                },
+                "(spin 40)",
+                LimitExceeded::Time,
+            ),
+        ];
+        for evaluator in [Evaluator::Bytecode, Evaluator::TreeWalker] {
+            let mut interpreter = Interpreter::new();
+            interpreter.set_evaluator(evaluator);
+            // Takes forever without nesting deeply
+            interpreter
+                .eval_str(
+                    "(def spin (lambda (n) (if (= n 0) 0 (+ (spin (- n 1)) (spin (- n 1))))))",
+                )
+                .unwrap();
+            interpreter
+                .eval_str("(def deep (lambda (n) (+ 1 (deep n))))")
+                .unwrap();
+            for (limits, form, expected) in cases {
+                interpreter.set_limits(limits);
+                let err = interpreter.eval_str(form).unwrap_err();
+                assert_eq!(
+                    LimitExceeded::of(&err),
+                    Some(expected),
+                    "{:?} {}",
+                    evaluator,
+                    form
+                );
+                // Each evaluation gets the whole budget again
+                assert_eq!(interpreter.eval_str("(+ 1 2)").unwrap().to_string(), "3");
+            }
+            // Limits can't be caught, and an error that only says the same isn't one
+            interpreter.set_limits(Limits {
+                depth: Some(100),
+                ..Limits::default()
+            });
+            let err = interpreter
+                .eval_str("(guard (e (#t 'caught)) (deep 0))")
+                .unwrap_err();
+            assert_eq!(LimitExceeded::of(&err), Some(LimitExceeded::Depth));
+            let err = interpreter.eval_str("(error \"Out of fuel\")").unwrap_err();
+            assert_eq!(LimitExceeded::of(&err), None);
+            assert_eq!(
+                interpreter
+                    .eval_str("(guard (e (#t 'caught)) (error \"Out of fuel\"))")
+                    .unwrap()
+                    .to_string(),
+                "caught"
+            );
+        }
+    }
+
+    #[test]
+    fn interrupts_evaluation() {
+        for evaluator in [Evaluator::Bytecode, Evaluator::TreeWalker] {
+            let mut interpreter = Interpreter::new();
+            interpreter.set_evaluator(evaluator);
+            interpreter
+                .eval_str(
+                    "(def spin (lambda (n) (if (= n 0) 0 (+ (spin (- n 1)) (spin (- n 1))))))",
+                )
+                .unwrap();
+            let interrupt = interpreter.interrupt_handle();
+            let interrupter = thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                interrupt.interrupt();
+            });
+            let err = interpreter.eval_str("(spin 60)").unwrap_err();
+            interrupter.join().unwrap();
+            assert_eq!(err, "Interrupted", "{:?}", evaluator);
+            // Being interrupted doesn't carry over, and what was defined is still there
+            assert_eq!(interpreter.eval_str("(spin 2)").unwrap().to_string(), "0");
+        }
+    }
+}
@@ -1,5 +1,7 @@
 use core::fmt;
-use std::rc::Rc;
+use std::rc::{Rc, Weak};
+
+use crate::gc::{Trace, Tracer};
 
 #[derive(Clone)]
 pub struct List<T> {
@@ -12,6 +14,11 @@ pub struct Iter<'a, T> {
 
 type Link<T> = Option<Rc<Node<T>>>;
 
+// Refers to a list without keeping it alive
+pub struct WeakList<T> {
+    head: Weak<Node<T>>,
+}
+
 struct Node<T> {
     elem: T,
     next: Link<T>,
@@ -50,6 +57,28 @@ impl<T> List<T> {
         }
     }
 
+    // Whether both lists share the same first node
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        match (&self.head, &other.head) {
+            (Some(a), Some(b)) => Rc::ptr_eq(a, b),
+            (None, None) => true,
+            _ => false,
+        }
+    }
+
+    // The address of the first node, which identifies the list for as long as it's alive
+    pub fn as_ptr(&self) -> *const () {
+        self.head
+            .as_ref()
+            .map_or(std::ptr::null(), |node| Rc::as_ptr(node) as *const ())
+    }
+
+    pub fn downgrade(&self) -> WeakList<T> {
+        WeakList {
+            head: self.head.as_ref().map_or_else(Weak::new, Rc::downgrade),
+        }
+    }
+
     pub fn iter(&'_ self) -> Iter<'_, T> {
         Iter {
             next: self.head.as_deref(),
@@ -57,6 +86,25 @@ impl<T> List<T> {
     }
 }
 
+impl<T> WeakList<T> {
+    pub fn is_alive(&self) -> bool {
+        self.head.strong_count() > 0
+    }
+
+    // Whether this refers to the same first node as the list
+    pub fn refers_to(&self, list: &List<T>) -> bool {
+        list.head
+            .as_ref()
+            .is_some_and(|node| Rc::as_ptr(node) == self.head.as_ptr())
+    }
+}
+
+impl<T> Default for List<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<T> Drop for List<T> {
     fn drop(&mut self) {
         let mut head = self.head.take();
@@ -73,6 +121,32 @@ impl<T> Drop for List<T> {
     }
 }
 
+// Walks the nodes iteratively so long lists can't overflow the stack
+impl<T: Trace> Trace for List<T> {
+    fn trace(&self, tracer: &mut Tracer) {
+        let mut link = &self.head;
+        let mut previous = None;
+        while let Some(node) = link {
+            let ptr = Rc::as_ptr(node) as *const ();
+            let strong = Rc::strong_count(node);
+            let first_visit = match previous {
+                None => tracer.edge(ptr, strong),
+                Some(previous) => {
+                    let mut first_visit = false;
+                    tracer.within(previous, |tracer| first_visit = tracer.edge(ptr, strong));
+                    first_visit
+                }
+            };
+            if !first_visit {
+                break;
+            }
+            tracer.within(ptr, |tracer| node.elem.trace(tracer));
+            previous = Some(ptr);
+            link = &node.next;
+        }
+    }
+}
+
 impl<T: fmt::Debug> fmt::Debug for List<T> {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         fmt.debug_list().entries(self.iter()).finish()
@@ -0,0 +1,66 @@
+use crate::{expression::Exp, list::List, persistent};
+
+pub fn list(args: &List<Exp>) -> Result<Exp, String> {
+    Ok(Exp::List(args.clone()))
+}
+
+// Returns the first sublist whose head matches the item, or #f
+fn find_tail(args: &List<Exp>, same: fn(&Exp, &Exp) -> bool) -> Result<Exp, String> {
+    let mut args_iter = args.iter();
+    let item = args_iter
+        .next()
+        .ok_or("Missing required argument".to_owned())?;
+    let mut list = match args_iter.next() {
+        Some(Exp::List(list)) => list.clone(),
+        _ => return Err("Type error".to_owned()),
+    };
+    loop {
+        match list.head() {
+            Some(head) if same(item, head) => return Ok(Exp::List(list)),
+            Some(_) => list = list.tail().expect("List with head but no tail"),
+            None => return Ok(Exp::Bool(false)),
+        }
+    }
+}
+
+// Returns the first entry of an association list whose key matches, or #f
+fn find_entry(args: &List<Exp>, same: fn(&Exp, &Exp) -> bool) -> Result<Exp, String> {
+    let mut args_iter = args.iter();
+    let key = args_iter
+        .next()
+        .ok_or("Missing required argument".to_owned())?;
+    let alist = match args_iter.next() {
+        Some(Exp::List(list)) => list,
+        _ => return Err("Type error".to_owned()),
+    };
+    for entry in alist.iter() {
+        match entry {
+            Exp::List(pair) if pair.head().is_some_and(|head| same(key, head)) => {
+                return Ok(entry.clone())
+            }
+            Exp::List(_) => (),
+            _ => return Err("Association list entries must be lists".to_owned()),
+        }
+    }
+    Ok(Exp::Bool(false))
+}
+
+pub fn memq(args: &List<Exp>) -> Result<Exp, String> {
+    find_tail(args, Exp::is_eqv)
+}
+
+pub fn member(args: &List<Exp>) -> Result<Exp, String> {
+    find_tail(args, Exp::eq)
+}
+
+pub fn assq(args: &List<Exp>) -> Result<Exp, String> {
+    find_entry(args, Exp::is_eqv)
+}
+
+// Given a map this is the persistent map update, otherwise it's the association list lookup
+pub fn assoc(args: &List<Exp>) -> Result<Exp, String> {
+    match args.head() {
+        Some(Exp::Map(_)) => persistent::assoc(args),
+        _ => find_entry(args, Exp::eq),
+    }
+}
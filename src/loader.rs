@@ -0,0 +1,217 @@
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    env, fs,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use crate::{
+    environment::Environment, exceptions, expression::Exp, lexer::Position, list::List,
+    ports::Port, reader, vm,
+};
+
+// The values a module exports, by name
+pub type Exports = Rc<Vec<(String, Exp)>>;
+
+// Keeps track of which files have been loaded, and which are being loaded right now. Modules are
+// cached here too, so each is only evaluated once.
+#[derive(Default)]
+pub struct Loader {
+    loaded: RefCell<HashSet<PathBuf>>,
+    loading: RefCell<Vec<PathBuf>>,
+    modules: RefCell<HashMap<String, Exports>>,
+    importing: RefCell<Vec<String>>,
+    // The names given to export by each module currently being evaluated, innermost last. None
+    // until the module's first export form.
+    exporting: RefCell<Vec<Option<Vec<String>>>>,
+}
+
+thread_local! {
+    static CURRENT: RefCell<Rc<Loader>> = RefCell::new(Rc::new(Loader::default()));
+}
+
+impl Loader {
+    pub fn current() -> Rc<Loader> {
+        CURRENT.with(|loader| loader.borrow().clone())
+    }
+
+    pub fn set_current(loader: Rc<Loader>) {
+        CURRENT.with(|current| *current.borrow_mut() = loader)
+    }
+
+    // Relative paths are tried against the directory of the file being loaded (or the working
+    // directory at the top level), then each directory in LIASP_PATH
+    pub fn resolve(&self, path: &str) -> Result<PathBuf, String> {
+        let path = Path::new(path);
+        let base = self
+            .loading
+            .borrow()
+            .last()
+            .and_then(|file| file.parent().map(Path::to_path_buf))
+            .unwrap_or_default();
+        let search_path = env::var_os("LIASP_PATH").unwrap_or_default();
+        let mut candidates = vec![base.join(path)];
+        if path.is_relative() {
+            candidates.extend(env::split_paths(&search_path).map(|dir| dir.join(path)));
+        }
+        candidates
+            .into_iter()
+            .find(|candidate| candidate.is_file())
+            .and_then(|found| fs::canonicalize(found).ok())
+            .ok_or(format!("Could not find file '{}'", path.display()))
+    }
+
+    pub fn is_loaded(&self, path: &Path) -> bool {
+        self.loaded.borrow().contains(path)
+    }
+
+    // Evaluates every form in the file into env. Errors are prefixed with file:line.
+    pub fn load(&self, path: &Path, env: &mut Environment) -> Result<(), String> {
+        if self.loading.borrow().iter().any(|file| file == path) {
+            return Err(format!("Circular load of '{}'", path.display()));
+        }
+        let port = Port::open_input_file(&path.to_string_lossy(), false)?;
+        self.loading.borrow_mut().push(path.to_path_buf());
+        let file = path.to_string_lossy().into();
+        let result = eval_all(&port, &file, env).map_err(|(line, err)| {
+            exceptions::annotate(err, |err| format!("{}:{}: {}", path.display(), line, err))
+        });
+        self.loading.borrow_mut().pop();
+        // A file that failed to load can be fixed and loaded again
+        if result.is_ok() {
+            self.loaded.borrow_mut().insert(path.to_path_buf());
+        }
+        result
+    }
+}
+
+impl Loader {
+    pub fn define_module(&self, name: &str, exports: Exports) {
+        self.modules.borrow_mut().insert(name.to_owned(), exports);
+    }
+
+    // Modules that haven't been defined yet are loaded from name.lsp on the search path
+    pub fn import(&self, name: &str) -> Result<Exports, String> {
+        if let Some(exports) = self.modules.borrow().get(name) {
+            return Ok(exports.clone());
+        }
+        if self.importing.borrow().iter().any(|module| module == name) {
+            return Err(format!("Circular import of module '{}'", name));
+        }
+        let path = self.resolve(&format!("{}.lsp", name))?;
+        let global = Environment::interaction().ok_or("No interaction environment".to_owned())?;
+
+        self.importing.borrow_mut().push(name.to_owned());
+        let result = self.eval_module(&global, |env| self.load(&path, env));
+        self.importing.borrow_mut().pop();
+
+        let exports = result?;
+        self.define_module(name, exports.clone());
+        Ok(exports)
+    }
+
+    // Runs body in a fresh environment below parent, then collects what it exported. If there
+    // was no export form, every definition is exported.
+    pub fn eval_module(
+        &self,
+        parent: &Environment,
+        body: impl FnOnce(&mut Environment) -> Result<(), String>,
+    ) -> Result<Exports, String> {
+        let mut env = parent.extend();
+        self.exporting.borrow_mut().push(None);
+        let result = body(&mut env);
+        let names = self.exporting.borrow_mut().pop().unwrap();
+        result?;
+
+        let exports = match names {
+            Some(names) => names
+                .into_iter()
+                .map(|name| match env.lookup(&name) {
+                    Some(val) => Ok((name, val)),
+                    None => Err(format!("Exported identifier '{}' is not defined", name)),
+                })
+                .collect::<Result<Vec<_>, String>>()?,
+            None => env.local_bindings(),
+        };
+        Ok(Rc::new(exports))
+    }
+
+    pub fn export(&self, names: Vec<String>) -> Result<(), String> {
+        match self.exporting.borrow_mut().last_mut() {
+            Some(exports) => {
+                exports.get_or_insert_with(Vec::new).extend(names);
+                Ok(())
+            }
+            None => Err("Export outside of a module".to_owned()),
+        }
+    }
+}
+
+fn eval_all(port: &Port, file: &Rc<str>, env: &mut Environment) -> Result<(), (usize, String)> {
+    let mut position = Position::start();
+    loop {
+        let (exp, start) = reader::read_datum_at(port, &mut position, Some(file))
+            .map_err(|err| (position.line, err))?;
+        if let Exp::Eof = exp {
+            return Ok(());
+        }
+        vm::eval_toplevel(&exp, env).map_err(|err| (start.line, err))?;
+    }
+}
+
+fn path_arg(arg: Option<&Exp>) -> Result<PathBuf, String> {
+    match arg {
+        Some(Exp::Str(path)) => Loader::current().resolve(path),
+        _ => Err("Type error".to_owned()),
+    }
+}
+
+pub fn load(args: &List<Exp>) -> Result<Exp, String> {
+    let path = path_arg(args.head())?;
+    let mut env = Environment::interaction().ok_or("No interaction environment".to_owned())?;
+    Loader::current().load(&path, &mut env)?;
+    Ok(Exp::Void)
+}
+
+// Whether the file has already been loaded, so that files can guard against loading twice
+pub fn is_loaded(args: &List<Exp>) -> Result<Exp, String> {
+    let path = path_arg(args.head())?;
+    Ok(Exp::Bool(Loader::current().is_loaded(&path)))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env, fs, process};
+
+    use crate::{expression::Exp, Interpreter};
+
+    #[test]
+    fn loads_files() {
+        let dir = env::temp_dir().join(format!("liasp-load-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let good = dir.join("good.lsp");
+        let bad = dir.join("bad.lsp");
+        fs::write(&good, "(def loaded-value 42)\n").unwrap();
+        fs::write(&bad, "(def partial 1)\n(+ 1 \"a\")\n").unwrap();
+        let (good, bad) = (good.to_str().unwrap(), bad.to_str().unwrap());
+
+        let mut interpreter = Interpreter::new();
+        interpreter.eval_str(&format!("(load {:?})", good)).unwrap();
+        assert_eq!(interpreter.eval_str("loaded-value"), Ok(Exp::Number(42.0)));
+        assert_eq!(
+            interpreter.eval_str(&format!("(loaded? {:?})", good)),
+            Ok(Exp::Bool(true))
+        );
+
+        let err = interpreter
+            .eval_str(&format!("(load {:?})", bad))
+            .unwrap_err();
+        assert!(err.ends_with("bad.lsp:2: Type error"), "{}", err);
+        assert_eq!(
+            interpreter.eval_str(&format!("(loaded? {:?})", bad)),
+            Ok(Exp::Bool(false))
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
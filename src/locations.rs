@@ -0,0 +1,76 @@
+use core::fmt;
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use crate::{
+    expression::Exp,
+    lexer::Position,
+    list::{List, WeakList},
+};
+
+// Where the lists read from files started, so backtraces can point at the calls they show. Lists
+// are known by their first node, and forgotten once that's freed.
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Location {
+    pub file: Rc<str>,
+    pub position: Position,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}",
+            self.file, self.position.line, self.position.column
+        )
+    }
+}
+
+struct Locations {
+    lists: HashMap<*const (), (WeakList<Exp>, Location)>,
+    prune_at: usize,
+}
+
+impl Locations {
+    fn prune(&mut self) {
+        self.lists.retain(|_, (list, _)| list.is_alive());
+        self.prune_at = (self.lists.len() * 2).max(1024);
+    }
+}
+
+thread_local! {
+    static LOCATIONS: RefCell<Locations> = RefCell::new(Locations { lists: HashMap::new(), prune_at: 1024 });
+}
+
+pub fn record(list: &List<Exp>, location: Location) {
+    if list.head().is_none() {
+        return;
+    }
+    LOCATIONS.with(|locations| {
+        let mut locations = locations.borrow_mut();
+        if locations.lists.len() >= locations.prune_at {
+            locations.prune();
+        }
+        locations
+            .lists
+            .insert(list.as_ptr(), (list.downgrade(), location));
+    });
+}
+
+pub fn of(list: &List<Exp>) -> Option<Location> {
+    LOCATIONS.with(|locations| {
+        locations
+            .borrow()
+            .lists
+            .get(&list.as_ptr())
+            .filter(|(known, _)| known.refers_to(list))
+            .map(|(_, location)| location.clone())
+    })
+}
+
+// Gives a list that was rewritten from another the other's location
+pub fn copy(from: &List<Exp>, to: &List<Exp>) {
+    if let Some(location) = of(from) {
+        record(to, location);
+    }
+}
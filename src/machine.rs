@@ -0,0 +1,749 @@
+use std::{ptr, rc::Rc};
+
+use crate::{
+    backtrace,
+    conditions::{self, Restart, RestartClause},
+    continuations::{self, Active, Continuation, Winder},
+    environment::Environment,
+    evaluation,
+    exceptions::{self, Handler},
+    expression::{Exp, Function},
+    gc::{Trace, Tracer},
+    limits,
+    list::List,
+    macros, special_forms,
+    vm::{self, Step, Suspended},
+};
+
+// Evaluation keeps what's left to do in frames on the heap instead of on the Rust stack, so
+// call/cc can copy them and a continuation can be resumed however often, wherever it was captured.
+// Compiled code runs on the VM, which hands back calls of functions that aren't compiled, so
+// that the rest of its run becomes a frame too. Builtins are still called from Rust, and what
+// they call runs on a machine of its own, which a continuation can only escape from.
+
+type Builtin = fn(&List<Exp>) -> Result<Exp, String>;
+type SpecialForm = fn(&List<Exp>, &mut Environment) -> Result<Exp, String>;
+
+#[derive(Clone)]
+pub enum Frame {
+    // A form whose head is being evaluated
+    Head {
+        form: List<Exp>,
+        env: Environment,
+    },
+    // A call whose arguments are being evaluated
+    Args {
+        f: Function,
+        form: List<Exp>,
+        rest: List<Exp>,
+        done: Vec<Exp>,
+        env: Environment,
+    },
+    If {
+        then: Exp,
+        otherwise: Exp,
+        env: Environment,
+    },
+    Def {
+        name: String,
+        env: Environment,
+    },
+    Set {
+        name: String,
+        env: Environment,
+    },
+    Sequence {
+        rest: List<Exp>,
+        env: Environment,
+    },
+    // A function running, for backtraces and the depth limit
+    Call {
+        f: Function,
+        args: List<Exp>,
+        call: Option<Exp>,
+    },
+    // A run of the VM waiting for a call to return
+    Vm(Suspended),
+    // The handlers to put back once the body of a handler-bind is done
+    Handlers(List<Rc<Handler>>),
+    Guard(Rc<Guard>),
+    // A guard clause whose test is being evaluated
+    Clause {
+        exprs: List<Exp>,
+        rest: List<Exp>,
+        env: Environment,
+        obj: Exp,
+    },
+    // A guard clause's (test => f), waiting for f to call it with the test's value
+    Arrow(Exp),
+    ExceptionHandler {
+        handler: Exp,
+        outer: List<Rc<Handler>>,
+    },
+    // A with-exception-handler handler that was called for a raise, which it can't return from
+    HandlerReturned,
+    Restarts(List<Rc<Restart>>),
+    // Where a restart-case's restarts resume, with which one was invoked and its arguments
+    Restarted {
+        clauses: Rc<Vec<RestartClause>>,
+        env: Environment,
+    },
+}
+
+pub struct Guard {
+    var: String,
+    clauses: List<Exp>,
+    env: Environment,
+    winders: List<Rc<Winder>>,
+    outer: List<Rc<Handler>>,
+}
+
+impl Frame {
+    // How many calls the frame counts as, for the depth limit
+    fn depth(&self) -> usize {
+        match self {
+            Frame::Call { .. } => 1,
+            Frame::Vm(state) => state.depth(),
+            _ => 0,
+        }
+    }
+}
+
+pub enum Control {
+    Eval(Exp, Environment),
+    Return(Exp),
+    // A function, its arguments and the call expression, if it was called from code
+    Apply(Function, List<Exp>, Option<Exp>),
+    // Compiled code to run, with the value of the call it stopped at if it's being resumed
+    Run(Suspended, Option<Exp>),
+}
+
+struct Machine {
+    frames: Vec<Frame>,
+    // How many calls the frames count as
+    depth: usize,
+    active: Active,
+    // Whether it's the interpreter's own, whose continuations later forms can resume
+    rooted: bool,
+}
+
+pub fn eval(exp: &Exp, env: &Environment) -> Result<Exp, String> {
+    run(Control::Eval(exp.clone(), env.clone()))
+}
+
+pub fn call(f: &Function, args: &List<Exp>) -> Result<Exp, String> {
+    run(Control::Apply(f.clone(), args.clone(), None))
+}
+
+pub fn run(control: Control) -> Result<Exp, String> {
+    Machine::new(false).run(control)
+}
+
+// Evaluates a form for the interpreter, which can resume continuations captured by earlier forms
+pub fn run_rooted(control: Control) -> Result<Exp, String> {
+    let mut machine = Machine::new(true);
+    continuations::root(machine.active.id(), || machine.run(control))
+}
+
+// Special forms the machine evaluates itself
+#[derive(Clone, Copy)]
+pub enum Native {
+    If,
+    Def,
+    Set,
+    Guard,
+    HandlerBind,
+    RestartCase,
+}
+
+impl Native {
+    // Functions that only pass themselves on can be merged into one by the optimizer, so the
+    // special forms' functions pass these instead, and are told apart by them
+    fn of(special: SpecialForm) -> Option<Native> {
+        let is = |other: SpecialForm| ptr::fn_addr_eq(special, other);
+        if is(special_forms::if_exp) {
+            Some(Native::If)
+        } else if is(special_forms::def) {
+            Some(Native::Def)
+        } else if is(special_forms::set) {
+            Some(Native::Set)
+        } else if is(exceptions::guard) {
+            Some(Native::Guard)
+        } else if is(conditions::handler_bind) {
+            Some(Native::HandlerBind)
+        } else if is(conditions::restart_case) {
+            Some(Native::RestartCase)
+        } else {
+            None
+        }
+    }
+}
+
+// Evaluates a special form the machine handles itself, for when it's called from Rust
+pub fn special_form(native: Native, args: &List<Exp>, env: &Environment) -> Result<Exp, String> {
+    let mut machine = Machine::new(false);
+    let control = machine.native(native, args.clone(), env.clone())?;
+    machine.run(control)
+}
+
+// Builtins that work with what's left to do, which the machine calls itself
+pub fn is_control(f: Builtin) -> bool {
+    let is = |other: Builtin| ptr::fn_addr_eq(f, other);
+    is(continuations::call_cc) || is(evaluation::apply) || is(exceptions::with_exception_handler)
+}
+
+fn value(exp: &Exp, env: &Environment) -> Result<Exp, String> {
+    match exp {
+        Exp::Resolved(resolved) => resolved
+            .lookup(env)
+            .map_or_else(|| conditions::unbound(&resolved.name), Ok),
+        Exp::Ident(ident) => env
+            .lookup(ident)
+            .map_or_else(|| conditions::unbound(ident), Ok),
+        exp => Ok(exp.clone()),
+    }
+}
+
+impl Machine {
+    fn new(rooted: bool) -> Self {
+        Machine {
+            frames: Vec::new(),
+            depth: 0,
+            active: Active::enter(),
+            rooted,
+        }
+    }
+
+    fn push(&mut self, frame: Frame) {
+        self.depth += frame.depth();
+        self.frames.push(frame);
+    }
+
+    fn pop(&mut self) -> Option<Frame> {
+        let frame = self.frames.pop()?;
+        self.depth -= frame.depth();
+        Some(frame)
+    }
+
+    fn run(&mut self, mut control: Control) -> Result<Exp, String> {
+        loop {
+            let next = match control {
+                Control::Eval(exp, env) => self.eval(exp, env),
+                Control::Return(val) => match self.pop() {
+                    Some(frame) => self.ret(frame, val),
+                    None => return Ok(val),
+                },
+                Control::Apply(f, args, call) => self.apply(f, args, call),
+                Control::Run(state, value) => self.run_vm(state, value),
+            };
+            control = match next {
+                Ok(control) => control,
+                Err(err) => self.unwind(err)?,
+            };
+        }
+    }
+
+    fn eval(&mut self, exp: Exp, env: Environment) -> Result<Control, String> {
+        let Exp::List(form) = exp else {
+            return value(&exp, &env).map(Control::Return);
+        };
+        match form.head().ok_or("Error while evaluating".to_owned())? {
+            // The head decides how the rest is treated, so it's evaluated first
+            Exp::List(_) => {
+                let head = form.head().unwrap().clone();
+                self.push(Frame::Head {
+                    form,
+                    env: env.clone(),
+                });
+                Ok(Control::Eval(head, env))
+            }
+            head => {
+                let head = value(head, &env)?;
+                self.dispatch(head, form, env)
+            }
+        }
+    }
+
+    fn dispatch(
+        &mut self,
+        head: Exp,
+        form: List<Exp>,
+        env: Environment,
+    ) -> Result<Control, String> {
+        match head {
+            Exp::SpecialForm(special) => {
+                let args = form.tail().unwrap();
+                match Native::of(special) {
+                    Some(native) => self.native(native, args, env),
+                    None => {
+                        let mut env = env;
+                        let _nested = limits::nest(self.depth + 1)?;
+                        special(&args, &mut env).map(Control::Return)
+                    }
+                }
+            }
+            // Macros get the unevaluated form and the expansion is evaluated in its place
+            Exp::Macro(macro_) => {
+                limits::tick()?;
+                let _nested = limits::nest(self.depth + 1)?;
+                let expansion = macros::expand_cached(&macro_, &form, &env, &|_| false)?;
+                Ok(Control::Eval(expansion, env))
+            }
+            Exp::Function(f) => {
+                limits::tick()?;
+                let rest = form.tail().unwrap();
+                self.args(f, form, rest, Vec::new(), env)
+            }
+            _ => Err("Error while evaluating".to_owned()),
+        }
+    }
+
+    // Evaluates the remaining arguments of a call, and then makes it. Only arguments that are
+    // forms themselves need a frame.
+    fn args(
+        &mut self,
+        f: Function,
+        form: List<Exp>,
+        mut rest: List<Exp>,
+        mut done: Vec<Exp>,
+        env: Environment,
+    ) -> Result<Control, String> {
+        while let Some(arg) = rest.head().cloned() {
+            rest = rest.tail().unwrap();
+            if let Exp::List(_) = arg {
+                self.push(Frame::Args {
+                    f,
+                    form,
+                    rest,
+                    done,
+                    env: env.clone(),
+                });
+                return Ok(Control::Eval(arg, env));
+            }
+            done.push(value(&arg, &env)?);
+        }
+        Ok(Control::Apply(
+            f,
+            List::from_vec(done),
+            Some(Exp::List(form)),
+        ))
+    }
+
+    fn native(
+        &mut self,
+        native: Native,
+        args: List<Exp>,
+        env: Environment,
+    ) -> Result<Control, String> {
+        match native {
+            Native::If => {
+                let (test, then, otherwise) = special_forms::if_parts(&args)?;
+                self.push(Frame::If {
+                    then,
+                    otherwise,
+                    env: env.clone(),
+                });
+                Ok(Control::Eval(test, env))
+            }
+            Native::Def => {
+                let (name, val) = special_forms::target(&args)?;
+                self.push(Frame::Def {
+                    name,
+                    env: env.clone(),
+                });
+                Ok(Control::Eval(val, env))
+            }
+            Native::Set => {
+                let (name, val) = special_forms::target(&args)?;
+                self.push(Frame::Set {
+                    name,
+                    env: env.clone(),
+                });
+                Ok(Control::Eval(val, env))
+            }
+            Native::Guard => {
+                let (var, clauses, body) = exceptions::guard_parts(&args)?;
+                let guard = Guard {
+                    var,
+                    clauses,
+                    env: env.clone(),
+                    winders: continuations::winders(),
+                    outer: exceptions::push_handler(Handler::Guard),
+                };
+                self.push(Frame::Guard(Rc::new(guard)));
+                Ok(self.sequence(body, env))
+            }
+            Native::HandlerBind => {
+                let (handlers, body) = {
+                    let _nested = limits::nest(self.depth + 1)?;
+                    conditions::bound_handlers(&args, &env)?
+                };
+                let outer = exceptions::set_handlers(handlers);
+                self.push(Frame::Handlers(outer));
+                Ok(self.sequence(body, env))
+            }
+            Native::RestartCase => {
+                let (form, clauses) = conditions::restart_clauses(&args)?;
+                let infos = clauses.iter().map(|(info, _)| info.clone()).collect();
+                // The restarts resume a copy of the frames that continues with the chosen
+                // clause, while the form returns past it
+                let mut frames = self.frames.clone();
+                frames.push(Frame::Restarted {
+                    clauses: Rc::new(clauses),
+                    env: env.clone(),
+                });
+                let continuation = Continuation::new(Some(frames), self.active.id(), self.rooted);
+                let outer = conditions::establish(infos, Rc::new(continuation));
+                self.push(Frame::Restarts(outer));
+                Ok(Control::Eval(form, env))
+            }
+        }
+    }
+
+    // Evaluates forms in order, returning the value of the last
+    fn sequence(&mut self, forms: List<Exp>, env: Environment) -> Control {
+        let Some(first) = forms.head().cloned() else {
+            return Control::Return(Exp::Void);
+        };
+        let rest = forms.tail().unwrap();
+        if rest.head().is_some() {
+            self.push(Frame::Sequence {
+                rest,
+                env: env.clone(),
+            });
+        }
+        Control::Eval(first, env)
+    }
+
+    fn check_depth(&self) -> Result<(), String> {
+        if limits::depth() + self.depth + 1 > limits::max_depth() {
+            return limits::depth_exceeded();
+        }
+        Ok(())
+    }
+
+    // A call made as the last thing a function does takes the place of that function's frame, so
+    // tail calls run in constant space, as they do on the VM
+    fn tail_call(&mut self) {
+        if let Some(Frame::Call { .. }) = self.frames.last() {
+            self.pop();
+        }
+    }
+
+    fn apply(
+        &mut self,
+        f: Function,
+        args: List<Exp>,
+        call: Option<Exp>,
+    ) -> Result<Control, String> {
+        match &f {
+            Function::Lambda(lambda) => {
+                self.tail_call();
+                self.check_depth()?;
+                let lambda = lambda.clone();
+                self.push(Frame::Call {
+                    f,
+                    args: args.clone(),
+                    call,
+                });
+                let mut env = lambda.closing_env.extend();
+                let mut remaining = args.iter();
+                for ident in &lambda.params {
+                    let arg = remaining
+                        .next()
+                        .ok_or("Missing required argument".to_owned())?;
+                    env.define(ident, arg)?;
+                }
+                Ok(Control::Eval((*lambda.body).clone(), env))
+            }
+            Function::Compiled(closure) => {
+                self.tail_call();
+                self.check_depth()?;
+                let closure = closure.clone();
+                self.push(Frame::Call {
+                    f,
+                    args: args.clone(),
+                    call,
+                });
+                Ok(Control::Run(vm::start_call(&closure, &args)?, None))
+            }
+            Function::Continuation(continuation) => continuation.resume(&args).map(Control::Return),
+            Function::External(builtin) => {
+                let builtin = *builtin;
+                let is = |other: Builtin| ptr::fn_addr_eq(builtin, other);
+                if is(continuations::call_cc) {
+                    let f = continuations::receiver(&args)?;
+                    let continuation =
+                        Continuation::new(Some(self.frames.clone()), self.active.id(), self.rooted);
+                    let k = Exp::Function(Function::Continuation(Rc::new(continuation)));
+                    Ok(Control::Apply(f, List::new().prepend(k), None))
+                } else if is(evaluation::apply) {
+                    let (f, args) = evaluation::spread(&args)?;
+                    Ok(Control::Apply(f, args, None))
+                } else if is(exceptions::with_exception_handler) {
+                    let (handler, thunk) = exceptions::handler_and_thunk(&args)?;
+                    let outer = exceptions::push_handler(Handler::Exception(handler.clone()));
+                    self.push(Frame::ExceptionHandler { handler, outer });
+                    match thunk {
+                        Exp::Function(thunk) => Ok(Control::Apply(thunk, List::new(), None)),
+                        _ => Err("Type error".to_owned()),
+                    }
+                } else {
+                    let result = {
+                        let _nested = limits::nest(self.depth + 1)?;
+                        conditions::call_builtin(builtin, &args)
+                    };
+                    result.map(Control::Return).inspect_err(|err| {
+                        backtrace::record(err, || {
+                            backtrace::Frame::new(&f, call.as_ref(), args.iter().cloned().collect())
+                        })
+                    })
+                }
+            }
+        }
+    }
+
+    fn run_vm(&mut self, state: Suspended, value: Option<Exp>) -> Result<Control, String> {
+        let step = {
+            let _nested = limits::nest(self.depth)?;
+            vm::run(state, value)?
+        };
+        match step {
+            Step::Done(val) => Ok(Control::Return(val)),
+            Step::Call(state, f, args, call) => {
+                self.push(Frame::Vm(state));
+                Ok(Control::Apply(f, args, call))
+            }
+        }
+    }
+
+    fn ret(&mut self, frame: Frame, val: Exp) -> Result<Control, String> {
+        match frame {
+            Frame::Head { form, env } => self.dispatch(val, form, env),
+            Frame::Args {
+                f,
+                form,
+                rest,
+                mut done,
+                env,
+            } => {
+                done.push(val);
+                self.args(f, form, rest, done, env)
+            }
+            Frame::If {
+                then,
+                otherwise,
+                env,
+            } => {
+                let branch = if val.is_truthy() { then } else { otherwise };
+                Ok(Control::Eval(branch, env))
+            }
+            Frame::Def { name, mut env } => {
+                if let Exp::Function(f) = &val {
+                    f.name_as(&name);
+                }
+                env.define(&name, &val)?;
+                Ok(Control::Return(Exp::Void))
+            }
+            Frame::Set { name, mut env } => {
+                env.assign(&name, &val)?;
+                Ok(Control::Return(Exp::Void))
+            }
+            Frame::Sequence { rest, env } => Ok(self.sequence(rest, env)),
+            Frame::Call { .. } => Ok(Control::Return(val)),
+            Frame::Vm(state) => Ok(Control::Run(state, Some(val))),
+            Frame::Handlers(outer) | Frame::ExceptionHandler { outer, .. } => {
+                exceptions::set_handlers(outer);
+                Ok(Control::Return(val))
+            }
+            Frame::Guard(guard) => {
+                exceptions::set_handlers(guard.outer.clone());
+                Ok(Control::Return(val))
+            }
+            Frame::Clause {
+                exprs,
+                rest,
+                env,
+                obj,
+            } => {
+                if !val.is_truthy() {
+                    return self.clauses(rest, env, obj);
+                }
+                match exprs.head() {
+                    None => Ok(Control::Return(val)),
+                    Some(Exp::Ident(arrow)) if arrow == "=>" => {
+                        let exprs = exprs.tail().unwrap();
+                        let f = exprs.head().ok_or("Type error".to_owned())?.clone();
+                        self.push(Frame::Arrow(val));
+                        Ok(Control::Eval(f, env))
+                    }
+                    Some(_) => Ok(self.sequence(exprs, env)),
+                }
+            }
+            Frame::Arrow(arg) => match val {
+                Exp::Function(f) => Ok(Control::Apply(f, List::new().prepend(arg), None)),
+                _ => Err("Type error".to_owned()),
+            },
+            Frame::HandlerReturned => Err("Exception handler returned".to_owned()),
+            Frame::Restarts(outer) => {
+                conditions::set_restarts(outer);
+                Ok(Control::Return(val))
+            }
+            Frame::Restarted { clauses, env } => {
+                let (index, values) = conditions::restart_value(val)?;
+                let (info, body) = &clauses[index];
+                let mut restart_env = env.extend();
+                let mut values = values.iter();
+                for param in &info.params {
+                    let value = values
+                        .next()
+                        .ok_or("Missing required argument".to_owned())?;
+                    restart_env.define(param, value)?;
+                }
+                Ok(self.sequence(body.clone(), restart_env))
+            }
+        }
+    }
+
+    // Tries a guard's clauses in turn, the way cond would. If none of them match, the object is
+    // raised again.
+    fn clauses(
+        &mut self,
+        clauses: List<Exp>,
+        env: Environment,
+        obj: Exp,
+    ) -> Result<Control, String> {
+        let Some(clause) = clauses.head() else {
+            return Err(exceptions::raise_err(obj));
+        };
+        let Exp::List(clause) = clause else {
+            return Err("Type error".to_owned());
+        };
+        let test = clause.head().ok_or("Type error".to_owned())?.clone();
+        let exprs = clause.tail().unwrap();
+        let rest = clauses.tail().unwrap();
+        let test = match test {
+            Exp::Ident(name) if name == "else" => Exp::Bool(true),
+            test => test,
+        };
+        self.push(Frame::Clause {
+            exprs,
+            rest,
+            env: env.clone(),
+            obj,
+        });
+        Ok(Control::Eval(test, env))
+    }
+
+    // Passes an error down the frames until something catches it
+    fn unwind(&mut self, mut err: String) -> Result<Control, String> {
+        loop {
+            if let Some((value, continuation)) = self.active.catch(&err) {
+                self.frames = continuation.reinstate();
+                self.depth = self.frames.iter().map(Frame::depth).sum();
+                return Ok(Control::Return(value));
+            }
+            let Some(frame) = self.pop() else {
+                return Err(err);
+            };
+            let caught = match frame {
+                Frame::Call { f, args, call } => {
+                    backtrace::record(&err, || {
+                        backtrace::Frame::new(&f, call.as_ref(), args.iter().cloned().collect())
+                    });
+                    Err(err)
+                }
+                Frame::Vm(state) => {
+                    state.record_frames(&err);
+                    Err(err)
+                }
+                Frame::Handlers(outer) => {
+                    exceptions::set_handlers(outer);
+                    Err(err)
+                }
+                Frame::Restarts(outer) => {
+                    conditions::set_restarts(outer);
+                    Err(err)
+                }
+                Frame::Guard(guard) => {
+                    exceptions::set_handlers(guard.outer.clone());
+                    match exceptions::caught(&err) {
+                        Some(obj) => self.guarded(&guard, obj),
+                        None => Err(err),
+                    }
+                }
+                Frame::ExceptionHandler { handler, outer } => {
+                    exceptions::set_handlers(outer);
+                    match (exceptions::caught(&err), handler) {
+                        (Some(obj), Exp::Function(handler)) => {
+                            self.push(Frame::HandlerReturned);
+                            Ok(Control::Apply(handler, List::new().prepend(obj), None))
+                        }
+                        _ => Err(err),
+                    }
+                }
+                _ => Err(err),
+            };
+            match caught {
+                Ok(control) => return Ok(control),
+                Err(next) => err = next,
+            }
+        }
+    }
+
+    fn guarded(&mut self, guard: &Guard, obj: Exp) -> Result<Control, String> {
+        // The clauses run where the guard is, outside of any dynamic-wind in the body
+        continuations::rewind(&guard.winders)?;
+        let mut env = guard.env.extend();
+        env.define(&guard.var, &obj)?;
+        self.clauses(guard.clauses.clone(), env, obj)
+    }
+}
+
+impl Trace for Frame {
+    fn trace(&self, tracer: &mut Tracer) {
+        match self {
+            Frame::Head { form, env } => {
+                form.trace(tracer);
+                env.trace(tracer);
+            }
+            Frame::Args {
+                f, form, done, env, ..
+            } => {
+                f.trace(tracer);
+                form.trace(tracer);
+                done.trace(tracer);
+                env.trace(tracer);
+            }
+            Frame::If {
+                then,
+                otherwise,
+                env,
+            } => {
+                then.trace(tracer);
+                otherwise.trace(tracer);
+                env.trace(tracer);
+            }
+            Frame::Def { env, .. } | Frame::Set { env, .. } | Frame::Restarted { env, .. } => {
+                env.trace(tracer)
+            }
+            Frame::Sequence { rest, env } => {
+                rest.trace(tracer);
+                env.trace(tracer);
+            }
+            Frame::Call { f, args, .. } => {
+                f.trace(tracer);
+                args.trace(tracer);
+            }
+            Frame::Vm(state) => state.trace(tracer),
+            Frame::Guard(guard) => guard.env.trace(tracer),
+            Frame::Clause { env, obj, .. } => {
+                env.trace(tracer);
+                obj.trace(tracer);
+            }
+            Frame::Arrow(val) => val.trace(tracer),
+            Frame::ExceptionHandler { handler, .. } => handler.trace(tracer),
+            Frame::Handlers(_) | Frame::HandlerReturned | Frame::Restarts(_) => (),
+        }
+    }
+}
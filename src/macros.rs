@@ -0,0 +1,758 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
+
+use crate::{
+    environment::Environment,
+    eval,
+    expression::{Exp, Function},
+    gc::{Trace, Tracer},
+    list::{List, WeakList},
+    resolver, special_forms,
+};
+
+pub enum Macro {
+    SyntaxRules(SyntaxRules),
+    // A defmacro transformer, called on the unevaluated argument forms. If rest is set, the
+    // arguments after that many are passed as a single list.
+    Procedural {
+        transformer: Function,
+        rest: Option<usize>,
+    },
+}
+
+impl Macro {
+    // Expands a whole macro use, including the macro keyword itself. The use is in env, inside
+    // functions binding the names is_local accepts that haven't been called yet.
+    pub fn expand(
+        &self,
+        form: &List<Exp>,
+        env: &Environment,
+        is_local: &dyn Fn(&str) -> bool,
+    ) -> Result<Exp, String> {
+        match self {
+            Macro::SyntaxRules(rules) => rules.expand(form, env, is_local),
+            Macro::Procedural { transformer, rest } => {
+                let mut args = form.iter().skip(1).cloned().collect::<Vec<Exp>>();
+                if let Some(fixed) = *rest {
+                    let rest_args = args.split_off(fixed.min(args.len()));
+                    args.push(Exp::List(List::from_vec(rest_args)));
+                }
+                transformer.call(&List::from_vec(args))
+            }
+        }
+    }
+}
+
+impl Trace for Macro {
+    fn trace(&self, tracer: &mut Tracer) {
+        match self {
+            Macro::SyntaxRules(rules) => {
+                for (pattern, template) in &rules.rules {
+                    pattern.trace(tracer);
+                    template.trace(tracer);
+                }
+                rules.env.trace(tracer);
+            }
+            Macro::Procedural { transformer, .. } => transformer.trace(tracer),
+        }
+    }
+}
+
+struct CachedExpansion {
+    // Expansions are dropped once their call site is freed. Until then, its address can't be
+    // reused by another form.
+    form: WeakList<Exp>,
+    macro_: Rc<Macro>,
+    expansion: Exp,
+}
+
+struct Cache {
+    expansions: HashMap<*const (), CachedExpansion>,
+    prune_at: usize,
+}
+
+impl Cache {
+    fn prune(&mut self) {
+        self.expansions.retain(|_, cached| cached.form.is_alive());
+        self.prune_at = (self.expansions.len() * 2).max(1024);
+    }
+}
+
+pub struct SyntaxRules {
+    ellipsis: String,
+    literals: Vec<String>,
+    rules: Vec<(Exp, Exp)>,
+    // Where the macro was defined, which is what identifiers in templates refer to
+    env: Environment,
+}
+
+// What a pattern variable matched. Variables under n ellipses are nested n levels deep.
+#[derive(Clone)]
+enum Binding {
+    One(Exp),
+    Many(Vec<Binding>),
+}
+
+type Bindings = HashMap<String, Binding>;
+
+thread_local! {
+    static EXPANSIONS: Cell<usize> = const { Cell::new(0) };
+    static CACHE: RefCell<Cache> = RefCell::new(Cache { expansions: HashMap::new(), prune_at: 1024 });
+}
+
+// Expands the form, reusing the previous expansion if this call site has been expanded by the
+// same macro before. Call sites are identified by the list itself, so a macro used in the body
+// of a function is only expanded once however many times the function is called.
+pub fn expand_cached(
+    macro_: &Rc<Macro>,
+    form: &List<Exp>,
+    env: &Environment,
+    is_local: &dyn Fn(&str) -> bool,
+) -> Result<Exp, String> {
+    let cached = CACHE.with(|cache| {
+        cache
+            .borrow()
+            .expansions
+            .get(&form.as_ptr())
+            .filter(|cached| Rc::ptr_eq(&cached.macro_, macro_) && cached.form.refers_to(form))
+            .map(|cached| cached.expansion.clone())
+    });
+    if let Some(expansion) = cached {
+        return Ok(expansion);
+    }
+    let expansion = match resolver::unresolve(form) {
+        Some(unresolved) => macro_.expand(&unresolved, env, is_local)?,
+        None => macro_.expand(form, env, is_local)?,
+    };
+    CACHE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        if cache.expansions.len() >= cache.prune_at {
+            cache.prune();
+        }
+        cache.expansions.insert(
+            form.as_ptr(),
+            CachedExpansion {
+                form: form.downgrade(),
+                macro_: macro_.clone(),
+                expansion: expansion.clone(),
+            },
+        )
+    });
+    Ok(expansion)
+}
+
+fn fresh_id() -> usize {
+    EXPANSIONS.with(|count| {
+        count.set(count.get() + 1);
+        count.get()
+    })
+}
+
+fn ident_arg(arg: Option<&Exp>) -> Result<&str, String> {
+    match arg {
+        Some(Exp::Ident(ident)) => Ok(ident),
+        _ => Err("Type error".to_owned()),
+    }
+}
+
+impl SyntaxRules {
+    // Parses (syntax-rules (literal...) (pattern template)...), optionally with a custom
+    // ellipsis identifier before the literals
+    pub fn new(spec: &Exp, env: &Environment) -> Result<Self, String> {
+        let mut parts = match spec {
+            Exp::List(list) => list.iter(),
+            _ => return Err("Expected syntax-rules".to_owned()),
+        };
+        if ident_arg(parts.next()) != Ok("syntax-rules") {
+            return Err("Expected syntax-rules".to_owned());
+        }
+        let mut next = parts.next();
+        let ellipsis = match next {
+            Some(Exp::Ident(ellipsis)) => {
+                next = parts.next();
+                ellipsis.clone()
+            }
+            _ => "...".to_owned(),
+        };
+        let literals = match next {
+            Some(Exp::List(literals)) => literals
+                .iter()
+                .map(|literal| ident_arg(Some(literal)).map(str::to_owned))
+                .collect::<Result<Vec<String>, String>>()?,
+            _ => return Err("Invalid syntax-rules literals".to_owned()),
+        };
+        let rules = parts
+            .map(|rule| match rule {
+                Exp::List(rule) => match rule.iter().collect::<Vec<&Exp>>()[..] {
+                    [pattern @ Exp::List(_), template] => Ok((pattern.clone(), template.clone())),
+                    _ => Err("Invalid syntax rule".to_owned()),
+                },
+                _ => Err("Invalid syntax rule".to_owned()),
+            })
+            .collect::<Result<Vec<(Exp, Exp)>, String>>()?;
+        Ok(SyntaxRules {
+            ellipsis,
+            literals,
+            rules,
+            env: env.clone(),
+        })
+    }
+
+    fn is_ellipsis(&self, exp: &Exp) -> bool {
+        matches!(exp, Exp::Ident(ident) if *ident == self.ellipsis)
+    }
+
+    pub fn expand(
+        &self,
+        form: &List<Exp>,
+        env: &Environment,
+        is_local: &dyn Fn(&str) -> bool,
+    ) -> Result<Exp, String> {
+        let args = form.iter().skip(1).collect::<Vec<&Exp>>();
+        for (pattern, template) in &self.rules {
+            // The keyword position of the pattern is ignored
+            let pattern = match pattern {
+                Exp::List(pattern) => pattern.iter().skip(1).collect::<Vec<&Exp>>(),
+                _ => unreachable!("Patterns are checked to be lists"),
+            };
+            let mut bindings = Bindings::new();
+            if self.match_seq(&pattern, &args, &mut bindings) {
+                let mut renames = HashMap::new();
+                let expansion = self.instantiate(template, &bindings, &mut renames, fresh_id())?;
+                return self.close_free_aliases(expansion, &renames, env, is_local);
+            }
+        }
+        Err("No syntax rule matches".to_owned())
+    }
+
+    fn match_pattern(&self, pattern: &Exp, form: &Exp, bindings: &mut Bindings) -> bool {
+        match pattern {
+            Exp::Ident(ident) if ident == "_" => true,
+            Exp::Ident(ident) if self.literals.contains(ident) => {
+                matches!(form, Exp::Ident(name) if name == ident)
+            }
+            Exp::Ident(ident) => {
+                bindings.insert(ident.clone(), Binding::One(form.clone()));
+                true
+            }
+            Exp::List(patterns) => match form {
+                Exp::List(forms) => self.match_seq(
+                    &patterns.iter().collect::<Vec<&Exp>>(),
+                    &forms.iter().collect::<Vec<&Exp>>(),
+                    bindings,
+                ),
+                _ => false,
+            },
+            _ => pattern == form,
+        }
+    }
+
+    fn match_seq(&self, patterns: &[&Exp], forms: &[&Exp], bindings: &mut Bindings) -> bool {
+        let ellipsis_idx = match patterns
+            .iter()
+            .position(|pattern| self.is_ellipsis(pattern))
+        {
+            Some(0) => return false,
+            Some(idx) => idx,
+            None => {
+                return patterns.len() == forms.len()
+                    && patterns
+                        .iter()
+                        .zip(forms)
+                        .all(|(pattern, form)| self.match_pattern(pattern, form, bindings))
+            }
+        };
+
+        // The pattern before the ellipsis takes whatever the fixed patterns around it don't
+        let before = &patterns[..ellipsis_idx - 1];
+        let repeated = patterns[ellipsis_idx - 1];
+        let after = &patterns[ellipsis_idx + 1..];
+        if forms.len() < before.len() + after.len() {
+            return false;
+        }
+        let repeat_end = forms.len() - after.len();
+        if !self.match_seq(before, &forms[..before.len()], bindings)
+            || !self.match_seq(after, &forms[repeat_end..], bindings)
+        {
+            return false;
+        }
+
+        let mut matches = Vec::new();
+        for form in &forms[before.len()..repeat_end] {
+            let mut repetition = Bindings::new();
+            if !self.match_pattern(repeated, form, &mut repetition) {
+                return false;
+            }
+            matches.push(repetition);
+        }
+        for var in self.pattern_vars(repeated) {
+            let repetitions = matches
+                .iter_mut()
+                .map(|repetition| repetition.remove(&var).unwrap())
+                .collect();
+            bindings.insert(var, Binding::Many(repetitions));
+        }
+        true
+    }
+
+    fn pattern_vars(&self, pattern: &Exp) -> Vec<String> {
+        match pattern {
+            Exp::Ident(ident)
+                if ident != "_" && !self.is_ellipsis(pattern) && !self.literals.contains(ident) =>
+            {
+                vec![ident.clone()]
+            }
+            Exp::List(patterns) => patterns
+                .iter()
+                .flat_map(|pattern| self.pattern_vars(pattern))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    // Fills in the template. Identifiers that didn't come from the pattern are renamed to aliases
+    // unique to this expansion, recorded in renames.
+    fn instantiate(
+        &self,
+        template: &Exp,
+        bindings: &Bindings,
+        renames: &mut HashMap<String, String>,
+        id: usize,
+    ) -> Result<Exp, String> {
+        match template {
+            Exp::Ident(ident) => match bindings.get(ident) {
+                Some(Binding::One(exp)) => Ok(exp.clone()),
+                Some(Binding::Many(_)) => Err(format!("'{}' needs an ellipsis", ident)),
+                None => {
+                    // '#' can't appear in identifiers that were read in, so aliases can't clash
+                    let alias = format!("{}#{}", ident, id);
+                    renames.insert(alias.clone(), ident.clone());
+                    Ok(Exp::Ident(alias))
+                }
+            },
+            Exp::List(templates) => {
+                let templates = templates.iter().collect::<Vec<&Exp>>();
+                // (... template) escapes the ellipsis inside template
+                if let [escape, escaped] = templates[..] {
+                    if self.is_ellipsis(escape) {
+                        return Ok(escaped.clone());
+                    }
+                }
+                let mut elems = Vec::new();
+                let mut idx = 0;
+                while idx < templates.len() {
+                    let depth = templates[idx + 1..]
+                        .iter()
+                        .take_while(|template| self.is_ellipsis(template))
+                        .count();
+                    if depth == 0 {
+                        elems.push(self.instantiate(templates[idx], bindings, renames, id)?);
+                    } else {
+                        elems.extend(self.instantiate_repeated(
+                            templates[idx],
+                            bindings,
+                            renames,
+                            id,
+                            depth,
+                        )?);
+                    }
+                    idx += depth + 1;
+                }
+                Ok(Exp::List(List::from_vec(elems)))
+            }
+            _ => Ok(template.clone()),
+        }
+    }
+
+    fn instantiate_repeated(
+        &self,
+        template: &Exp,
+        bindings: &Bindings,
+        renames: &mut HashMap<String, String>,
+        id: usize,
+        depth: usize,
+    ) -> Result<Vec<Exp>, String> {
+        let repeated = self
+            .pattern_vars(template)
+            .into_iter()
+            .filter_map(|var| match bindings.get(&var) {
+                Some(Binding::Many(repetitions)) => Some((var, repetitions)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let len = match repeated.first() {
+            Some((_, repetitions)) => repetitions.len(),
+            None => return Err("No pattern variable before ellipsis".to_owned()),
+        };
+        if repeated
+            .iter()
+            .any(|(_, repetitions)| repetitions.len() != len)
+        {
+            return Err("Mismatched ellipsis lengths".to_owned());
+        }
+
+        let mut elems = Vec::new();
+        for idx in 0..len {
+            let mut repetition = bindings.clone();
+            for (var, repetitions) in &repeated {
+                repetition.insert(var.clone(), repetitions[idx].clone());
+            }
+            if depth == 1 {
+                elems.push(self.instantiate(template, &repetition, renames, id)?);
+            } else {
+                elems.extend(self.instantiate_repeated(
+                    template,
+                    &repetition,
+                    renames,
+                    id,
+                    depth - 1,
+                )?);
+            }
+        }
+        Ok(elems)
+    }
+}
+
+// The name an identifier in the expansion was written as in the template
+fn original_name<'a>(exp: &'a Exp, renames: &'a HashMap<String, String>) -> Option<&'a str> {
+    match exp {
+        Exp::Ident(ident) => Some(renames.get(ident).unwrap_or(ident)),
+        _ => None,
+    }
+}
+
+// Collects the aliases that the expansion binds itself, as lambda parameters or definitions
+fn bound_aliases(exp: &Exp, renames: &HashMap<String, String>, bound: &mut HashSet<String>) {
+    if let Exp::List(list) = exp {
+        let mut elems = list.iter();
+        match (elems.next(), elems.next()) {
+            (Some(head), Some(Exp::List(params)))
+                if original_name(head, renames) == Some("lambda") =>
+            {
+                for param in params.iter() {
+                    if let Exp::Ident(ident) = param {
+                        if renames.contains_key(ident) {
+                            bound.insert(ident.clone());
+                        }
+                    }
+                }
+            }
+            (Some(head), Some(Exp::Ident(ident)))
+                if original_name(head, renames) == Some("def") && renames.contains_key(ident) =>
+            {
+                bound.insert(ident.clone());
+            }
+            _ => (),
+        }
+        for elem in list.iter() {
+            bound_aliases(elem, renames, bound);
+        }
+    }
+}
+
+// Inside quoted data, aliases that aren't bound by the expansion are given back their names
+fn replace_aliases(
+    exp: &Exp,
+    renames: &HashMap<String, String>,
+    free: &HashMap<&String, &String>,
+    unbound: &HashMap<&String, &String>,
+) -> Exp {
+    match exp {
+        Exp::Ident(ident) => match free.get(ident) {
+            Some(original) => Exp::Ident((*original).clone()),
+            None => exp.clone(),
+        },
+        Exp::List(list) => {
+            let quoted = list
+                .head()
+                .is_some_and(|head| original_name(head, renames) == Some("quote"));
+            Exp::List(List::from_vec(
+                list.iter()
+                    .enumerate()
+                    .map(|(idx, elem)| match idx {
+                        0 => replace_aliases(elem, renames, free, unbound),
+                        _ if quoted => replace_aliases(elem, renames, unbound, unbound),
+                        _ => replace_aliases(elem, renames, free, unbound),
+                    })
+                    .collect(),
+            ))
+        }
+        _ => exp.clone(),
+    }
+}
+
+impl SyntaxRules {
+    // Aliases bound by the expansion keep their fresh names, so they can't capture identifiers
+    // passed in by the user. Every other alias means what its original name does where the macro
+    // was defined. That's usually also what the name means at the use site, so it's given back.
+    // If the use site binds the name to something else, the alias is kept and bound to the
+    // macro's meaning instead.
+    fn close_free_aliases(
+        &self,
+        expansion: Exp,
+        renames: &HashMap<String, String>,
+        env: &Environment,
+        is_local: &dyn Fn(&str) -> bool,
+    ) -> Result<Exp, String> {
+        let mut bound = HashSet::new();
+        bound_aliases(&expansion, renames, &mut bound);
+        let unbound = renames
+            .iter()
+            .filter(|(alias, _)| !bound.contains(*alias))
+            .collect::<HashMap<&String, &String>>();
+        let mut free = HashMap::new();
+        for (alias, original) in &unbound {
+            let binder = self.env.binder(original);
+            let shadowed = is_local(original)
+                || match (&binder, env.binder(original)) {
+                    (Some(binder), Some(used)) => !binder.ptr_eq(&used),
+                    _ => false,
+                };
+            match binder.and_then(|binder| binder.lookup(original)) {
+                Some(val) if shadowed => self.env.clone().define(alias, &val)?,
+                _ => {
+                    free.insert(*alias, *original);
+                }
+            }
+        }
+        Ok(replace_aliases(&expansion, renames, &free, &unbound))
+    }
+}
+
+fn define_macro(
+    env: &mut Environment,
+    name: Option<&Exp>,
+    spec: Option<&Exp>,
+) -> Result<(), String> {
+    let name = ident_arg(name)?;
+    let spec = spec.ok_or("Missing required argument".to_owned())?;
+    let rules = SyntaxRules::new(spec, env)?;
+    env.define(name, &Exp::Macro(Rc::new(Macro::SyntaxRules(rules))))
+}
+
+// (define-syntax name (syntax-rules ...))
+pub fn define_syntax(args: &List<Exp>, env: &mut Environment) -> Result<Exp, String> {
+    let mut args_iter = args.iter();
+    define_macro(env, args_iter.next(), args_iter.next())?;
+    Ok(Exp::Void)
+}
+
+// (let-syntax ((name (syntax-rules ...))...) body...) evaluates body with the macros in scope
+pub fn let_syntax(args: &List<Exp>, env: &mut Environment) -> Result<Exp, String> {
+    let mut args_iter = args.iter();
+    let mut body_env = env.extend();
+    match args_iter.next() {
+        Some(Exp::List(bindings)) => {
+            for binding in bindings.iter() {
+                match binding {
+                    Exp::List(binding) => {
+                        let mut parts = binding.iter();
+                        define_macro(&mut body_env, parts.next(), parts.next())?;
+                    }
+                    _ => return Err("Type error".to_owned()),
+                }
+            }
+        }
+        _ => return Err("Type error".to_owned()),
+    }
+    let mut result = Exp::Void;
+    for exp in args_iter {
+        result = eval(exp, &mut body_env)?;
+    }
+    Ok(result)
+}
+
+// (defmacro name (param... [&rest rest]) body)
+pub fn defmacro(args: &List<Exp>, env: &mut Environment) -> Result<Exp, String> {
+    let name = ident_arg(args.head())?;
+    let rest = args.tail().expect("List with head but no tail");
+    let params = match rest.head() {
+        Some(Exp::List(params)) => params.iter().cloned().collect::<Vec<Exp>>(),
+        _ => return Err("Type error".to_owned()),
+    };
+    let body = rest.tail().ok_or("Type error".to_owned())?;
+
+    let (params, rest) = match params
+        .iter()
+        .position(|param| param == &Exp::Ident("&rest".to_owned()))
+    {
+        Some(idx) if idx + 2 == params.len() => {
+            let mut params = params;
+            params.remove(idx);
+            (params, Some(idx))
+        }
+        Some(_) => return Err("&rest must be followed by exactly one parameter".to_owned()),
+        None => (params, None),
+    };
+    let transformer =
+        match special_forms::lambda(&body.prepend(Exp::List(List::from_vec(params))), env)? {
+            Exp::Function(f) => f,
+            _ => unreachable!("lambda returns a function"),
+        };
+    env.define(
+        name,
+        &Exp::Macro(Rc::new(Macro::Procedural { transformer, rest })),
+    )?;
+    Ok(Exp::Void)
+}
+
+fn macro_for(form: &Exp, env: &Environment) -> Option<(Rc<Macro>, List<Exp>)> {
+    match form {
+        Exp::List(list) => match list.head() {
+            Some(Exp::Ident(ident)) => match env.lookup(ident) {
+                Some(Exp::Macro(macro_)) => Some((macro_, list.clone())),
+                _ => None,
+            },
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn form_and_env(args: &List<Exp>) -> Result<(&Exp, Environment), String> {
+    let mut args_iter = args.iter();
+    let form = args_iter
+        .next()
+        .ok_or("Missing required argument".to_owned())?;
+    let env = match args_iter.next() {
+        Some(Exp::Environment(env)) => env.clone(),
+        Some(_) => return Err("Type error".to_owned()),
+        None => Environment::interaction().ok_or("No interaction environment".to_owned())?,
+    };
+    Ok((form, env))
+}
+
+// Expands the form once if it's a macro use, otherwise returns it unchanged
+pub fn macroexpand_1(args: &List<Exp>) -> Result<Exp, String> {
+    let (form, env) = form_and_env(args)?;
+    match macro_for(form, &env) {
+        Some((macro_, list)) => macro_.expand(&list, &env, &|_| false),
+        None => Ok(form.clone()),
+    }
+}
+
+// Expands the form until it's no longer a macro use. Subforms aren't expanded.
+pub fn macroexpand(args: &List<Exp>) -> Result<Exp, String> {
+    let (form, env) = form_and_env(args)?;
+    let mut form = form.clone();
+    while let Some((macro_, list)) = macro_for(&form, &env) {
+        form = macro_.expand(&list, &env, &|_| false)?;
+    }
+    Ok(form)
+}
+
+// A fresh symbol that can't be equal to any symbol that was read in
+pub fn gensym(args: &List<Exp>) -> Result<Exp, String> {
+    let prefix = match args.head() {
+        Some(Exp::Str(prefix)) => prefix.to_string(),
+        Some(Exp::Ident(prefix)) => prefix.clone(),
+        Some(_) => return Err("Type error".to_owned()),
+        None => "g".to_owned(),
+    };
+    Ok(Exp::Ident(format!("{}#{}", prefix, fresh_id())))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{evaluator::Evaluator, expression::Exp, Interpreter};
+
+    #[test]
+    fn ellipsis_patterns() {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .eval_str("(define-syntax flat (syntax-rules () ((_ (a ...) ...) (list a ... ...))))")
+            .unwrap();
+        let flat = interpreter.eval_str("(flat (1 2) () (3))").unwrap();
+        assert_eq!(flat.to_string(), "(1 2 3)");
+
+        interpreter
+            .eval_str("(define-syntax arrow (syntax-rules (=>) ((_ a => b) b) ((_ a b c) a)))")
+            .unwrap();
+        assert_eq!(interpreter.eval_str("(arrow 1 => 2)"), Ok(Exp::Number(2.0)));
+        assert_eq!(interpreter.eval_str("(arrow 1 2 3)"), Ok(Exp::Number(1.0)));
+    }
+
+    #[test]
+    fn hygiene() {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .eval_str(
+                "(define-syntax my-or (syntax-rules ()
+                   ((_ a b) ((lambda (t) (if t t b)) a))))",
+            )
+            .unwrap();
+        interpreter.eval_str("(def t 5)").unwrap();
+        // The t bound by the expansion must not capture the user's t
+        assert_eq!(interpreter.eval_str("(my-or #f t)"), Ok(Exp::Number(5.0)));
+
+        let scoped = "(let-syntax ((ten (syntax-rules () ((_) 10)))) (ten))";
+        assert_eq!(interpreter.eval_str(scoped), Ok(Exp::Number(10.0)));
+        assert!(interpreter.eval_str("(ten)").is_err());
+
+        // Free identifiers in the template mean what they did where the macro was defined,
+        // even where the use site binds them
+        for evaluator in [Evaluator::Bytecode, Evaluator::TreeWalker] {
+            let mut interpreter = Interpreter::new();
+            interpreter.set_evaluator(evaluator);
+            interpreter
+                .eval_str("(define-syntax my-if (syntax-rules () ((_ a b c) (if a b c))))")
+                .unwrap();
+            interpreter
+                .eval_str("(define-syntax pair (syntax-rules () ((_ a b) (list a b))))")
+                .unwrap();
+            let cases = [
+                ("((lambda (if) (my-if #t 1 2)) 5)", "1"),
+                ("((lambda (list) (pair 1 2)) vector)", "(1 2)"),
+                ("((lambda (list) (list 1 2)) vector)", "#(1 2)"),
+                ("((lambda (x) (pair 'list x)) 3)", "(list 3)"),
+                ("(my-if #f 1 2)", "2"),
+            ];
+            for (form, expected) in cases {
+                let val = interpreter.eval_str(form);
+                assert_eq!(
+                    val.map(|val| val.to_string()),
+                    Ok(expected.to_owned()),
+                    "{}",
+                    form
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn defmacro_expands_once_per_call_site() {
+        let mut interpreter = Interpreter::new();
+        interpreter.eval_str("(def expansions 0)").unwrap();
+        interpreter
+            .eval_str(
+                "(defmacro counted (x) ((lambda (ignored) x) (set! expansions (+ expansions 1))))",
+            )
+            .unwrap();
+        interpreter
+            .eval_str("(def f (lambda (y) (counted y)))")
+            .unwrap();
+        interpreter.eval_str("(f 1)").unwrap();
+        assert_eq!(interpreter.eval_str("(f 2)"), Ok(Exp::Number(2.0)));
+        assert_eq!(interpreter.eval_str("expansions"), Ok(Exp::Number(1.0)));
+
+        interpreter
+            .eval_str("(defmacro quoted (&rest forms) (list 'quote forms))")
+            .unwrap();
+        let expanded = interpreter.eval_str("(macroexpand '(quoted a b))").unwrap();
+        assert_eq!(
+            interpreter.eval_str("(quoted a b)").unwrap().to_string(),
+            "(a b)"
+        );
+        assert!(matches!(expanded, Exp::List(_)));
+
+        // Expansions of call sites that are gone are dropped
+        for n in 0..3000 {
+            interpreter.eval_str(&format!("(counted {})", n)).unwrap();
+        }
+        let cached = super::CACHE.with(|cache| cache.borrow().expansions.len());
+        assert!(cached <= 1024, "{}", cached);
+    }
+}
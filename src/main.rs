@@ -1,82 +1,185 @@
-mod environment;
-mod expression;
-mod lexer;
-mod list;
-mod math;
-mod parser;
-mod special_forms;
+use liasp::{
+    conditions::RestartInfo,
+    evaluator::Evaluator,
+    expression::Exp,
+    limits::{CountingAllocator, Interrupt, Limits},
+    truthiness::Truthiness,
+    Interpreter,
+};
 
-use environment::Environment;
-use expression::Exp;
-use lexer::tokenize;
-use list::List;
-use parser::parse;
-
-use std::error::Error;
+use std::{
+    cell::RefCell,
+    env,
+    error::Error,
+    io::{self, IsTerminal},
+    process,
+    rc::Rc,
+    str::FromStr,
+    sync::OnceLock,
+    thread,
+    time::Duration,
+};
 
 use rustyline::{error::ReadlineError, DefaultEditor};
 
-fn eval(exp: &Exp, env: &mut Environment) -> Result<Exp, String> {
-    if let Exp::List(list) = exp {
-        let first = list.head().ok_or("Error while evaluating".to_owned())?;
-        if let Exp::SpecialForm(special_f) = first {
-            let rest = list.tail().ok_or("Error while evaluating".to_owned())?;
-            special_f(&rest, env)
-        } else {
-            let evaulated_list = list
-                .iter()
-                .map(|exp| eval(exp, env))
-                .collect::<Result<Vec<Exp>, String>>()
-                .map(List::from_vec)?;
-            let evaluated_first = evaulated_list
-                .head()
-                .ok_or("Error while evaluating".to_owned())?;
-            let evaluated_rest = evaulated_list
-                .tail()
-                .ok_or("Error while evaluating".to_owned())?;
-            match evaluated_first {
-                Exp::Function(f) => f.call(&evaluated_rest),
-                _ => Err("Error while evaluating".to_owned()),
+// Counted so the memory limit can see every allocation
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+const USAGE: &str = "Usage: liasp [--truthiness strict|c-like] [--evaluator bytecode|tree-walker] [--backtrace-depth N] [--fuel N] [--max-depth N] [--max-memory BYTES] [--time-limit SECONDS]";
+
+// The REPL runs on a thread with a stack this big, so the default depth limit fits on it
+const STACK_SIZE: usize = 256 * 1024 * 1024;
+const DEFAULT_MAX_DEPTH: usize = 10_000;
+
+fn number_arg<T: FromStr>(
+    args: &mut impl Iterator<Item = String>,
+    flag: &str,
+) -> Result<T, String> {
+    let value = args
+        .next()
+        .ok_or_else(|| format!("Missing value for {}", flag))?;
+    value
+        .parse::<T>()
+        .map_err(|_| format!("Invalid value '{}' for {}", value, flag))
+}
+
+fn parse_args(interpreter: &mut Interpreter) -> Result<(), String> {
+    let mut limits = Limits {
+        depth: Some(DEFAULT_MAX_DEPTH),
+        ..Limits::default()
+    };
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--truthiness" => {
+                let value = args.next().ok_or("Missing value for --truthiness")?;
+                interpreter.set_truthiness(value.parse::<Truthiness>()?);
+            }
+            "--evaluator" => {
+                let value = args.next().ok_or("Missing value for --evaluator")?;
+                interpreter.set_evaluator(value.parse::<Evaluator>()?);
             }
+            "--backtrace-depth" => {
+                interpreter.set_backtrace_depth(number_arg(&mut args, &arg)?);
+            }
+            "--fuel" => limits.fuel = Some(number_arg(&mut args, &arg)?),
+            "--max-depth" => limits.depth = Some(number_arg(&mut args, &arg)?),
+            "--max-memory" => limits.memory = Some(number_arg(&mut args, &arg)?),
+            "--time-limit" => {
+                let seconds = number_arg::<f64>(&mut args, &arg)?;
+                let time = Duration::try_from_secs_f64(seconds)
+                    .map_err(|_| format!("Invalid value '{}' for {}", seconds, arg))?;
+                limits.time = Some(time);
+            }
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            _ => return Err(format!("Unknown argument '{}'\n{}", arg, USAGE)),
+        }
+    }
+    interpreter.set_limits(limits);
+    Ok(())
+}
+
+// Lists the restarts for an error nothing handled, and asks which to invoke and with what
+fn choose_restart(
+    rl: &RefCell<DefaultEditor>,
+    err: &str,
+    restarts: &[RestartInfo],
+) -> Option<(usize, Vec<String>)> {
+    println!("Error: {}", err);
+    println!("Restarts:");
+    for (index, restart) in restarts.iter().enumerate() {
+        match restart.description.as_str() {
+            "" => println!("  {}: [{}]", index, restart.name),
+            description => println!("  {}: [{}] {}", index, restart.name, description),
         }
-    } else if let Exp::Ident(ident) = exp {
-        env.lookup(ident).ok_or("Undefined identifier".to_owned())
-    } else {
-        Ok(exp.clone())
+    }
+    println!("  {}: [abort] Return to the top level", restarts.len());
+    let mut rl = rl.borrow_mut();
+    let choice = rl.readline("restart> ").ok()?;
+    let index = choice.trim().parse::<usize>().ok()?;
+    let restart = restarts.get(index)?;
+    let args = restart
+        .params
+        .iter()
+        .map(|param| rl.readline(&format!("{}> ", param)).ok())
+        .collect::<Option<Vec<_>>>()?;
+    Some((index, args))
+}
+
+// What Ctrl-C interrupts. The editor reads Ctrl-C at the prompt itself, so the signal only
+// arrives while evaluating.
+static INTERRUPT: OnceLock<Interrupt> = OnceLock::new();
+
+extern "C" fn on_interrupt(_signal: libc::c_int) {
+    if let Some(interrupt) = INTERRUPT.get() {
+        interrupt.interrupt();
+    }
+}
+
+fn handle_interrupts(interrupt: Interrupt) {
+    INTERRUPT.get_or_init(|| interrupt);
+    let handler = on_interrupt as extern "C" fn(libc::c_int);
+    unsafe {
+        libc::signal(libc::SIGINT, handler as libc::sighandler_t);
     }
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
-    let mut global_env = environment::build_global_env();
-    // let input = "(+(* 2 3)1)"; // let input = " ( *   (+ -.3  6) 21.7 )  ";
-    // let input = "(lambda (a b) (+ (* 2 a) b))";
-    // println!("Input: {}\n", input);
-    // let tokens = tokenize(input)?;
-    // println!("Token stream: {:?}\n", tokens);
-    // let ast = parse(&tokens)?;
-    // println!("Parse tree: {:?}", ast);
-    // let res = eval(&ast, &mut global_env)?;
-    // println!("Result: {:?}", res);
+    let repl = thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(|| repl().map_err(|err| err.to_string()))?;
+    repl.join().expect("REPL thread panicked")?;
+    Ok(())
+}
 
-    // let lst = list::List::new().prepend(3).prepend(2).prepend(1);
-    // println!("{:?}", lst.head());
-    // let minus1 = lst.tail().unwrap();
-    // println!("{:?}", minus1.head());
-    // let minus2 = minus1.tail().unwrap();
-    // println!("{:?}", minus2.head());
+fn repl() -> Result<(), Box<dyn Error>> {
+    let mut interpreter = Interpreter::new();
+    if let Err(err) = parse_args(&mut interpreter) {
+        eprintln!("{}", err);
+        process::exit(2);
+    }
 
-    let mut rl = DefaultEditor::new()?;
+    let rl = Rc::new(RefCell::new(DefaultEditor::new()?));
+    // The error last shown along with its restarts, so it isn't shown again once aborted
+    let shown = Rc::new(RefCell::new(None));
+    // Piped input has nobody to answer
+    if io::stdin().is_terminal() {
+        let rl = rl.clone();
+        let shown = shown.clone();
+        interpreter.set_debugger(move |err, restarts| {
+            *shown.borrow_mut() = Some(err.to_owned());
+            choose_restart(&rl, err, restarts)
+        });
+        // Without a terminal Ctrl-C still ends the process
+        handle_interrupts(interpreter.interrupt_handle());
+    }
     loop {
-        let input = rl.readline("> ");
+        let input = rl.borrow_mut().readline("> ");
         match input {
             Ok(line) => {
-                rl.add_history_entry(&line)?;
-                let output = tokenize(&line)
-                    .and_then(|tokens| parse(&tokens))
-                    .and_then(|exp| eval(&exp, &mut global_env));
-                match output {
+                rl.borrow_mut().add_history_entry(&line)?;
+                shown.borrow_mut().take();
+                match interpreter.eval_str(&line) {
+                    Ok(Exp::Void) => (),
                     Ok(val) => println!("{}", val),
-                    Err(err) => println!("Error: {}", err),
+                    Err(err) => {
+                        if shown.borrow_mut().take() != Some(err.clone()) {
+                            println!("Error: {}", err);
+                        }
+                        // A builtin failing right in the form typed has nothing more to show
+                        if let Some(backtrace) = interpreter.backtrace() {
+                            let frames = backtrace.frames();
+                            if !matches!(frames, [frame] if frame.is_builtin())
+                                && !frames.is_empty()
+                            {
+                                print!("{}", backtrace);
+                            }
+                        }
+                    }
                 }
             }
             Err(ReadlineError::Interrupted) => {
@@ -50,3 +50,10 @@ pub fn equals(args: &List<Exp>) -> Result<Exp, String> {
     }
     Ok(Exp::Bool(true))
 }
+
+pub fn integer_arg(arg: &Exp) -> Result<i64, String> {
+    match arg {
+        Exp::Number(val) if val.fract() == 0.0 => Ok(*val as i64),
+        _ => Err("Type error".to_owned()),
+    }
+}
@@ -0,0 +1,130 @@
+use crate::{environment::Environment, expression::Exp, list::List, loader::Loader, vm};
+
+fn ident_arg(arg: Option<&Exp>) -> Result<&str, String> {
+    match arg {
+        Some(Exp::Ident(ident)) => Ok(ident),
+        _ => Err("Type error".to_owned()),
+    }
+}
+
+fn ident_list<'a>(args: impl Iterator<Item = &'a Exp>) -> Result<Vec<String>, String> {
+    args.map(|arg| ident_arg(Some(arg)).map(str::to_owned))
+        .collect()
+}
+
+// (module name body...) evaluates body in its own environment and caches what it exports
+pub fn module(args: &List<Exp>, env: &mut Environment) -> Result<Exp, String> {
+    let name = ident_arg(args.head())?;
+    let body = args.tail().expect("List with head but no tail");
+    let loader = Loader::current();
+    let exports = loader.eval_module(env, |module_env| {
+        for exp in body.iter() {
+            vm::eval_toplevel(exp, module_env)?;
+        }
+        Ok(())
+    })?;
+    loader.define_module(name, exports);
+    Ok(Exp::Void)
+}
+
+pub fn export(args: &List<Exp>, _env: &mut Environment) -> Result<Exp, String> {
+    Loader::current().export(ident_list(args.iter())?)?;
+    Ok(Exp::Void)
+}
+
+fn exported<'a>(exports: &'a [(String, Exp)], name: &str) -> Result<&'a Exp, String> {
+    exports
+        .iter()
+        .find(|(ident, _)| ident == name)
+        .map(|(_, val)| val)
+        .ok_or(format!("'{}' is not exported", name))
+}
+
+// An import set is a module name, or one of (only set name...), (except set name...),
+// (prefix set prefix) and (rename set (from to)...) wrapped around another import set
+fn import_set(spec: &Exp) -> Result<Vec<(String, Exp)>, String> {
+    let list = match spec {
+        Exp::Ident(name) => return Ok(Loader::current().import(name)?.to_vec()),
+        Exp::List(list) => list,
+        _ => return Err("Invalid import set".to_owned()),
+    };
+    let mut parts = list.iter();
+    let kind = ident_arg(parts.next())?;
+    let inner = import_set(parts.next().ok_or("Invalid import set".to_owned())?)?;
+    match kind {
+        "only" => ident_list(parts)?
+            .into_iter()
+            .map(|name| exported(&inner, &name).map(|val| (name.clone(), val.clone())))
+            .collect(),
+        "except" => {
+            let excluded = ident_list(parts)?;
+            for name in &excluded {
+                exported(&inner, name)?;
+            }
+            Ok(inner
+                .into_iter()
+                .filter(|(name, _)| !excluded.contains(name))
+                .collect())
+        }
+        "prefix" => {
+            let prefix = ident_arg(parts.next())?;
+            Ok(inner
+                .into_iter()
+                .map(|(name, val)| (format!("{}{}", prefix, name), val))
+                .collect())
+        }
+        "rename" => {
+            let mut renamed = inner;
+            for pair in parts {
+                let pair = match pair {
+                    Exp::List(pair) => ident_list(pair.iter())?,
+                    _ => return Err("Invalid import set".to_owned()),
+                };
+                let [from, to] =
+                    <[String; 2]>::try_from(pair).map_err(|_| "Invalid import set".to_owned())?;
+                exported(&renamed, &from)?;
+                for (name, _) in renamed.iter_mut().filter(|(name, _)| *name == from) {
+                    *name = to.clone();
+                }
+            }
+            Ok(renamed)
+        }
+        _ => Err("Invalid import set".to_owned()),
+    }
+}
+
+pub fn import(args: &List<Exp>, env: &mut Environment) -> Result<Exp, String> {
+    for spec in args.iter() {
+        for (name, val) in import_set(spec)? {
+            // Importing again, e.g. at the REPL, replaces the earlier binding
+            if env.define(&name, &val).is_err() {
+                env.assign(&name, &val)?;
+            }
+        }
+    }
+    Ok(Exp::Void)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{expression::Exp, Interpreter};
+
+    #[test]
+    fn inline_modules() {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .eval_str("(module shapes (export area) (def pi 3) (def area (lambda (r) (* pi r r))))")
+            .unwrap();
+        interpreter
+            .eval_str("(import (prefix (only shapes area) s:))")
+            .unwrap();
+        assert_eq!(interpreter.eval_str("(s:area 2)"), Ok(Exp::Number(12.0)));
+        assert!(interpreter.eval_str("pi").is_err());
+        assert!(interpreter.eval_str("(import (only shapes pi))").is_err());
+
+        interpreter
+            .eval_str("(import (rename shapes (area size)))")
+            .unwrap();
+        assert_eq!(interpreter.eval_str("(size 1)"), Ok(Exp::Number(3.0)));
+    }
+}
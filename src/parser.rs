@@ -1,50 +1,167 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use itertools::Itertools;
+
 use crate::expression::Exp;
-use crate::lexer::Token;
+use crate::hamt::Hamt;
+use crate::lexer::{Position, Token};
 use crate::list::List;
-use crate::special_forms;
+use crate::locations::{self, Location};
+
+// Where the tokens being parsed were read from, so the lists they make can be given locations
+struct Source<'a> {
+    file: &'a Rc<str>,
+    positions: &'a [Position],
+}
+
+fn is_opener(token: &Token) -> bool {
+    matches!(
+        token,
+        Token::OpenParen
+            | Token::VectorOpen
+            | Token::BytevectorOpen
+            | Token::MapOpen
+            | Token::SetOpen
+    )
+}
+
+fn closer_for(opener: &Token) -> Token {
+    match opener {
+        Token::MapOpen | Token::SetOpen => Token::CloseBrace,
+        _ => Token::CloseParen,
+    }
+}
+
+// Finds the index of the last token of the expression starting at idx
+fn expression_end(tokens: &[Token], idx: usize) -> Result<usize, String> {
+    match tokens
+        .get(idx)
+        .ok_or("Parse error: unexpected end of file".to_owned())?
+    {
+        Token::QuoteMark => expression_end(tokens, idx + 1),
+        token if is_opener(token) => {
+            let mut end = idx;
+            let mut depth = 1;
+            while depth != 0 {
+                end += 1;
+                depth += match tokens
+                    .get(end)
+                    .ok_or("Parse error: unexpected end of file".to_owned())?
+                {
+                    token if is_opener(token) => 1,
+                    Token::CloseParen | Token::CloseBrace => -1,
+                    _ => 0,
+                }
+            }
+            Ok(end)
+        }
+        _ => Ok(idx),
+    }
+}
+
+// Parses the elements between an opening token and its matching closing token. at is the index
+// of the opening token among all the tokens being parsed.
+fn parse_seq(tokens: &[Token], at: usize, source: Option<&Source>) -> Result<Vec<Exp>, String> {
+    let mut idx = 1;
+    let mut elems = Vec::<Exp>::new();
+    while idx < tokens.len() - 1 {
+        let end = expression_end(tokens, idx)?;
+        elems.push(parse_at(&tokens[idx..end + 1], at + idx, source)?);
+        idx = end + 1;
+    }
+    match tokens.last() {
+        Some(token) if *token == closer_for(&tokens[0]) => Ok(elems),
+        _ => Err("Parse error: expected closing parenthesis".to_owned()),
+    }
+}
+
+fn parse_bytevector(tokens: &[Token]) -> Result<Exp, String> {
+    let bytes = parse_seq(tokens, 0, None)?
+        .iter()
+        .map(|elem| match elem {
+            Exp::Number(val) if val.fract() == 0.0 && (0.0..=255.0).contains(val) => Ok(*val as u8),
+            _ => Err("Parse error: bytevector elements must be bytes".to_owned()),
+        })
+        .collect::<Result<Vec<u8>, String>>()?;
+    Ok(Exp::Bytevector(Rc::new(RefCell::new(bytes))))
+}
+
+fn hashable_key(exp: Exp) -> Result<Exp, String> {
+    if exp.is_hashable() {
+        Ok(exp)
+    } else {
+        Err("Parse error: unhashable key".to_owned())
+    }
+}
+
+fn parse_map(tokens: &[Token], at: usize, source: Option<&Source>) -> Result<Exp, String> {
+    let mut map = Hamt::new();
+    for mut pair in parse_seq(tokens, at, source)?
+        .into_iter()
+        .chunks(2)
+        .into_iter()
+    {
+        let key = hashable_key(pair.next().unwrap())?;
+        let val = pair
+            .next()
+            .ok_or("Parse error: map literal is missing a value".to_owned())?;
+        map = map.insert(key, val);
+    }
+    Ok(Exp::Map(map))
+}
+
+fn parse_set(tokens: &[Token], at: usize, source: Option<&Source>) -> Result<Exp, String> {
+    let mut set = Hamt::new();
+    for elem in parse_seq(tokens, at, source)? {
+        set = set.insert(hashable_key(elem)?, ());
+    }
+    Ok(Exp::Set(set))
+}
 
 pub fn parse(tokens: &[Token]) -> Result<Exp, String> {
+    parse_at(tokens, 0, None)
+}
+
+// Like parse, but remembers where in the file each list starts, given where each token does
+pub fn parse_from(tokens: &[Token], positions: &[Position], file: &Rc<str>) -> Result<Exp, String> {
+    parse_at(tokens, 0, Some(&Source { file, positions }))
+}
+
+fn parse_at(tokens: &[Token], at: usize, source: Option<&Source>) -> Result<Exp, String> {
     let first = tokens.first().ok_or("Parse error: no tokens".to_owned())?;
     match first {
         Token::Number(n) => Ok(Exp::Number(*n)),
         Token::Bool(b) => Ok(Exp::Bool(*b)),
+        Token::Char(c) => Ok(Exp::Char(*c)),
+        Token::Str(s) => Ok(Exp::Str(s.as_str().into())),
         // TODO: refcount the strings instead of cloning
         Token::Ident(s) => Ok(Exp::Ident(s.clone())),
-        Token::If => Ok(Exp::SpecialForm(special_forms::if_exp)),
-        Token::Def => Ok(Exp::SpecialForm(special_forms::def)),
-        Token::Lambda => Ok(Exp::SpecialForm(special_forms::lambda)),
+        // 'x is shorthand for (quote x)
+        Token::QuoteMark => {
+            let quoted = parse_at(&tokens[1..], at + 1, source)?;
+            Ok(Exp::List(List::from_vec(vec![
+                Exp::Ident("quote".to_owned()),
+                quoted,
+            ])))
+        }
         Token::OpenParen => {
-            let mut idx = 1;
-            let mut list_vec = Vec::<Exp>::new();
-            while idx < tokens.len() - 1 {
-                let subslice = if tokens[idx] == Token::OpenParen {
-                    let begin = idx;
-                    let mut depth = 1;
-                    while depth != 0 {
-                        idx += 1;
-                        depth += match tokens
-                            .get(idx)
-                            .ok_or("Parse error: unexpected end of file".to_owned())?
-                        {
-                            Token::OpenParen => 1,
-                            Token::CloseParen => -1,
-                            _ => 0,
-                        }
-                    }
-                    &tokens[begin..idx + 1]
-                } else {
-                    &tokens[idx..idx + 1]
+            let list = List::from_vec(parse_seq(tokens, at, source)?);
+            if let Some(source) = source {
+                let location = Location {
+                    file: source.file.clone(),
+                    position: source.positions[at],
                 };
-                list_vec.push(parse(subslice)?);
-                idx += 1;
-            }
-            match tokens.last() {
-                Some(token) if *token == Token::CloseParen => {
-                    Ok(Exp::List(List::from_vec(list_vec)))
-                }
-                _ => Err("Parse error: expected closing parenthesis".to_owned()),
+                locations::record(&list, location);
             }
+            Ok(Exp::List(list))
+        }
+        Token::VectorOpen => {
+            parse_seq(tokens, at, source).map(|elems| Exp::Vector(Rc::new(RefCell::new(elems))))
         }
+        Token::BytevectorOpen => parse_bytevector(tokens),
+        Token::MapOpen => parse_map(tokens, at, source),
+        Token::SetOpen => parse_set(tokens, at, source),
         _ => Err("Parse error: unexpected token".to_owned()),
     }
 }
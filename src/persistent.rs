@@ -0,0 +1,232 @@
+use crate::{expression::Exp, hamt::Hamt, list::List};
+
+fn key_arg(arg: Option<&Exp>) -> Result<&Exp, String> {
+    match arg {
+        Some(key) if key.is_hashable() => Ok(key),
+        Some(_) => Err("Unhashable key".to_owned()),
+        None => Err("Missing required argument".to_owned()),
+    }
+}
+
+fn set_args(args: &List<Exp>) -> Result<Vec<&Hamt<Exp, ()>>, String> {
+    let sets = args
+        .iter()
+        .map(|arg| match arg {
+            Exp::Set(set) => Ok(set),
+            _ => Err("Type error".to_owned()),
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    if sets.is_empty() {
+        Err("Missing required argument".to_owned())
+    } else {
+        Ok(sets)
+    }
+}
+
+fn entry_pair(entry: &Exp) -> Result<(Exp, Exp), String> {
+    let elems: Vec<Exp> = match entry {
+        Exp::List(list) => list.iter().cloned().collect(),
+        Exp::Vector(vec) => vec.borrow().clone(),
+        _ => return Err("Type error".to_owned()),
+    };
+    match <[Exp; 2]>::try_from(elems) {
+        Ok([key, val]) => Ok((key_arg(Some(&key))?.clone(), val)),
+        Err(_) => Err("Map entries must have exactly two elements".to_owned()),
+    }
+}
+
+pub fn assoc(args: &List<Exp>) -> Result<Exp, String> {
+    let mut map = match args.head() {
+        Some(Exp::Map(map)) => map.clone(),
+        _ => return Err("Type error".to_owned()),
+    };
+    let mut args_iter = args.iter().skip(1);
+    while let Some(key) = args_iter.next() {
+        let key = key_arg(Some(key))?;
+        let val = args_iter.next().ok_or("Missing value for key".to_owned())?;
+        map = map.insert(key.clone(), val.clone());
+    }
+    Ok(Exp::Map(map))
+}
+
+pub fn dissoc(args: &List<Exp>) -> Result<Exp, String> {
+    let mut map = match args.head() {
+        Some(Exp::Map(map)) => map.clone(),
+        _ => return Err("Type error".to_owned()),
+    };
+    for key in args.iter().skip(1) {
+        map = map.remove(key_arg(Some(key))?);
+    }
+    Ok(Exp::Map(map))
+}
+
+pub fn get(args: &List<Exp>) -> Result<Exp, String> {
+    let mut args_iter = args.iter();
+    let coll = args_iter.next();
+    let key = key_arg(args_iter.next())?;
+    let found = match coll {
+        Some(Exp::Map(map)) => map.get(key),
+        // Looking an element up in a set gives back the element itself
+        Some(Exp::Set(set)) => set.get(key).map(|_| key),
+        _ => return Err("Type error".to_owned()),
+    };
+    found
+        .or(args_iter.next())
+        .cloned()
+        .ok_or("Key not found".to_owned())
+}
+
+pub fn contains(args: &List<Exp>) -> Result<Exp, String> {
+    let mut args_iter = args.iter();
+    let coll = args_iter.next();
+    let key = key_arg(args_iter.next())?;
+    match coll {
+        Some(Exp::Map(map)) => Ok(Exp::Bool(map.contains_key(key))),
+        Some(Exp::Set(set)) => Ok(Exp::Bool(set.contains_key(key))),
+        _ => Err("Type error".to_owned()),
+    }
+}
+
+// Adds elements to a set, or (key value) entries to a map
+pub fn conj(args: &List<Exp>) -> Result<Exp, String> {
+    let rest = args.iter().skip(1);
+    match args.head() {
+        Some(Exp::Set(set)) => {
+            let mut set = set.clone();
+            for elem in rest {
+                set = set.insert(key_arg(Some(elem))?.clone(), ());
+            }
+            Ok(Exp::Set(set))
+        }
+        Some(Exp::Map(map)) => {
+            let mut map = map.clone();
+            for entry in rest {
+                let (key, val) = entry_pair(entry)?;
+                map = map.insert(key, val);
+            }
+            Ok(Exp::Map(map))
+        }
+        _ => Err("Type error".to_owned()),
+    }
+}
+
+pub fn union(args: &List<Exp>) -> Result<Exp, String> {
+    let sets = set_args(args)?;
+    // Start from the largest set to share as much of its structure as possible
+    let (largest_idx, _) = sets
+        .iter()
+        .enumerate()
+        .max_by_key(|(_, set)| set.len())
+        .unwrap();
+    let mut result = sets[largest_idx].clone();
+    for (idx, set) in sets.iter().enumerate() {
+        if idx != largest_idx {
+            for (elem, _) in set.iter() {
+                result = result.insert(elem.clone(), ());
+            }
+        }
+    }
+    Ok(Exp::Set(result))
+}
+
+pub fn intersection(args: &List<Exp>) -> Result<Exp, String> {
+    let sets = set_args(args)?;
+    let mut result = sets[0].clone();
+    for (elem, _) in sets[0].iter() {
+        if !sets[1..].iter().all(|set| set.contains_key(elem)) {
+            result = result.remove(elem);
+        }
+    }
+    Ok(Exp::Set(result))
+}
+
+pub fn difference(args: &List<Exp>) -> Result<Exp, String> {
+    let sets = set_args(args)?;
+    let mut result = sets[0].clone();
+    for set in &sets[1..] {
+        for (elem, _) in set.iter() {
+            result = result.remove(elem);
+        }
+    }
+    Ok(Exp::Set(result))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::Interpreter;
+
+    fn check(cases: &[(&str, Result<&str, &str>)]) {
+        let mut interpreter = Interpreter::new();
+        for (form, expected) in cases {
+            let result = interpreter
+                .eval_str(form)
+                .map(|val| val.display_form().to_string());
+            let expected = expected.map(str::to_owned).map_err(str::to_owned);
+            assert_eq!(result, expected, "{}", form);
+        }
+    }
+
+    #[test]
+    fn maps() {
+        check(&[
+            ("(def m {a 1 b 2})", Ok("#void#")),
+            ("(def m2 (assoc m 'c 3 'a 10))", Ok("#void#")),
+            // The original is left as it was
+            ("(list (get m 'a) (contains? m 'c))", Ok("(1 #f)")),
+            ("(list (get m2 'a) (get m2 'c))", Ok("(10 3)")),
+            ("(get m 'z)", Err("Key not found")),
+            ("(get m 'z 0)", Ok("0")),
+            ("(get m #(1))", Err("Unhashable key")),
+            ("(get '((a 1)) 'a)", Err("Type error")),
+            ("(equal? (dissoc m2 'c 'z) {b 2 a 10})", Ok("#t")),
+            ("(contains? (dissoc m 'a) 'a)", Ok("#f")),
+            ("(dissoc '((a 1)) 'a)", Err("Type error")),
+            ("(equal? (conj m '(c 3)) {a 1 b 2 c 3})", Ok("#t")),
+            (
+                "(conj m '(c))",
+                Err("Map entries must have exactly two elements"),
+            ),
+            ("(assoc {a 1} #(1) 2)", Err("Unhashable key")),
+            // Maps are equal by contents, whatever order they were built in
+            ("(equal? {a 1 b 2} (assoc {b 2} 'a 1))", Ok("#t")),
+            ("(equal? {a 1} {a 2})", Ok("#f")),
+            ("(equal? {a 1} {a 1 b 2})", Ok("#f")),
+            ("(eqv? m m)", Ok("#t")),
+            ("(get {(1 2) x} (list 1 2))", Ok("x")),
+        ]);
+    }
+
+    #[test]
+    fn sets() {
+        check(&[
+            ("(def s #{1 2 3})", Ok("#void#")),
+            ("(list (contains? s 2) (contains? s 4))", Ok("(#t #f)")),
+            ("(get s 2)", Ok("2")),
+            ("(get s 4)", Err("Key not found")),
+            ("(equal? (conj s 3 4) #{1 2 3 4})", Ok("#t")),
+            ("(contains? s 4)", Ok("#f")),
+            ("(equal? (union s #{3 4} #{5}) #{1 2 3 4 5})", Ok("#t")),
+            ("(equal? (intersection s #{2 3 4} #{3 2}) #{2 3})", Ok("#t")),
+            ("(equal? (difference s #{1} #{3}) #{2})", Ok("#t")),
+            ("(equal? (intersection s #{4}) #{})", Ok("#t")),
+            ("(union s '(1 2))", Err("Type error")),
+            ("(equal? #{1 2} #{2 1})", Ok("#t")),
+            ("(equal? #{1 2} #{1 2 3})", Ok("#f")),
+            ("(equal? #{1} {1 1})", Ok("#f")),
+            // Sets can be elements of sets
+            ("(contains? #{#{1 2}} #{2 1})", Ok("#t")),
+        ]);
+    }
+
+    #[test]
+    fn assoc_on_maps_and_lists() {
+        check(&[
+            ("(assoc {a 1} 'b 2)", Ok("{a 1 b 2}")),
+            ("(assoc {a 1})", Ok("{a 1}")),
+            ("(assoc {a 1} 'b)", Err("Missing value for key")),
+            ("(assoc 'b '((a 1) (b 2)))", Ok("(b 2)")),
+            ("(assoc 'c '((a 1) (b 2)))", Ok("#f")),
+            ("(assoc 'b '((a 1)) 'extra)", Ok("#f")),
+        ]);
+    }
+}
@@ -0,0 +1,324 @@
+use std::{
+    cell::RefCell,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Cursor, Write},
+    rc::Rc,
+};
+
+pub struct Port {
+    state: RefCell<PortState>,
+}
+
+// File ports are wrapped in BufReader/BufWriter. Dropping the state (on close-port or when
+// the last reference to the port goes away) flushes any buffered output and closes the file.
+enum PortState {
+    TextInput {
+        reader: Box<dyn BufRead>,
+        peeked: Option<char>,
+    },
+    // Locks std's stdin for each read rather than buffering separately, so that input is shared
+    // fairly with anything else reading stdin (such as the REPL's line editor)
+    Stdin {
+        peeked: Option<char>,
+    },
+    BinaryInput(Box<dyn BufRead>),
+    TextOutput(Box<dyn Write>),
+    StringOutput(String),
+    BinaryOutput(Box<dyn Write>),
+    BytevectorOutput(Vec<u8>),
+    Closed,
+}
+
+thread_local! {
+    static CURRENT_INPUT: RefCell<Rc<Port>> = RefCell::new(Rc::new(Port::stdin()));
+    static CURRENT_OUTPUT: RefCell<Rc<Port>> = RefCell::new(Rc::new(Port::stdout()));
+}
+
+fn io_error(err: io::Error) -> String {
+    format!("I/O error: {}", err)
+}
+
+fn file_error(path: &str, err: io::Error) -> String {
+    format!("Could not open file '{}': {}", path, err)
+}
+
+// Decodes a single UTF-8 character, or returns None at the end of input
+fn decode_char(reader: &mut dyn BufRead) -> Result<Option<char>, String> {
+    let first = match reader.fill_buf().map_err(io_error)? {
+        [] => return Ok(None),
+        buf => buf[0],
+    };
+    let len = match first {
+        0x00..=0x7f => 1,
+        0xc0..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf7 => 4,
+        _ => return Err("Invalid UTF-8 in input".to_owned()),
+    };
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes[..len]).map_err(io_error)?;
+    std::str::from_utf8(&bytes[..len])
+        .map(|s| s.chars().next())
+        .map_err(|_| "Invalid UTF-8 in input".to_owned())
+}
+
+impl Port {
+    fn from_state(state: PortState) -> Self {
+        Port {
+            state: RefCell::new(state),
+        }
+    }
+
+    pub fn input(reader: impl BufRead + 'static) -> Self {
+        Self::from_state(PortState::TextInput {
+            reader: Box::new(reader),
+            peeked: None,
+        })
+    }
+
+    pub fn output(writer: impl Write + 'static) -> Self {
+        Self::from_state(PortState::TextOutput(Box::new(writer)))
+    }
+
+    pub fn binary_input(reader: impl BufRead + 'static) -> Self {
+        Self::from_state(PortState::BinaryInput(Box::new(reader)))
+    }
+
+    pub fn binary_output(writer: impl Write + 'static) -> Self {
+        Self::from_state(PortState::BinaryOutput(Box::new(writer)))
+    }
+
+    pub fn string_input(text: &str) -> Self {
+        Self::input(Cursor::new(text.as_bytes().to_vec()))
+    }
+
+    // An output port that collects everything written to it, see output_string
+    pub fn string_output() -> Self {
+        Self::from_state(PortState::StringOutput(String::new()))
+    }
+
+    pub fn bytevector_input(bytes: Vec<u8>) -> Self {
+        Self::binary_input(Cursor::new(bytes))
+    }
+
+    // A binary output port that collects everything written to it, see output_bytes
+    pub fn bytevector_output() -> Self {
+        Self::from_state(PortState::BytevectorOutput(Vec::new()))
+    }
+
+    pub fn open_input_file(path: &str, binary: bool) -> Result<Self, String> {
+        let reader = BufReader::new(File::open(path).map_err(|err| file_error(path, err))?);
+        Ok(if binary {
+            Self::binary_input(reader)
+        } else {
+            Self::input(reader)
+        })
+    }
+
+    pub fn open_output_file(path: &str, binary: bool) -> Result<Self, String> {
+        let writer = BufWriter::new(File::create(path).map_err(|err| file_error(path, err))?);
+        Ok(if binary {
+            Self::binary_output(writer)
+        } else {
+            Self::output(writer)
+        })
+    }
+
+    pub fn stdin() -> Self {
+        Self::from_state(PortState::Stdin { peeked: None })
+    }
+
+    pub fn stdout() -> Self {
+        Self::output(io::stdout())
+    }
+
+    pub fn current_input() -> Rc<Port> {
+        CURRENT_INPUT.with(|port| port.borrow().clone())
+    }
+
+    pub fn current_output() -> Rc<Port> {
+        CURRENT_OUTPUT.with(|port| port.borrow().clone())
+    }
+
+    pub fn set_current_input(port: Rc<Port>) {
+        CURRENT_INPUT.with(|current| *current.borrow_mut() = port)
+    }
+
+    // Returns the port that was current before
+    pub fn set_current_output(port: Rc<Port>) -> Rc<Port> {
+        CURRENT_OUTPUT.with(|current| std::mem::replace(&mut *current.borrow_mut(), port))
+    }
+
+    pub fn is_input(&self) -> bool {
+        matches!(
+            &*self.state.borrow(),
+            PortState::TextInput { .. } | PortState::Stdin { .. } | PortState::BinaryInput(_)
+        )
+    }
+
+    pub fn is_output(&self) -> bool {
+        matches!(
+            &*self.state.borrow(),
+            PortState::TextOutput(_)
+                | PortState::StringOutput(_)
+                | PortState::BinaryOutput(_)
+                | PortState::BytevectorOutput(_)
+        )
+    }
+
+    pub fn output_string(&self) -> Option<String> {
+        match &*self.state.borrow() {
+            PortState::StringOutput(text) => Some(text.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn output_bytes(&self) -> Option<Vec<u8>> {
+        match &*self.state.borrow() {
+            PortState::BytevectorOutput(bytes) => Some(bytes.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn write_str(&self, text: &str) -> Result<(), String> {
+        match &mut *self.state.borrow_mut() {
+            PortState::TextOutput(writer) => writer.write_all(text.as_bytes()).map_err(io_error),
+            PortState::StringOutput(buf) => {
+                buf.push_str(text);
+                Ok(())
+            }
+            PortState::Closed => Err("Port is closed".to_owned()),
+            _ => Err("Not a textual output port".to_owned()),
+        }
+    }
+
+    pub fn write_u8(&self, byte: u8) -> Result<(), String> {
+        match &mut *self.state.borrow_mut() {
+            PortState::BinaryOutput(writer) => writer.write_all(&[byte]).map_err(io_error),
+            PortState::BytevectorOutput(bytes) => {
+                bytes.push(byte);
+                Ok(())
+            }
+            PortState::Closed => Err("Port is closed".to_owned()),
+            _ => Err("Not a binary output port".to_owned()),
+        }
+    }
+
+    pub fn flush(&self) -> Result<(), String> {
+        match &mut *self.state.borrow_mut() {
+            PortState::TextOutput(writer) | PortState::BinaryOutput(writer) => {
+                writer.flush().map_err(io_error)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Flushes anything buffered and releases the underlying file. Closing twice is harmless.
+    pub fn close(&self) -> Result<(), String> {
+        let flushed = self.flush();
+        *self.state.borrow_mut() = PortState::Closed;
+        flushed
+    }
+
+    fn with_reader<T>(
+        &self,
+        f: impl FnOnce(&mut dyn BufRead, &mut Option<char>) -> Result<T, String>,
+    ) -> Result<T, String> {
+        match &mut *self.state.borrow_mut() {
+            PortState::TextInput { reader, peeked } => f(reader.as_mut(), peeked),
+            PortState::Stdin { peeked } => f(&mut io::stdin().lock(), peeked),
+            PortState::Closed => Err("Port is closed".to_owned()),
+            _ => Err("Not a textual input port".to_owned()),
+        }
+    }
+
+    pub fn read_char(&self) -> Result<Option<char>, String> {
+        self.with_reader(|reader, peeked| match peeked.take() {
+            Some(c) => Ok(Some(c)),
+            None => decode_char(reader),
+        })
+    }
+
+    pub fn peek_char(&self) -> Result<Option<char>, String> {
+        self.with_reader(|reader, peeked| {
+            if peeked.is_none() {
+                *peeked = decode_char(reader)?;
+            }
+            Ok(*peeked)
+        })
+    }
+
+    // Reads up to the next newline, which is consumed but not included
+    pub fn read_line(&self) -> Result<Option<String>, String> {
+        self.with_reader(|reader, peeked| {
+            let mut line = String::new();
+            match peeked.take() {
+                Some('\n') => return Ok(Some(line)),
+                Some(c) => line.push(c),
+                None => (),
+            }
+            let read = reader.read_line(&mut line).map_err(io_error)?;
+            if read == 0 && line.is_empty() {
+                return Ok(None);
+            }
+            if line.ends_with('\n') {
+                line.pop();
+                if line.ends_with('\r') {
+                    line.pop();
+                }
+            }
+            Ok(Some(line))
+        })
+    }
+
+    fn with_binary_reader<T>(
+        &self,
+        f: impl FnOnce(&mut dyn BufRead) -> Result<T, String>,
+    ) -> Result<T, String> {
+        match &mut *self.state.borrow_mut() {
+            PortState::BinaryInput(reader) => f(reader.as_mut()),
+            PortState::Closed => Err("Port is closed".to_owned()),
+            _ => Err("Not a binary input port".to_owned()),
+        }
+    }
+
+    pub fn peek_u8(&self) -> Result<Option<u8>, String> {
+        self.with_binary_reader(|reader| Ok(reader.fill_buf().map_err(io_error)?.first().copied()))
+    }
+
+    pub fn read_u8(&self) -> Result<Option<u8>, String> {
+        self.with_binary_reader(|reader| {
+            let byte = reader.fill_buf().map_err(io_error)?.first().copied();
+            if byte.is_some() {
+                reader.consume(1);
+            }
+            Ok(byte)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Port;
+
+    #[test]
+    fn string_and_bytevector_ports() {
+        let input = Port::string_input("ab\ncd");
+        assert_eq!(input.peek_char(), Ok(Some('a')));
+        assert_eq!(input.read_char(), Ok(Some('a')));
+        assert_eq!(input.read_line(), Ok(Some("b".to_owned())));
+        assert_eq!(input.read_line(), Ok(Some("cd".to_owned())));
+        assert_eq!(input.read_line(), Ok(None));
+        assert!(input.read_u8().is_err());
+
+        let output = Port::bytevector_output();
+        output.write_u8(1).unwrap();
+        output.write_u8(2).unwrap();
+        assert!(output.write_str("text").is_err());
+        assert_eq!(output.output_bytes(), Some(vec![1, 2]));
+
+        output.close().unwrap();
+        assert!(output.write_u8(3).is_err());
+        assert!(!output.is_output());
+    }
+}
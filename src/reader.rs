@@ -0,0 +1,221 @@
+use std::rc::Rc;
+
+use crate::{
+    expression::Exp,
+    lexer::{self, Position},
+    list::List,
+    parser,
+    ports::Port,
+};
+
+fn unexpected_eof() -> String {
+    "Parse error: unexpected end of file".to_owned()
+}
+
+fn is_atom_end(c: char) -> bool {
+    c.is_whitespace() || "(){}\"'".contains(c)
+}
+
+fn take_char(port: &Port, text: &mut String) -> Result<char, String> {
+    let c = port.read_char()?.ok_or_else(unexpected_eof)?;
+    text.push(c);
+    Ok(c)
+}
+
+// Returns what was skipped
+fn skip_whitespace(port: &Port) -> Result<String, String> {
+    let mut skipped = String::new();
+    while let Some(c) = port.peek_char()? {
+        if !c.is_whitespace() {
+            break;
+        }
+        take_char(port, &mut skipped)?;
+    }
+    Ok(skipped)
+}
+
+// Consumes everything up to and including the closing quote
+fn scan_string(port: &Port, text: &mut String) -> Result<(), String> {
+    loop {
+        match take_char(port, text)? {
+            '"' => return Ok(()),
+            '\\' => {
+                take_char(port, text)?;
+            }
+            _ => (),
+        }
+    }
+}
+
+fn scan_atom(port: &Port, text: &mut String) -> Result<(), String> {
+    while let Some(c) = port.peek_char()? {
+        if is_atom_end(c) {
+            break;
+        }
+        take_char(port, text)?;
+    }
+    Ok(())
+}
+
+// Consumes up to and including the closer matching an opener already in text
+fn scan_compound(port: &Port, text: &mut String) -> Result<(), String> {
+    let mut depth = 1;
+    while depth != 0 {
+        match take_char(port, text)? {
+            '(' | '{' => depth += 1,
+            ')' | '}' => depth -= 1,
+            '"' => scan_string(port, text)?,
+            // Character literals such as #\( don't affect the depth
+            '#' if port.peek_char()? == Some('\\') => {
+                take_char(port, text)?;
+                take_char(port, text)?;
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+// Collects the source text of the next datum without consuming anything after it, so the
+// rest of the port is left for later reads. Returns false if the input ran out first.
+fn scan_datum(port: &Port, text: &mut String) -> Result<bool, String> {
+    let c = match port.read_char()? {
+        Some(c) => c,
+        None => return Ok(false),
+    };
+    text.push(c);
+    match c {
+        '\'' => {
+            // Kept in the text so that positions stay accurate
+            text.push_str(&skip_whitespace(port)?);
+            if !scan_datum(port, text)? {
+                return Err(unexpected_eof());
+            }
+        }
+        '(' | '{' => scan_compound(port, text)?,
+        '"' => scan_string(port, text)?,
+        ')' | '}' => return Err("Parse error: unexpected token".to_owned()),
+        '#' => match port.peek_char()? {
+            Some('(') | Some('{') => {
+                take_char(port, text)?;
+                scan_compound(port, text)?;
+            }
+            Some('u') => {
+                take_char(port, text)?;
+                take_char(port, text)?;
+                if take_char(port, text)? != '(' {
+                    return Err("Error while tokenizing".to_owned());
+                }
+                scan_compound(port, text)?;
+            }
+            Some('\\') => {
+                take_char(port, text)?;
+                take_char(port, text)?;
+                scan_atom(port, text)?;
+            }
+            _ => scan_atom(port, text)?,
+        },
+        _ => scan_atom(port, text)?,
+    }
+    Ok(true)
+}
+
+pub fn read_datum(port: &Port) -> Result<Exp, String> {
+    read_datum_at(port, &mut Position::start(), None).map(|(exp, _)| exp)
+}
+
+// Like read_datum, but also returns where the datum started. position tracks where the port is
+// across calls. Lists read from a file remember where they were.
+pub fn read_datum_at(
+    port: &Port,
+    position: &mut Position,
+    file: Option<&Rc<str>>,
+) -> Result<(Exp, Position), String> {
+    skip_whitespace(port)?
+        .chars()
+        .for_each(|c| position.advance(c));
+    let start = *position;
+    let mut text = String::new();
+    let found = scan_datum(port, &mut text);
+    text.chars().for_each(|c| position.advance(c));
+    if !found? {
+        return Ok((Exp::Eof, start));
+    }
+    let exp = match file {
+        Some(file) => {
+            let (tokens, positions) = lexer::tokenize_from(&text, start)?;
+            parser::parse_from(&tokens, &positions, file)?
+        }
+        None => parser::parse(&lexer::tokenize(&text)?)?,
+    };
+    Ok((exp, start))
+}
+
+pub fn read(args: &List<Exp>) -> Result<Exp, String> {
+    match args.head() {
+        None => read_datum(&Port::current_input()),
+        Some(Exp::Port(port)) => read_datum(port),
+        Some(_) => Err("Type error".to_owned()),
+    }
+}
+
+pub fn read_string(args: &List<Exp>) -> Result<Exp, String> {
+    match args.head() {
+        Some(Exp::Str(text)) => read_datum(&Port::string_input(text)),
+        _ => Err("Type error".to_owned()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{read_datum, read_datum_at};
+    use crate::{
+        expression::Exp,
+        lexer::Position,
+        locations::{self, Location},
+        ports::Port,
+    };
+
+    #[test]
+    fn reads_one_datum_at_a_time() {
+        let port = Port::string_input("(1 (2 \")\") #\\)) foo 'bar\n#(3) {a 1}");
+        assert_eq!(read_datum(&port).unwrap().to_string(), "(1 (2 \")\") #\\))");
+        assert_eq!(read_datum(&port).unwrap(), Exp::Ident("foo".to_owned()));
+        read_datum(&port).unwrap();
+        assert_eq!(read_datum(&port).unwrap().to_string(), "#(3)");
+        assert_eq!(read_datum(&port).unwrap().to_string(), "{a 1}");
+        assert_eq!(read_datum(&port).unwrap(), Exp::Eof);
+
+        assert!(read_datum(&Port::string_input("(1 2")).is_err());
+    }
+
+    #[test]
+    fn tracks_positions() {
+        let port = Port::string_input("a\n\n(b\n c) '\nd\n(e");
+        let mut position = Position::start();
+        let mut line = || read_datum_at(&port, &mut position, None).map(|(_, at)| at.line);
+        assert_eq!(line(), Ok(1));
+        assert_eq!(line(), Ok(3));
+        assert_eq!(line(), Ok(4));
+        assert!(line().is_err());
+        assert_eq!(position.line, 6);
+
+        let port = Port::string_input("(f 1)\n  (g\n    (h))");
+        let file = "test.lsp".into();
+        let mut position = Position::start();
+        read_datum_at(&port, &mut position, Some(&file)).unwrap();
+        let (exp, _) = read_datum_at(&port, &mut position, Some(&file)).unwrap();
+        let Exp::List(g) = exp else { panic!() };
+        let Some(Exp::List(h)) = g.iter().nth(1) else {
+            panic!()
+        };
+        let at = |line, column| {
+            Some(Location {
+                file: file.clone(),
+                position: Position { line, column },
+            })
+        };
+        assert_eq!(locations::of(&g), at(2, 3));
+        assert_eq!(locations::of(h), at(3, 5));
+    }
+}
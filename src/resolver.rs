@@ -0,0 +1,300 @@
+use std::{ptr, rc::Rc};
+
+use crate::{environment::Environment, expression::Exp, list::List, locations, special_forms};
+
+// A pass over top-level forms before the tree-walker evaluates them, which rewrites identifiers in
+// function bodies to say where their value lives. A parameter is read straight out of its slot,
+// and an identifier no enclosing function binds skips the call frames in between and is looked up
+// from the top-level environment. Everything else keeps being looked up by name, which is also
+// how code constructed at runtime, like macro expansions, is evaluated.
+
+// Where a resolved identifier's value lives, relative to the frame it's evaluated in
+#[derive(Clone, Copy, Debug)]
+pub enum Address {
+    // A parameter, in a slot of the frame depth levels up
+    Local { depth: usize, index: usize },
+    // Looked up by name, starting depth levels up
+    Global { depth: usize },
+}
+
+pub struct Resolved {
+    pub name: String,
+    pub address: Address,
+}
+
+impl Resolved {
+    pub fn lookup(&self, env: &Environment) -> Option<Exp> {
+        match self.address {
+            Address::Local { depth, index } => env.slot(depth, index),
+            Address::Global { depth } => env.ancestor(depth)?.lookup(&self.name),
+        }
+    }
+}
+
+// A call runs in a fresh frame extending the closing environment, which itself extends the
+// environment the lambda was evaluated in
+const FRAMES_PER_FUNCTION: usize = 2;
+
+struct Scope {
+    params: Vec<String>,
+    defs: Vec<String>,
+    // Whether the body could bind names that can't be seen here, through macros or special forms
+    // other than the core ones. Such a function is left unresolved, along with everything in it.
+    dynamic: bool,
+}
+
+enum Form<'a> {
+    Quote,
+    If,
+    Def,
+    Set,
+    Lambda(&'a List<Exp>),
+    Call,
+    // Left alone, since it could evaluate its parts anywhere
+    Opaque,
+}
+
+struct Resolver<'a> {
+    env: &'a Environment,
+    scopes: Vec<Scope>,
+}
+
+pub fn resolve(exp: &Exp, env: &Environment) -> Exp {
+    let mut resolver = Resolver {
+        env,
+        scopes: Vec::new(),
+    };
+    resolver.resolve(exp)
+}
+
+impl Resolver<'_> {
+    fn is_bound(&self, name: &str) -> bool {
+        self.scopes.iter().any(|scope| {
+            scope
+                .params
+                .iter()
+                .chain(&scope.defs)
+                .any(|bound| bound == name)
+        })
+    }
+
+    fn form<'b>(&self, list: &'b List<Exp>) -> Form<'b> {
+        let form = match list.head() {
+            Some(Exp::Ident(name)) if !self.is_bound(name) => match self.env.lookup(name) {
+                Some(Exp::SpecialForm(form)) => form,
+                Some(Exp::Macro(_)) => return Form::Opaque,
+                _ => return Form::Call,
+            },
+            Some(Exp::SpecialForm(form)) => *form,
+            _ => return Form::Call,
+        };
+        let is = |other: fn(&List<Exp>, &mut Environment) -> Result<Exp, String>| {
+            ptr::fn_addr_eq(form, other)
+        };
+        if is(special_forms::quote) {
+            Form::Quote
+        } else if is(special_forms::if_exp) {
+            Form::If
+        } else if is(special_forms::def) {
+            Form::Def
+        } else if is(special_forms::set) {
+            Form::Set
+        } else if is(special_forms::lambda) {
+            match lambda_params(list) {
+                Some(_) => Form::Lambda(list),
+                None => Form::Opaque,
+            }
+        } else {
+            Form::Opaque
+        }
+    }
+
+    // Finds the definitions a function body makes in its own frame, and whether it could make
+    // any that aren't visible
+    fn scan(&self, exp: &Exp, scope: &mut Scope) {
+        let Exp::List(list) = exp else { return };
+        match self.form(list) {
+            Form::Quote | Form::Lambda(_) => (),
+            Form::Opaque => scope.dynamic = true,
+            form => {
+                if let (Form::Def, Some(Exp::Ident(name))) = (form, list.iter().nth(1)) {
+                    scope.defs.push(name.clone());
+                }
+                list.iter().for_each(|exp| self.scan(exp, scope));
+            }
+        }
+    }
+
+    fn resolve(&mut self, exp: &Exp) -> Exp {
+        match exp {
+            Exp::Ident(name) => self.resolve_ident(name).unwrap_or_else(|| exp.clone()),
+            Exp::List(list) => match self.form(list) {
+                Form::Quote | Form::Opaque => exp.clone(),
+                Form::Lambda(list) => self.resolve_lambda(list),
+                // The identifier a def or set! targets stays a name
+                Form::Def | Form::Set => {
+                    let mut elems = list.iter();
+                    let head = elems.next().cloned().into_iter();
+                    let target = elems.next().cloned().into_iter();
+                    let rest = elems.map(|exp| self.resolve(exp)).collect::<Vec<_>>();
+                    rebuilt(list, head.chain(target).chain(rest).collect())
+                }
+                Form::If | Form::Call => {
+                    rebuilt(list, list.iter().map(|exp| self.resolve(exp)).collect())
+                }
+            },
+            _ => exp.clone(),
+        }
+    }
+
+    fn resolve_lambda(&mut self, list: &List<Exp>) -> Exp {
+        let params = lambda_params(list).expect("Checked when classifying the form");
+        let mut elems = list.iter().cloned();
+        let head = elems.next().into_iter();
+        let params_exp = elems.next().into_iter();
+        let body = elems.next();
+        let rest = elems;
+
+        let mut scope = Scope {
+            params,
+            defs: Vec::new(),
+            dynamic: false,
+        };
+        if let Some(body) = &body {
+            self.scan(body, &mut scope);
+        }
+        if scope.dynamic {
+            return Exp::List(list.clone());
+        }
+        self.scopes.push(scope);
+        let body = body.map(|body| self.resolve(&body));
+        self.scopes.pop();
+
+        let elems = head.chain(params_exp).chain(body).chain(rest).collect();
+        rebuilt(list, elems)
+    }
+
+    fn resolve_ident(&self, name: &str) -> Option<Exp> {
+        if self.scopes.is_empty() {
+            return None;
+        }
+        for (level, scope) in self.scopes.iter().rev().enumerate() {
+            let depth = level * FRAMES_PER_FUNCTION;
+            if let Some(index) = scope.params.iter().position(|param| param == name) {
+                return Some(resolved(name, Address::Local { depth, index }));
+            }
+            if scope.defs.iter().any(|def| def == name) {
+                return None;
+            }
+        }
+        let depth = self.scopes.len() * FRAMES_PER_FUNCTION;
+        Some(resolved(name, Address::Global { depth }))
+    }
+}
+
+// A list with the elements given, which stands in for the original wherever it was read from
+fn rebuilt(original: &List<Exp>, elems: Vec<Exp>) -> Exp {
+    let list = List::from_vec(elems);
+    locations::copy(original, &list);
+    Exp::List(list)
+}
+
+fn resolved(name: &str, address: Address) -> Exp {
+    Exp::Resolved(Rc::new(Resolved {
+        name: name.to_owned(),
+        address,
+    }))
+}
+
+fn lambda_params(list: &List<Exp>) -> Option<Vec<String>> {
+    match list.iter().nth(1)? {
+        Exp::List(params) => params
+            .iter()
+            .map(|param| match param {
+                Exp::Ident(name) => Some(name.clone()),
+                _ => None,
+            })
+            .collect(),
+        _ => None,
+    }
+}
+
+// Turns resolved identifiers back into names, for a form that turned out to be a macro use only
+// once it was evaluated. Returns None if there weren't any.
+pub fn unresolve(list: &List<Exp>) -> Option<List<Exp>> {
+    let mut changed = false;
+    let elems = list
+        .iter()
+        .map(|exp| match exp {
+            Exp::Resolved(resolved) => {
+                changed = true;
+                Exp::Ident(resolved.name.clone())
+            }
+            Exp::List(inner) => match unresolve(inner) {
+                Some(inner) => {
+                    changed = true;
+                    Exp::List(inner)
+                }
+                None => exp.clone(),
+            },
+            _ => exp.clone(),
+        })
+        .collect();
+    changed.then(|| List::from_vec(elems))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{evaluator::Evaluator, Interpreter};
+
+    // Forms whose bindings can't all be seen before evaluating them, so the tree-walker has to
+    // fall back to looking them up by name
+    #[test]
+    fn falls_back_to_names() {
+        let cases: &[(&[&str], &str)] = &[
+            // A macro defined after the function using it
+            (
+                &[
+                    "(def f (lambda (x) (inc x)))",
+                    "(defmacro inc (a) (list '+ a 1))",
+                    "(f 41)",
+                ],
+                "42",
+            ),
+            // A definition in a call frame shadowing a global
+            (
+                &[
+                    "(def y 100)",
+                    "(def f (lambda (n) ((lambda (ignored) (+ y n)) (def y 10))))",
+                    "(f 1)",
+                ],
+                "11",
+            ),
+            // A frame captured and extended at runtime
+            (
+                &[
+                    "(def y 100)",
+                    "(def f (lambda (x) ((lambda (e) ((lambda (ignored) (+ x y)) (eval '(def y 1) e))) (the-environment))))",
+                    "(f 1)",
+                ],
+                "2",
+            ),
+            // A parameter shadowing a special form
+            (&["(def f (lambda (if) (if 1 2 3)))", "(f list)"], "(1 2 3)"),
+        ];
+        for (forms, expected) in cases {
+            let mut interpreter = Interpreter::new();
+            interpreter.set_evaluator(Evaluator::TreeWalker);
+            let mut result = None;
+            for form in *forms {
+                result = Some(interpreter.eval_str(form).unwrap());
+            }
+            assert_eq!(
+                result.unwrap().display_form().to_string(),
+                *expected,
+                "{:?}",
+                forms
+            );
+        }
+    }
+}
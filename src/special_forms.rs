@@ -1,11 +1,14 @@
+use std::{cell::OnceCell, rc::Rc};
+
 use crate::{
     environment::Environment,
-    eval,
     expression::{Exp, Function, Lambda},
     list::List,
+    machine::{self, Native},
 };
 
-pub fn def(args: &List<Exp>, env: &mut Environment) -> Result<Exp, String> {
+// The identifier and value expression of (def x value) or (set! x value)
+pub fn target(args: &List<Exp>) -> Result<(String, Exp), String> {
     let ident = match args.head().ok_or("Type error".to_owned())? {
         Exp::Ident(x) => x,
         _ => return Err("Type error".to_owned()),
@@ -13,10 +16,26 @@ pub fn def(args: &List<Exp>, env: &mut Environment) -> Result<Exp, String> {
 
     let snd = args.tail().ok_or("Type error".to_owned())?;
     let value_exp = snd.head().ok_or("Type error".to_owned())?;
-    let value = eval(value_exp, env)?;
+    Ok((ident.clone(), value_exp.clone()))
+}
+
+// def, set! and if are evaluated by the machine, which these hand them to when called from Rust
+pub fn def(args: &List<Exp>, env: &mut Environment) -> Result<Exp, String> {
+    machine::special_form(Native::Def, args, env)
+}
+
+pub fn set(args: &List<Exp>, env: &mut Environment) -> Result<Exp, String> {
+    machine::special_form(Native::Set, args, env)
+}
+
+pub fn quote(args: &List<Exp>, _env: &mut Environment) -> Result<Exp, String> {
+    args.head()
+        .cloned()
+        .ok_or("Missing quoted expression".to_owned())
+}
 
-    env.define(ident, &value)?;
-    Ok(Exp::Ident(ident.to_owned()))
+pub fn the_environment(_args: &List<Exp>, env: &mut Environment) -> Result<Exp, String> {
+    Ok(Exp::Environment(env.clone()))
 }
 
 pub fn lambda(args: &List<Exp>, env: &mut Environment) -> Result<Exp, String> {
@@ -39,19 +58,20 @@ pub fn lambda(args: &List<Exp>, env: &mut Environment) -> Result<Exp, String> {
         closing_env,
         params,
         body: Box::new(body.clone()),
+        name: OnceCell::new(),
     };
-    Ok(Exp::Function(Function::Lambda(lambda)))
+    Ok(Exp::Function(Function::Lambda(Rc::new(lambda))))
 }
 
-pub fn if_exp(args: &List<Exp>, env: &mut Environment) -> Result<Exp, String> {
+// The test, then and else expressions of (if test then else)
+pub fn if_parts(args: &List<Exp>) -> Result<(Exp, Exp, Exp), String> {
     let mut args_iter = args.iter();
     let conditional = args_iter.next().ok_or("Missing conditional".to_owned())?;
     let then_exp = args_iter.next().ok_or("Missing then clause".to_owned())?;
     let else_exp = args_iter.next().ok_or("Missing else clause".to_owned())?;
+    Ok((conditional.clone(), then_exp.clone(), else_exp.clone()))
+}
 
-    if eval(conditional, env)?.is_truthy() {
-        eval(then_exp, env)
-    } else {
-        eval(else_exp, env)
-    }
+pub fn if_exp(args: &List<Exp>, env: &mut Environment) -> Result<Exp, String> {
+    machine::special_form(Native::If, args, env)
 }
@@ -0,0 +1,52 @@
+use crate::{expression::Exp, list::List};
+
+pub fn string_to_list(args: &List<Exp>) -> Result<Exp, String> {
+    match args.head() {
+        Some(Exp::Str(s)) => Ok(Exp::List(List::from_vec(
+            s.chars().map(Exp::Char).collect(),
+        ))),
+        _ => Err("Type error".to_owned()),
+    }
+}
+
+pub fn list_to_string(args: &List<Exp>) -> Result<Exp, String> {
+    let list = match args.head() {
+        Some(Exp::List(list)) => list,
+        _ => return Err("Type error".to_owned()),
+    };
+    list.iter()
+        .map(|exp| match exp {
+            Exp::Char(c) => Ok(*c),
+            _ => Err("Type error".to_owned()),
+        })
+        .collect::<Result<String, String>>()
+        .map(|s| Exp::Str(s.into()))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::Interpreter;
+
+    #[test]
+    fn strings_and_lists() {
+        let mut interpreter = Interpreter::new();
+        let cases = [
+            ("(string->list \"héllo\")", Ok("(#\\h #\\é #\\l #\\l #\\o)")),
+            ("(list->string (string->list \"héllo\"))", Ok("\"héllo\"")),
+            (
+                "(string->list (list->string (list #\\a #\\b)))",
+                Ok("(#\\a #\\b)"),
+            ),
+            ("(string->list \"\")", Ok("()")),
+            ("(list->string (list))", Ok("\"\"")),
+            ("(string->list 5)", Err("Type error")),
+            ("(list->string \"ab\")", Err("Type error")),
+            ("(list->string (list #\\a 1))", Err("Type error")),
+        ];
+        for (form, expected) in cases {
+            let result = interpreter.eval_str(form).map(|val| val.to_string());
+            let expected = expected.map(str::to_owned).map_err(str::to_owned);
+            assert_eq!(result, expected, "{}", form);
+        }
+    }
+}
@@ -0,0 +1,39 @@
+use std::cell::Cell;
+
+// Which values count as false in conditionals. See the README for how each form behaves.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Truthiness {
+    // Only #f is false, as in Scheme
+    Strict,
+    // #f, zero and the empty list are all false
+    CLike,
+}
+
+thread_local! {
+    static CURRENT: Cell<Truthiness> = const { Cell::new(Truthiness::CLike) };
+}
+
+impl Truthiness {
+    pub fn current() -> Self {
+        CURRENT.with(Cell::get)
+    }
+
+    pub fn set_current(self) {
+        CURRENT.with(|current| current.set(self))
+    }
+}
+
+impl std::str::FromStr for Truthiness {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "strict" => Ok(Truthiness::Strict),
+            "c-like" => Ok(Truthiness::CLike),
+            _ => Err(format!(
+                "Unknown truthiness '{}', expected strict or c-like",
+                s
+            )),
+        }
+    }
+}
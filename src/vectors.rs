@@ -0,0 +1,193 @@
+use std::{cell::RefCell, mem, rc::Rc};
+
+use crate::{
+    expression::{Exp, Function},
+    limits,
+    list::List,
+    math,
+};
+
+fn new_vector(elems: Vec<Exp>) -> Exp {
+    Exp::Vector(Rc::new(RefCell::new(elems)))
+}
+
+fn vector_arg(arg: Option<&Exp>) -> Result<&Rc<RefCell<Vec<Exp>>>, String> {
+    match arg {
+        Some(Exp::Vector(vec)) => Ok(vec),
+        _ => Err("Type error".to_owned()),
+    }
+}
+
+fn function_arg(arg: Option<&Exp>) -> Result<&Function, String> {
+    match arg {
+        Some(Exp::Function(f)) => Ok(f),
+        _ => Err("Type error".to_owned()),
+    }
+}
+
+fn index_arg(arg: Option<&Exp>, len: usize) -> Result<usize, String> {
+    let idx = math::integer_arg(arg.ok_or("Missing required argument".to_owned())?)?;
+    usize::try_from(idx)
+        .ok()
+        .filter(|idx| *idx < len)
+        .ok_or("Index out of bounds".to_owned())
+}
+
+pub fn vector(args: &List<Exp>) -> Result<Exp, String> {
+    Ok(new_vector(args.iter().cloned().collect()))
+}
+
+pub fn make_vector(args: &List<Exp>) -> Result<Exp, String> {
+    let mut args_iter = args.iter();
+    let len = math::integer_arg(args_iter.next().ok_or("Type error".to_owned())?)?;
+    let len = usize::try_from(len).map_err(|_| "Invalid vector length".to_owned())?;
+    limits::reserve(len.saturating_mul(mem::size_of::<Exp>()))?;
+    let fill = args_iter.next().cloned().unwrap_or(Exp::Number(0.0));
+    // Failing to allocate would abort the process, so a length too big for memory is an error
+    let mut elems = Vec::new();
+    elems
+        .try_reserve_exact(len)
+        .map_err(|_| "Invalid vector length".to_owned())?;
+    elems.resize(len, fill);
+    Ok(new_vector(elems))
+}
+
+pub fn vector_ref(args: &List<Exp>) -> Result<Exp, String> {
+    let mut args_iter = args.iter();
+    let vec = vector_arg(args_iter.next())?.borrow();
+    let idx = index_arg(args_iter.next(), vec.len())?;
+    Ok(vec[idx].clone())
+}
+
+pub fn vector_set(args: &List<Exp>) -> Result<Exp, String> {
+    let mut args_iter = args.iter();
+    let mut vec = vector_arg(args_iter.next())?.borrow_mut();
+    let idx = index_arg(args_iter.next(), vec.len())?;
+    let val = args_iter
+        .next()
+        .ok_or("Missing required argument".to_owned())?;
+    vec[idx] = val.clone();
+    Ok(Exp::Void)
+}
+
+pub fn vector_length(args: &List<Exp>) -> Result<Exp, String> {
+    let vec = vector_arg(args.head())?.borrow();
+    Ok(Exp::Number(vec.len() as f32))
+}
+
+pub fn vector_to_list(args: &List<Exp>) -> Result<Exp, String> {
+    let vec = vector_arg(args.head())?.borrow();
+    Ok(Exp::List(List::from_vec(vec.clone())))
+}
+
+pub fn list_to_vector(args: &List<Exp>) -> Result<Exp, String> {
+    match args.head() {
+        Some(Exp::List(list)) => Ok(new_vector(list.iter().cloned().collect())),
+        _ => Err("Type error".to_owned()),
+    }
+}
+
+pub fn vector_fill(args: &List<Exp>) -> Result<Exp, String> {
+    let mut args_iter = args.iter();
+    let vec = vector_arg(args_iter.next())?;
+    let fill = args_iter
+        .next()
+        .ok_or("Missing required argument".to_owned())?;
+    vec.borrow_mut().fill(fill.clone());
+    Ok(Exp::Void)
+}
+
+// Calls f with the i-th element of every vector, stopping at the end of the shortest one
+fn map_vectors(args: &List<Exp>) -> Result<Vec<Exp>, String> {
+    let f = function_arg(args.head())?;
+    let vecs = args
+        .iter()
+        .skip(1)
+        .map(|arg| vector_arg(Some(arg)).cloned())
+        .collect::<Result<Vec<_>, String>>()?;
+    if vecs.is_empty() {
+        return Err("Missing required argument".to_owned());
+    }
+    let len = vecs.iter().map(|vec| vec.borrow().len()).min().unwrap();
+
+    let mut results = Vec::with_capacity(len);
+    for idx in 0..len {
+        // The borrow is released before the call so f is free to mutate the vectors
+        let call_args = vecs
+            .iter()
+            .map(|vec| vec.borrow().get(idx).cloned())
+            .collect::<Option<Vec<Exp>>>()
+            .ok_or("Index out of bounds".to_owned())?;
+        results.push(f.call(&List::from_vec(call_args))?);
+    }
+    Ok(results)
+}
+
+pub fn vector_map(args: &List<Exp>) -> Result<Exp, String> {
+    map_vectors(args).map(new_vector)
+}
+
+pub fn vector_for_each(args: &List<Exp>) -> Result<Exp, String> {
+    map_vectors(args)?;
+    Ok(Exp::Void)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::Interpreter;
+
+    #[test]
+    fn vector_builtins() {
+        let cases = [
+            ("(def v (vector 1 2 3))", Ok("#void#")),
+            ("(vector-ref v 0)", Ok("1")),
+            ("(vector-ref v 2)", Ok("3")),
+            ("(vector-ref v 3)", Err("Index out of bounds")),
+            ("(vector-ref v -1)", Err("Index out of bounds")),
+            ("(vector-ref v 1.5)", Err("Type error")),
+            ("(vector-ref v)", Err("Missing required argument")),
+            ("(vector-ref '(1 2) 0)", Err("Type error")),
+            ("(vector-set! v 3 'x)", Err("Index out of bounds")),
+            ("(vector-set! v 1 'x)", Ok("#void#")),
+            ("v", Ok("#(1 x 3)")),
+            ("(vector-length v)", Ok("3")),
+            ("(vector-length #())", Ok("0")),
+            ("(make-vector 2 'a)", Ok("#(a a)")),
+            ("(make-vector -1)", Err("Invalid vector length")),
+            ("(make-vector 100000000000 0)", Err("Invalid vector length")),
+            ("(vector->list #(1 2))", Ok("(1 2)")),
+            ("(list->vector '(1 2))", Ok("#(1 2)")),
+            ("(vector-map + #(1 2 3) #(10 20))", Ok("#(11 22)")),
+            ("(vector-fill! v 0)", Ok("#void#")),
+            ("v", Ok("#(0 0 0)")),
+            ("(equal? #(1 (2)) (vector 1 (list 2)))", Ok("#t")),
+            ("(equal? #(1 2) #(1 2 3))", Ok("#f")),
+            ("(eqv? #(1 2) #(1 2))", Ok("#f")),
+            ("(eqv? v v)", Ok("#t")),
+        ];
+        let mut interpreter = Interpreter::new();
+        for (form, expected) in cases {
+            let result = interpreter
+                .eval_str(form)
+                .map(|val| val.display_form().to_string());
+            let expected = expected.map(str::to_owned).map_err(str::to_owned);
+            assert_eq!(result, expected, "{}", form);
+        }
+    }
+
+    #[test]
+    fn prints_vectors_that_contain_themselves() {
+        let mut interpreter = Interpreter::new();
+        interpreter.eval_str("(def v (vector 1 2))").unwrap();
+        interpreter.eval_str("(vector-set! v 0 v)").unwrap();
+        assert_eq!(
+            interpreter.eval_str("v").unwrap().to_string(),
+            "#(#(...) 2)"
+        );
+        interpreter.eval_str("(vector-set! v 1 (list v))").unwrap();
+        assert_eq!(
+            interpreter.eval_str("(vector v)").unwrap().to_string(),
+            "#(#(#(...) (#(...))))"
+        );
+    }
+}
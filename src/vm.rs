@@ -0,0 +1,757 @@
+use std::{
+    cell::{OnceCell, RefCell},
+    mem,
+    rc::{Rc, Weak},
+};
+
+use crate::{
+    backtrace,
+    compiler::{self, Capture, Op, Operand, Proto},
+    conditions,
+    environment::Environment,
+    evaluator::Evaluator,
+    expression::{Exp, Function},
+    gc::{Trace, Tracer},
+    limits,
+    list::List,
+    machine::{self, Control},
+    resolver,
+};
+
+// The locals of a call that closures have captured
+type Captured = Rc<RefCell<Vec<Exp>>>;
+
+pub struct Closure {
+    proto: Rc<Proto>,
+    upvalues: Vec<(Captured, usize)>,
+    // Where global variables are looked up and defined
+    globals: Environment,
+    pub name: OnceCell<String>,
+}
+
+#[derive(Clone)]
+struct Frame {
+    closure: Rc<Closure>,
+    ip: usize,
+    // Stack index of the first local. The function being called sits just below it.
+    base: usize,
+    captured: Option<Captured>,
+}
+
+thread_local! {
+    // Every captured frame, so the collector can find cycles through them
+    static TRACKED: RefCell<Tracked> = const { RefCell::new(Tracked { frames: Vec::new(), prune_at: 1024 }) };
+}
+
+struct Tracked {
+    frames: Vec<Weak<RefCell<Vec<Exp>>>>,
+    prune_at: usize,
+}
+
+impl Tracked {
+    fn prune(&mut self) {
+        self.frames.retain(|frame| frame.strong_count() > 0);
+        self.prune_at = (self.frames.len() * 2).max(1024);
+    }
+}
+
+fn track(locals: Vec<Exp>) -> Captured {
+    let captured = Rc::new(RefCell::new(locals));
+    TRACKED.with(|tracked| {
+        let mut tracked = tracked.borrow_mut();
+        if tracked.frames.len() >= tracked.prune_at {
+            tracked.prune();
+        }
+        tracked.frames.push(Rc::downgrade(&captured));
+    });
+    captured
+}
+
+// The captured frames that are still alive
+pub fn tracked() -> Vec<Weak<RefCell<Vec<Exp>>>> {
+    TRACKED.with(|tracked| {
+        let mut tracked = tracked.borrow_mut();
+        tracked.prune();
+        tracked.frames.clone()
+    })
+}
+
+pub fn tracked_count() -> usize {
+    TRACKED.with(|tracked| {
+        let mut tracked = tracked.borrow_mut();
+        tracked.prune();
+        tracked.frames.len()
+    })
+}
+
+// Evaluates a top-level form with the selected evaluator
+pub fn eval_toplevel(exp: &Exp, env: &mut Environment) -> Result<Exp, String> {
+    machine::run(form(exp, env))
+}
+
+// Evaluates a form for the interpreter. Continuations captured while it runs can be resumed by
+// any later form, since what's left to do after each is the same.
+pub fn eval_rooted(exp: &Exp, env: &mut Environment) -> Result<Exp, String> {
+    machine::run_rooted(form(exp, env))
+}
+
+fn form(exp: &Exp, env: &Environment) -> Control {
+    match compiled(exp, env) {
+        Some(state) => Control::Run(state, None),
+        None => Control::Eval(resolver::resolve(exp, env), env.clone()),
+    }
+}
+
+// Runs a form on the VM, unless the tree-walker is selected or the form can't be compiled
+pub fn run_compiled(exp: &Exp, env: &Environment) -> Option<Result<Exp, String>> {
+    compiled(exp, env).map(|state| machine::run(Control::Run(state, None)))
+}
+
+fn compiled(exp: &Exp, env: &Environment) -> Option<Suspended> {
+    if Evaluator::current() != Evaluator::Bytecode {
+        return None;
+    }
+    start_form(exp, env)
+}
+
+// Runs a form on the VM regardless of the selected evaluator, if it can be compiled
+pub fn eval_compiled(exp: &Exp, env: &Environment) -> Option<Result<Exp, String>> {
+    start_form(exp, env).map(|state| machine::run(Control::Run(state, None)))
+}
+
+fn start_form(exp: &Exp, env: &Environment) -> Option<Suspended> {
+    let closure = Rc::new(Closure {
+        proto: compiler::compile(exp, env)?,
+        upvalues: Vec::new(),
+        globals: env.clone(),
+        name: OnceCell::new(),
+    });
+    start(closure, &List::new(), true).ok()
+}
+
+// The run of a call of a compiled function, for the machine to start
+pub fn start_call(closure: &Rc<Closure>, args: &List<Exp>) -> Result<Suspended, String> {
+    start(closure.clone(), args, false)
+}
+
+fn start(closure: Rc<Closure>, args: &List<Exp>, toplevel: bool) -> Result<Suspended, String> {
+    let mut stack = vec![Exp::Function(Function::Compiled(closure.clone()))];
+    stack.extend(args.iter().cloned());
+    let argc = stack.len() - 1;
+    let frame = enter(&mut stack, closure, 1, argc)?;
+    Ok(Suspended {
+        stack,
+        frames: Vec::new(),
+        frame,
+        toplevel,
+    })
+}
+
+// A call the VM leaves to the machine: the function, its arguments and the call expression
+type Call = (Function, List<Exp>, Option<Exp>);
+
+pub enum Step {
+    Done(Exp),
+    // The machine makes the call, and resumes the run with its result
+    Call(Suspended, Function, List<Exp>, Option<Exp>),
+}
+
+enum Exit {
+    Done(Exp),
+    Call(Call),
+}
+
+// Runs compiled code until it finishes or calls something the machine has to. A run being
+// resumed gets the value of the call it stopped at.
+pub fn run(state: Suspended, value: Option<Exp>) -> Result<Step, String> {
+    let mut vm = Vm::new(state);
+    vm.stack.extend(value);
+    match vm.run() {
+        Ok(Exit::Done(val)) => Ok(Step::Done(val)),
+        Ok(Exit::Call((f, args, call))) => Ok(Step::Call(vm.suspend(), f, args, call)),
+        Err(err) => {
+            vm.suspend().record_frames(&err);
+            Err(err)
+        }
+    }
+}
+
+// Sets up the locals of a call whose arguments start at base
+#[inline(always)]
+fn enter(
+    stack: &mut Vec<Exp>,
+    closure: Rc<Closure>,
+    base: usize,
+    argc: usize,
+) -> Result<Frame, String> {
+    let proto = &closure.proto;
+    if argc < proto.arity {
+        return Err("Missing required argument".to_owned());
+    }
+    // Extra arguments are ignored
+    if argc > proto.arity {
+        stack.truncate(base + proto.arity);
+    }
+    // Locals the body defines are unbound until it does
+    if proto.slots > proto.arity {
+        stack.resize(base + proto.slots, Exp::Unbound);
+    }
+    let captured = if proto.captured > 0 {
+        let mut locals = vec![Exp::Unbound; proto.captured];
+        for (param, index) in &proto.captured_params {
+            locals[*index] = mem::replace(&mut stack[base + param], Exp::Void);
+        }
+        Some(track(locals))
+    } else {
+        None
+    };
+    Ok(Frame {
+        closure,
+        ip: 0,
+        base,
+        captured,
+    })
+}
+
+// What's left to do of a run of the VM, which the machine resumes
+#[derive(Clone)]
+pub struct Suspended {
+    stack: Vec<Exp>,
+    frames: Vec<Frame>,
+    frame: Frame,
+    // Whether the outermost frame is a top-level form rather than a function
+    toplevel: bool,
+}
+
+impl Suspended {
+    // How many calls are running
+    pub fn depth(&self) -> usize {
+        self.frames.len() + 1
+    }
+
+    // Adds the calls still running to the error's backtrace. Whatever started the run records
+    // the call that began it, unless that's a top-level form.
+    pub fn record_frames(&self, err: &str) {
+        let frames = self.frames.iter().chain([&self.frame]).collect::<Vec<_>>();
+        for (depth, frame) in frames.iter().enumerate().rev() {
+            let closure = &frame.closure;
+            let caller = depth.checked_sub(1).map(|depth| frames[depth]);
+            if caller.is_none() && self.toplevel {
+                continue;
+            }
+            backtrace::record(err, || {
+                let call = caller.and_then(|caller| caller.closure.proto.call_site(caller.ip - 1));
+                let f = Function::Compiled(closure.clone());
+                backtrace::Frame::new(&f, call, self.args(frame))
+            });
+        }
+    }
+
+    // The arguments of a call, as its parameters hold them now
+    fn args(&self, frame: &Frame) -> Vec<Exp> {
+        let proto = &frame.closure.proto;
+        let mut args = self.stack[frame.base..frame.base + proto.arity].to_vec();
+        if let Some(captured) = &frame.captured {
+            for (param, index) in &proto.captured_params {
+                args[*param] = captured.borrow()[*index].clone();
+            }
+        }
+        args
+    }
+}
+
+struct Vm {
+    stack: Vec<Exp>,
+    // The callers of the current frame, innermost last
+    frames: Vec<Frame>,
+    frame: Frame,
+    // Bindings can only change in code that isn't compiled, or through a global definition or
+    // assignment, so the version only needs reading again after those
+    version: u64,
+    toplevel: bool,
+    // How deeply calls were nested when the run started, which its frames add to
+    base_depth: usize,
+    max_depth: usize,
+}
+
+impl Vm {
+    fn new(state: Suspended) -> Self {
+        Vm {
+            stack: state.stack,
+            frames: state.frames,
+            frame: state.frame,
+            version: Environment::bindings_version(),
+            toplevel: state.toplevel,
+            base_depth: limits::depth(),
+            max_depth: limits::max_depth(),
+        }
+    }
+
+    fn suspend(self) -> Suspended {
+        Suspended {
+            stack: self.stack,
+            frames: self.frames,
+            frame: self.frame,
+            toplevel: self.toplevel,
+        }
+    }
+
+    #[inline(always)]
+    fn cached_global<T>(&self, index: usize, f: impl FnOnce(&Exp) -> T) -> Option<T> {
+        match &self.frame.closure.proto.global_cache.borrow()[index] {
+            Some((version, val)) if *version == self.version => Some(f(val)),
+            _ => None,
+        }
+    }
+
+    fn global(&self, index: usize) -> Result<Exp, String> {
+        if let Some(val) = self.cached_global(index, Exp::clone) {
+            return Ok(val);
+        }
+        let closure = &self.frame.closure;
+        let name = &closure.proto.globals[index];
+        let Some(val) = closure.globals.lookup(name) else {
+            return conditions::unbound(name);
+        };
+        closure.proto.global_cache.borrow_mut()[index] = Some((self.version, val.clone()));
+        Ok(val)
+    }
+
+    // The value a local read gives, which is an error if it hasn't been defined yet
+    #[inline(always)]
+    fn bound(&self, val: Exp, op: Op) -> Result<Exp, String> {
+        match val {
+            Exp::Unbound => conditions::unbound(self.frame.closure.proto.name(op)),
+            val => Ok(val),
+        }
+    }
+
+    fn pop(&mut self) -> Exp {
+        self.stack.pop().unwrap()
+    }
+
+    fn number(&self, operand: Operand, stack_index: usize) -> Option<f32> {
+        let val = match operand {
+            Operand::Stack => &self.stack[stack_index],
+            Operand::Local(index) => &self.stack[self.frame.base + index as usize],
+            Operand::Const(index) => &self.frame.closure.proto.constants[index as usize],
+        };
+        match val {
+            Exp::Number(val) => Some(*val),
+            _ => None,
+        }
+    }
+
+    // Operands on the stack are popped, so the second has to be taken first
+    fn operand(&mut self, operand: Operand) -> Exp {
+        match operand {
+            Operand::Stack => self.pop(),
+            Operand::Local(index) => self.stack[self.frame.base + index as usize].clone(),
+            Operand::Const(index) => self.frame.closure.proto.constants[index as usize].clone(),
+        }
+    }
+
+    // Calls the function at stack[callee] with the arguments above it. Compiled functions get a
+    // new frame, and builtins are called directly and leave their result in place of the call.
+    // Anything else is returned for the machine to call, with the callee and arguments popped.
+    #[inline(always)]
+    fn call_at(&mut self, callee: usize, argc: usize) -> Result<Option<Call>, String> {
+        limits::tick()?;
+        match &self.stack[callee] {
+            Exp::Function(Function::Compiled(closure)) => {
+                if self.base_depth + self.frames.len() + 1 > self.max_depth {
+                    return limits::depth_exceeded();
+                }
+                let closure = closure.clone();
+                let frame = enter(&mut self.stack, closure, callee + 1, argc)?;
+                self.frames.push(mem::replace(&mut self.frame, frame));
+            }
+            Exp::Function(f @ Function::External(builtin)) if !machine::is_control(*builtin) => {
+                let f = f.clone();
+                let args = List::from_vec(self.stack.split_off(callee + 1));
+                self.stack.pop();
+                let nested = limits::nest(self.frames.len() + 1)?;
+                let result = f.call(&args);
+                drop(nested);
+                self.version = Environment::bindings_version();
+                let result = result.inspect_err(|err| {
+                    backtrace::record(err, || {
+                        let call = self.frame.closure.proto.call_site(self.frame.ip - 1);
+                        backtrace::Frame::new(&f, call, args.iter().cloned().collect())
+                    })
+                });
+                self.stack.push(result?);
+            }
+            Exp::Function(f) => {
+                let f = f.clone();
+                let args = List::from_vec(self.stack.split_off(callee + 1));
+                self.stack.pop();
+                let call = self.frame.closure.proto.call_site(self.frame.ip - 1);
+                return Ok(Some((f, args, call.cloned())));
+            }
+            _ => return Err("Error while evaluating".to_owned()),
+        }
+        Ok(None)
+    }
+
+    // Finishes the current call, and returns the result if it was the outermost one
+    #[inline(always)]
+    fn ret(&mut self, result: Exp) -> Option<Exp> {
+        self.stack.truncate(self.frame.base - 1);
+        match self.frames.pop() {
+            Some(caller) => {
+                self.frame = caller;
+                self.stack.push(result);
+                None
+            }
+            None => Some(result),
+        }
+    }
+
+    fn run(&mut self) -> Result<Exit, String> {
+        loop {
+            let frame = &mut self.frame;
+            let op = frame.closure.proto.code[frame.ip];
+            frame.ip += 1;
+            match op {
+                Op::Const(index) => {
+                    let val = frame.closure.proto.constants[index].clone();
+                    self.stack.push(val);
+                }
+                Op::Local(index) => {
+                    let val = self.stack[frame.base + index].clone();
+                    let val = self.bound(val, op)?;
+                    self.stack.push(val);
+                }
+                Op::SetLocal(index) => {
+                    let slot = frame.base + index;
+                    self.stack[slot] = self.pop();
+                }
+                Op::Captured(index) => {
+                    let val = frame.captured.as_ref().unwrap().borrow()[index].clone();
+                    let val = self.bound(val, op)?;
+                    self.stack.push(val);
+                }
+                Op::SetCaptured(index) => {
+                    let val = self.pop();
+                    self.frame.captured.as_ref().unwrap().borrow_mut()[index] = val;
+                }
+                Op::Upvalue(index) => {
+                    let (captured, slot) = &frame.closure.upvalues[index];
+                    let val = captured.borrow()[*slot].clone();
+                    let val = self.bound(val, op)?;
+                    self.stack.push(val);
+                }
+                Op::SetUpvalue(index) => {
+                    let val = self.pop();
+                    let (captured, slot) = &self.frame.closure.upvalues[index];
+                    captured.borrow_mut()[*slot] = val;
+                }
+                Op::Global(index) => {
+                    let val = self.global(index)?;
+                    self.stack.push(val);
+                }
+                Op::DefGlobal(index) | Op::SetGlobal(index) => {
+                    let val = self.pop();
+                    let closure = &self.frame.closure;
+                    let mut globals = closure.globals.clone();
+                    let name = &closure.proto.globals[index];
+                    if let Op::DefGlobal(_) = op {
+                        globals.define(name, &val)?;
+                    } else {
+                        globals.assign(name, &val)?;
+                    }
+                    self.version = Environment::bindings_version();
+                }
+                Op::Closure(index) => {
+                    let proto = frame.closure.proto.protos[index].clone();
+                    let upvalues = proto
+                        .upvalues
+                        .iter()
+                        .map(|capture| match capture {
+                            Capture::Local(index) => (frame.captured.clone().unwrap(), *index),
+                            Capture::Upvalue(index) => frame.closure.upvalues[*index].clone(),
+                        })
+                        .collect();
+                    let closure = Closure {
+                        proto,
+                        upvalues,
+                        globals: frame.closure.globals.clone(),
+                        name: OnceCell::new(),
+                    };
+                    self.stack
+                        .push(Exp::Function(Function::Compiled(Rc::new(closure))));
+                }
+                Op::Name(index) => {
+                    if let (Exp::Ident(name), Some(Exp::Function(f))) =
+                        (&frame.closure.proto.constants[index], self.stack.last())
+                    {
+                        f.name_as(name);
+                    }
+                }
+                Op::Jump(target) => frame.ip = target,
+                Op::JumpIfFalse(target) => {
+                    let truthy = match self.pop() {
+                        Exp::Bool(val) => val,
+                        val => val.is_truthy(),
+                    };
+                    if !truthy {
+                        self.frame.ip = target;
+                    }
+                }
+                Op::Call(argc) => {
+                    if let Some(call) = self.call_at(self.stack.len() - argc - 1, argc)? {
+                        return Ok(Exit::Call(call));
+                    }
+                }
+                Op::TailCall(argc) => {
+                    let callee = self.stack.len() - argc - 1;
+                    if let Exp::Function(Function::Compiled(closure)) = &self.stack[callee] {
+                        limits::tick()?;
+                        // The callee and its arguments replace the current call's
+                        let closure = closure.clone();
+                        let base = self.frame.base;
+                        for offset in 0..=argc {
+                            self.stack.swap(base - 1 + offset, callee + offset);
+                        }
+                        self.stack.truncate(base + argc);
+                        self.frame = enter(&mut self.stack, closure, base, argc)?;
+                    } else {
+                        // Every tail call is followed by a return, which returns the result
+                        if let Some(call) = self.call_at(callee, argc)? {
+                            return Ok(Exit::Call(call));
+                        }
+                    }
+                }
+                Op::Primitive(primitive, index, a, b) => {
+                    let index = index as usize;
+                    let unchanged = self.cached_global(
+                        index,
+                        |f| matches!(f, Exp::Function(f) if primitive.matches(f)),
+                    );
+                    // The second operand is on top if it's on the stack at all, the first just
+                    // below it
+                    let len = self.stack.len();
+                    let on_stack = [a, b]
+                        .iter()
+                        .filter(|operand| matches!(operand, Operand::Stack))
+                        .count();
+                    match (self.number(a, len - on_stack), self.number(b, len - 1)) {
+                        (Some(a), Some(b)) if unchanged == Some(true) => {
+                            self.stack.truncate(len - on_stack);
+                            self.stack.push(primitive.apply(a, b));
+                        }
+                        // Redefined, not looked up yet, or arguments the builtin has to report
+                        // an error for
+                        _ => {
+                            let b = self.operand(b);
+                            let a = self.operand(a);
+                            let callee = self.stack.len();
+                            let f = self.global(index)?;
+                            self.stack.extend([f, a, b]);
+                            if let Some(call) = self.call_at(callee, 2)? {
+                                return Ok(Exit::Call(call));
+                            }
+                        }
+                    }
+                }
+                Op::Return => {
+                    let result = self.pop();
+                    if let Some(result) = self.ret(result) {
+                        return Ok(Exit::Done(result));
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Trace for Suspended {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.stack.trace(tracer);
+        for frame in self.frames.iter().chain([&self.frame]) {
+            frame.closure.trace(tracer);
+            if let Some(captured) = &frame.captured {
+                captured.trace(tracer);
+            }
+        }
+    }
+}
+
+impl Trace for Closure {
+    fn trace(&self, tracer: &mut Tracer) {
+        for (captured, _) in &self.upvalues {
+            captured.trace(tracer);
+        }
+        self.globals.trace(tracer);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{evaluator::Evaluator, expression::Exp, limits::Limits, Interpreter};
+
+    // Each case is a sequence of forms and what the last one evaluates to, as display prints it.
+    // Both evaluators have to agree on all of them.
+    const CASES: &[(&[&str], Result<&str, &str>)] = &[
+        (&["(+ 1 2)"], Ok("3")),
+        (&["(def x 5)", "(* x x)"], Ok("25")),
+        (&["(if 0 1 2)"], Ok("2")),
+        (&["'(a (b c))"], Ok("(a (b c))")),
+        (
+            &[
+                "(def fact (lambda (n) (if (= n 0) 1 (* n (fact (- n 1))))))",
+                "(fact 10)",
+            ],
+            Ok("3628800"),
+        ),
+        (
+            &[
+                "(def even? (lambda (n) (if (= n 0) #t (odd? (- n 1)))))",
+                "(def odd? (lambda (n) (if (= n 0) #f (even? (- n 1)))))",
+                "(even? 100)",
+            ],
+            Ok("#t"),
+        ),
+        (
+            &[
+                "(def count (lambda (n acc) (if (= n 0) acc (count (- n 1) (+ acc 1)))))",
+                "(count 500 0)",
+            ],
+            Ok("500"),
+        ),
+        (
+            &[
+                "(def make-counter (lambda () ((lambda (count) (lambda () ((lambda (ignored) count) (set! count (+ count 1))))) 0)))",
+                "(def counter (make-counter))",
+                "(counter)",
+                "(counter)",
+            ],
+            Ok("2"),
+        ),
+        (
+            &["(def adder (lambda (a) (lambda (b) (lambda (c) (+ a (+ b c))))))", "(((adder 1) 2) 3)"],
+            Ok("6"),
+        ),
+        (
+            &[
+                "(def helper 1)",
+                "(def f (lambda (n) ((lambda (ignored) (helper n)) (def helper (lambda (m) (* m 2))))))",
+                "(f 4)",
+            ],
+            Ok("8"),
+        ),
+        (&["(vector-map (lambda (x) (* x x)) #(1 2 3))"], Ok("#(1 4 9)")),
+        (&["(apply (lambda (a b) (- a b)) (list 10 4))"], Ok("6")),
+        (
+            &[
+                "(define-syntax my-or (syntax-rules () ((_ a b) ((lambda (t) (if t t b)) a))))",
+                "(def f (lambda (t) (my-or #f t)))",
+                "(f 7)",
+            ],
+            Ok("7"),
+        ),
+        (&["(defmacro twice (x) (list '+ x x))", "((lambda (y) (twice y)) 4)"], Ok("8")),
+        (&["(eval (list '+ 1 2))"], Ok("3")),
+        (
+            &[
+                "(def ready #f)",
+                "(def offered 0)",
+                "(defmacro m () (if ready 1 (car 1)))",
+                "(handler-bind ((error-object? (lambda (e) (set! offered (+ offered 1))))) (eval '((lambda (a) (m)) (set! ready #t))))",
+                "offered",
+            ],
+            Ok("0"),
+        ),
+        (&["(eval 'y ((lambda (y) (the-environment)) 2))"], Ok("2")),
+        (&["((lambda (if) (+ if 1)) 2)"], Ok("3")),
+        (&["(set! + *)", "(+ 5 3)"], Ok("15")),
+        (&["((lambda (a) a) 1 2)"], Ok("1")),
+        (&["((lambda () nope))"], Err("Undefined identifier")),
+        (
+            &[
+                "(def f (lambda (c) ((lambda (ignored) a) (if c (def a 1) (def b 2)))))",
+                "(f #f)",
+            ],
+            Err("Undefined identifier"),
+        ),
+        (
+            &["(def f (lambda (c) (if c (def a 1) (+ a 1))))", "(f #f)"],
+            Err("Undefined identifier"),
+        ),
+        (
+            &[
+                "(def f (lambda () (m 1)))",
+                "(defmacro m (x) x)",
+                "(f)",
+            ],
+            Ok("1"),
+        ),
+        (&["((lambda (a b) a) 1)"], Err("Missing required argument")),
+        (&["(1 2)"], Err("Error while evaluating")),
+        (&["((lambda (x) (+ x #t)) 1)"], Err("Type error")),
+        (&["(def x 1)", "(def x 2)"], Err("Identifier already defined")),
+        (&["(set! nope 1)"], Err("Identifier does not exist in environment")),
+        (&["(if 1 2)"], Err("Missing else clause")),
+    ];
+
+    fn run(evaluator: Evaluator, forms: &[&str]) -> Result<String, String> {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_evaluator(evaluator);
+        let (last, rest) = forms.split_last().unwrap();
+        for form in rest {
+            interpreter.eval_str(form)?;
+        }
+        interpreter
+            .eval_str(last)
+            .map(|val| val.display_form().to_string())
+    }
+
+    #[test]
+    fn evaluators_agree() {
+        for (forms, expected) in CASES {
+            let expected = expected.map(str::to_owned).map_err(str::to_owned);
+            for evaluator in [Evaluator::TreeWalker, Evaluator::Bytecode] {
+                assert_eq!(
+                    run(evaluator, forms),
+                    expected,
+                    "{:?} {:?}",
+                    evaluator,
+                    forms
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn deep_recursion_and_tail_calls() {
+        let count = "(def count (lambda (n acc) (if (= n 0) acc (count (- n 1) (+ acc 1)))))";
+        let depth = "(def depth (lambda (n) (if (= n 0) 0 (+ 1 (depth (- n 1))))))";
+        // Tail calls take no depth, so the count goes far past the limit. The tree-walker is
+        // slower, so it counts less far.
+        for (evaluator, n) in [
+            (Evaluator::TreeWalker, 100000),
+            (Evaluator::Bytecode, 1000000),
+        ] {
+            let mut interpreter = Interpreter::new();
+            interpreter.set_evaluator(evaluator);
+            interpreter.set_limits(Limits {
+                depth: Some(1000),
+                ..Limits::default()
+            });
+            interpreter.eval_str(count).unwrap();
+            assert_eq!(
+                interpreter.eval_str(&format!("(count {} 0)", n)),
+                Ok(Exp::Number(n as f32)),
+                "{:?}",
+                evaluator
+            );
+            assert_eq!(
+                run(evaluator, &[depth, "(depth 100000)"]),
+                Ok("100000".to_owned()),
+                "{:?}",
+                evaluator
+            );
+        }
+    }
+}